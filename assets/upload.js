@@ -2,6 +2,11 @@
 
 window.addEventListener("DOMContentLoaded", function() {
   const SUPPORTED_TYPES = ["Files", "application/x-moz-file"];
+  const RESUMABLE_UPLOAD_SIZE = 16 * 1024 * 1024;
+  const MAX_UPLOAD_RETRIES = 8;
+
+  if(!document.querySelector("input[type=file]"))
+    return;
 
   let body = document.getElementsByTagName("body")[0];
   let file_upload_text = null;
@@ -92,51 +97,73 @@ window.addEventListener("DOMContentLoaded", function() {
     } else
       file_upload_text.data = remaining_files;
 
-    let request = new XMLHttpRequest();
-    request.addEventListener("loadend", function(e) {
-      if(request.status >= 200 && request.status < 300) {
-        if(!--remaining_files)
-          window.location.reload();
-
-        filename_line.remove();
-        progress_line.remove();
-
-        file_upload_text.data = remaining_files;
-      } else {
-        progress_line.innerText = request.response;
-        file_upload.outerHTML = req_url + "<br />" + request.response;
-      }
-    });
+    // Large files go up as resumable PATCHes, so a dropped connection only costs what was in flight
+    let resumable = file.size >= RESUMABLE_UPLOAD_SIZE;
+    let retries = 0;
+
+    let send = function(offset, probe) {
+      let request = new XMLHttpRequest();
+      request.addEventListener("loadend", function(e) {
+        let stored = resumable ? request.getResponseHeader("X-Upload-Offset") : null;
+        if(stored !== null && (request.status == 204 || (request.status == 409 && Number(stored) != offset)) && Number(stored) < file.size) {
+          if(Number(stored) > offset)
+            retries = 0;
+          send(Number(stored), false);
+        } else if(resumable && (request.status == 0 || request.status == 409 || request.status >= 500) && retries < MAX_UPLOAD_RETRIES) {
+          progress_progress_desc.innerText = "reconnecting";
+          setTimeout(function() {
+            send(0, true);
+          }, 1000 * Math.pow(2, retries++));
+        } else if(request.status >= 200 && request.status < 300) {
+          if(!--remaining_files)
+            window.location.reload();
+
+          filename_line.remove();
+          progress_line.remove();
+
+          file_upload_text.data = remaining_files;
+        } else {
+          progress_line.innerText = request.response;
+          file_upload.outerHTML = req_url + "<br />" + request.response;
+        }
+      });
 
-    let start = 0;
-    let update = -1000;
-    request.upload.addEventListener("loadstart", function(e) {
-      start = e.timeStamp;
-    });
-    let prog = function(e) {
-      if(e.lengthComputable) {
-        progress_progress.value = event.loaded;
-        progress_progress.max   = event.total;
-
-        let elapsed = (e.timeStamp - start) / 1000; // s
-        if(elapsed > 0.1) {
-          let speed = event.loaded / elapsed;
-          progress_progress_desc.innerText = human_readable_size(event.loaded) + "/" + human_readable_size(event.total);
-          progress_speed_text.data         = human_readable_size(speed);
-          progress_time.innerText          = hms(elapsed) + "/" + hms(event.total / speed);
+      let start = 0;
+      request.upload.addEventListener("loadstart", function(e) {
+        start = e.timeStamp;
+      });
+      let prog = function(e) {
+        if(e.lengthComputable) {
+          progress_progress.value = offset + e.loaded;
+          progress_progress.max   = file.size;
+
+          let elapsed = (e.timeStamp - start) / 1000; // s
+          if(elapsed > 0.1) {
+            let speed = e.loaded / elapsed;
+            progress_progress_desc.innerText = human_readable_size(offset + e.loaded) + "/" + human_readable_size(file.size);
+            progress_speed_text.data         = human_readable_size(speed);
+            progress_time.innerText          = hms(elapsed) + "/" + hms(elapsed + (file.size - offset - e.loaded) / speed);
+          }
+        } else {
+          progress_progress.removeAttribute("value");
+          progress_progress_desc.innerText = "uploading";
+          request.upload.removeEventListener("progress", prog);
         }
-      } else {
-        progress_progress.removeAttribute("value");
-        progress_progress_desc.innerText = "uploading";
-        request.upload.removeEventListener("progress", prog);
-      }
+      };
+      if(!probe)
+        request.upload.addEventListener("progress", prog);
+
+      request.open(resumable ? "PATCH" : "PUT", req_url);
+      if(file.lastModified)
+        request.setRequestHeader("X-Last-Modified", file.lastModified);
+      if(resumable) {
+        request.setRequestHeader("X-Upload-Offset", offset);
+        request.setRequestHeader("X-Upload-Length", file.size);
+        request.send(probe ? "" : file.slice(offset));
+      } else
+        request.send(file);
     };
-    request.upload.addEventListener("progress", prog);
-
-    request.open("PUT", req_url);
-    if(file.lastModified)
-      request.setRequestHeader("X-Last-Modified", file.lastModified);
-    request.send(file);
+    send(0, false);
   }
 
   function recurse_upload(entry, base_url) {
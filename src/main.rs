@@ -9,6 +9,14 @@ extern crate mime_guess;
 extern crate tabwriter;
 extern crate arrayvec;
 extern crate walkdir;
+extern crate argon2;
+extern crate pwhash;
+extern crate sha2;
+extern crate rcgen;
+extern crate rustls;
+extern crate webpki;
+extern crate x509_parser;
+extern crate hmac;
 extern crate blake3;
 extern crate brotli;
 extern crate chrono;
@@ -17,6 +25,8 @@ extern crate rfsapi;
 #[cfg(target_os = "windows")]
 extern crate winapi;
 extern crate ctrlc;
+extern crate hyper;
+extern crate socket2;
 extern crate serde;
 extern crate cidr;
 #[macro_use]
@@ -24,7 +34,11 @@ extern crate clap;
 extern crate iron;
 #[cfg(not(target_os = "windows"))]
 extern crate libc;
+#[cfg(not(target_os = "windows"))]
+extern crate xattr;
+extern crate md5;
 extern crate tar;
+extern crate toml;
 extern crate xml;
 extern crate zip;
 
@@ -36,15 +50,16 @@ pub mod util;
 pub struct Error(pub String);
 pub use options::{LogLevel, Options};
 
-use std::mem;
+use std::{mem, thread};
 use iron::Iron;
-use std::net::IpAddr;
+use std::net::{SocketAddr, Ipv4Addr, IpAddr};
 use std::process::exit;
-use std::time::Duration;
+use std::time::{SystemTime, Duration, UNIX_EPOCH};
+use std::collections::BTreeMap;
 use tabwriter::TabWriter;
-use std::io::{Write, stdout};
+use std::io::{ErrorKind as IoErrorKind, Write, stdout};
+use std::fs;
 use std::sync::{Mutex, Condvar};
-use hyper_native_tls::NativeTlsServer;
 
 
 fn main() {
@@ -63,46 +78,181 @@ fn actual_main() -> i32 {
 
 fn result_main() -> Result<(), Error> {
     let mut opts = Options::parse();
+    let access_log = match opts.access_log.clone() {
+        Some(Some(path)) => {
+            let name = path.0.clone();
+            Some(ops::AccessLog::open(opts.access_log_format, path).map_err(|err| Error(format!("Opening access log {}: {}", name, err)))?)
+        }
+        Some(None) => {
+            // The access log keeps stdout to itself, and everything else goes to --log-file or stderr
+            let output = util::duplicate_stdout().map_err(|err| Error(format!("Opening access log on standard output: {}", err)))?;
+            if opts.log_file.is_none() {
+                util::redirect_stdout_to_stderr().map_err(|err| Error(format!("Redirecting standard output: {}", err)))?;
+            }
+            Some(ops::AccessLog::new(opts.access_log_format, Box::new(output)))
+        }
+        None => None,
+    };
+    let log_file = match opts.log_file.clone() {
+        Some(path) => {
+            let name = path.0.clone();
+            Some(util::LogFile::open(path, opts.log_rotate_size, opts.log_rotate_age, opts.log_keep)
+                .map_err(|err| Error(format!("Opening log file {}: {}", name, err)))?)
+        }
+        None => None,
+    };
+    let mut generated_tls = None;
     if opts.generate_tls {
-        opts.tls_data = Some(ops::generate_tls_data(&opts.temp_directory)?);
+        let names = ops::tls_names(opts.bind_addresses.iter().copied().chain(opts.listen.iter().map(|(addr, _)| addr.ip())), &opts.tls_names);
+        let (identity, fingerprint) = ops::generate_tls_data(&opts.temp_directory, &names, opts.tls_key_type, opts.tls_validity)?;
+        opts.tls_data = vec![identity];
+        generated_tls = Some((names, fingerprint));
     }
-    for path in mem::take(&mut opts.generate_path_auth) {
-        opts.path_auth_data.insert(path, Some(ops::generate_auth_data()));
+    let (tls, tls_certificates) = match opts.tls_data.is_empty() {
+        true => (None, None),
+        false => ops::TlsServer::new(&opts.tls_data, &opts.client_ca, opts.client_cert_optional).map(|(server, certs)| (Some(server), certs))?,
+    };
+    let generated_path_auth: BTreeMap<_, _> = mem::take(&mut opts.generate_path_auth).into_iter().map(|path| (path, ops::generate_auth_data())).collect();
+    for (path, creds) in &generated_path_auth {
+        opts.path_auth_data.insert(path.clone(), Some(creds.clone()));
     }
 
-    let handler: &_ = Box::leak(Box::new(ops::SimpleChain::<ops::PruneChain, _> {
+    let handler: &_ = Box::leak(Box::new(ops::SimpleChain {
         handler: ops::PruneChain::new(&opts),
-        after: opts.request_bandwidth.map(ops::LimitBandwidthMiddleware::new),
+        after: Some(ops::LimitBandwidthMiddleware),
+        access_log,
     }));
-    let mut responder = if let Some(p) = opts.port {
-        if let Some(&((_, ref id), ref pw)) = opts.tls_data.as_ref() {
-                Iron::new(handler).https((opts.bind_address, p),
-                                         NativeTlsServer::new(id, pw).map_err(|err| Error(format!("Opening TLS certificate: {}", err)))?)
-            } else {
-                Iron::new(handler).http((opts.bind_address, p))
+    // (server, Unix socket, whether HTTPS, whether redirecting to HTTPS)
+    let mut responders = vec![];
+    let all_addresses: Vec<_> = opts.bind_addresses.iter().map(|&addr| SocketAddr::new(addr, opts.port.unwrap_or(0))).chain(opts.listen.iter().map(|&(addr, _)| addr)).collect();
+    if let Some((&first, rest)) = opts.bind_addresses.split_first() {
+        let only_v6 = |addr: IpAddr| addr.is_ipv6() && opts.bind_addresses.iter().any(IpAddr::is_ipv4);
+        let responder = if let Some(p) = opts.port {
+            let addr = SocketAddr::new(first, p);
+            let listener = ops::bind_tcp(addr, only_v6(first) || ops::needs_only_v6(addr, &all_addresses))
+                .map_err(|err| Error(format!("Starting server: {}", if err.kind() == IoErrorKind::AddrInUse { "port taken".to_string() } else { err.to_string() })))?;
+            ops::listen(handler, listener, &tls)?
+        } else {
+            ops::try_ports(handler, first, only_v6(first), util::PORT_SCAN_LOWEST, util::PORT_SCAN_HIGHEST, &tls)?
+        };
+        let port = responder.socket.port();
+        responders.push((responder, None, tls.is_some(), false));
+
+        for &addr in rest {
+            let addr = SocketAddr::new(addr, port);
+            let listener = ops::bind_tcp(addr, only_v6(addr.ip()) || ops::needs_only_v6(addr, &all_addresses))
+                .map_err(|err| Error(format!("Starting server on {}: {}", addr, err)))?;
+            responders.push((ops::listen(handler, listener, &tls)?, None, tls.is_some(), false));
+        }
+    }
+    for socket in util::activation_sockets() {
+        match ops::ActivationSocket::from_socket(socket).map_err(|err| Error(format!("Starting server on socket from systemd: {}", err)))? {
+            ops::ActivationSocket::Tcp(listener) => responders.push((ops::listen(handler, listener, &tls)?, None, tls.is_some(), false)),
+            #[cfg(not(target_os = "windows"))]
+            ops::ActivationSocket::Unix(listener, path) => {
+                let name = path.map(|path| path.display().to_string()).unwrap_or_else(|| "from systemd".to_string());
+                responders.push((ops::listen_unix(handler, listener)?, Some(name), false, false))
             }
-            .map_err(|_| Error(format!("Starting server: port taken")))
-    } else {
-        ops::try_ports(handler, opts.bind_address, util::PORT_SCAN_LOWEST, util::PORT_SCAN_HIGHEST, &opts.tls_data)
-    }?;
+        }
+    }
+    #[cfg(not(target_os = "windows"))]
+    for (name, path) in &opts.unix_sockets {
+        let listener = util::bind_unix(path, opts.unix_socket_mode).map_err(|err| Error(format!("Starting server on {}: {}", name, err)))?;
+        responders.push((ops::listen_unix(handler, listener)?, Some(name.clone()), false, false));
+    }
+    // HTTPS first, to know where to redirect plain HTTP
+    for &(addr, https) in opts.listen.iter().filter(|(_, https)| *https).chain(opts.listen.iter().filter(|(_, https)| !*https)) {
+        let listener = ops::bind_tcp(addr, ops::needs_only_v6(addr, &all_addresses)).map_err(|err| Error(format!("Starting server on {}: {}", addr, err)))?;
+        let https_port = responders.iter().find(|(_, _, https, _)| *https).map(|(responder, _, _, _)| responder.socket.port());
+        responders.push(match (https, https_port) {
+            (false, Some(https_port)) if opts.https_redirect => {
+                (ops::listen(ops::HttpsRedirectHandler(&handler.handler.handler, https_port), listener, &None)?, None, false, true)
+            }
+            (true, _) => (ops::listen(handler, listener, &tls)?, None, true, false),
+            (false, _) => (ops::listen(handler, listener, &None)?, None, false, false),
+        });
+    }
+    let metrics_responder = match opts.metrics_port {
+        Some(p) => {
+            Some(Iron::new(ops::MetricsHandler(&handler.handler.handler))
+                .http((opts.bind_addresses
+                           .first()
+                           .copied()
+                           .or_else(|| opts.listen.first().map(|(addr, _)| addr.ip()))
+                           .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+                       p))
+                .map_err(|err| Error(format!("Starting metrics server: {}", err)))?)
+        }
+        None => None,
+    };
 
     if opts.loglevel < options::LogLevel::NoStartup {
         if opts.log_colour {
             print!("{}", trivial_colours::Reset);
         }
-        print!("Hosting \"{}\" on port {}", opts.hosted_directory.0, responder.socket.port());
-        if responder.socket.ip() != IpAddr::from([0, 0, 0, 0]) {
-            print!(" under address {}", responder.socket.ip());
+        print!("Hosting \"{}\" on ", opts.hosted_directory.0);
+        for (i, (responder, unix_socket, https, redirect)) in responders.iter().enumerate() {
+            if i != 0 {
+                print!(", ");
+            }
+            if let Some(unix_socket) = unix_socket {
+                print!("socket {}", unix_socket);
+            } else {
+                print!("port {}", responder.socket.port());
+                if responder.socket.ip() != IpAddr::from([0, 0, 0, 0]) {
+                    print!(" under address {}", responder.socket.ip());
+                }
+            }
+            if tls.is_some() && !https {
+                print!(" {}", if *redirect { "(redirecting to HTTPS)" } else { "(without TLS)" });
+            }
         }
         print!(" with");
-        match opts.tls_data.as_ref() {
-            Some(&((ref id, _), _)) => print!(" TLS certificate from \"{}\"", id),
-            None => print!("out TLS"),
+        match &opts.tls_data[..] {
+            [] => print!("out TLS"),
+            [identity] => print!(" TLS certificate from \"{}\"", identity.name()),
+            identities => {
+                print!(" TLS certificates from ");
+                for (i, identity) in identities.iter().enumerate() {
+                    print!("{}\"{}\"", if i == 0 { "" } else { ", " }, identity.name());
+                }
+            }
         }
-        println!(" and {} authentication...", ["basic", "no"][opts.path_auth_data.is_empty() as usize]);
+        println!(" and {} authentication...",
+                 if opts.path_auth_data.is_empty() && opts.path_htpasswd.is_empty() && opts.path_tokens.is_empty() {
+                     "no"
+                 } else if opts.digest_auth {
+                     "digest"
+                 } else {
+                     "basic"
+                 });
 
-        if let Some(band) = opts.request_bandwidth {
-            println!("Requests limited to {}B/s.", band);
+        for (what, band, upload_band) in [("Requests", opts.request_bandwidth, opts.request_upload_bandwidth),
+                                          ("Clients", opts.client_bandwidth, opts.client_upload_bandwidth),
+                                          ("Users", opts.user_bandwidth, opts.user_upload_bandwidth),
+                                          ("All requests", opts.total_bandwidth, opts.total_upload_bandwidth)] {
+            match (band, upload_band) {
+                (Some(band), Some(upload_band)) => println!("{} limited to {}B/s, and {}B/s for uploads.", what, band, upload_band),
+                (Some(band), None) => println!("{} limited to {}B/s.", what, band),
+                (None, Some(upload_band)) => println!("{} limited to {}B/s for uploads.", what, upload_band),
+                (None, None) => {}
+            }
+        }
+        if let Some(path) = opts.metrics_path.as_ref() {
+            println!("Serving metrics at /{}.", path);
+        }
+        if let Some(metrics_responder) = metrics_responder.as_ref() {
+            println!("Serving metrics on port {}.", metrics_responder.socket.port());
+        }
+        match opts.access_log.as_ref() {
+            Some(Some((name, _))) => println!("Writing access log to {} in {}.", name, opts.access_log_format),
+            Some(None) => println!("Writing access log to standard output in {}.", opts.access_log_format),
+            None => {}
+        }
+        for (what, max) in [("requests", opts.max_requests), ("requests per client", opts.max_client_requests), ("archives", opts.max_archives)] {
+            if let Some(max) = max {
+                println!("Serving at most {} {} at once.", max, what);
+            }
         }
 
         for (ext, mime_type) in opts.mime_type_overrides {
@@ -112,7 +262,7 @@ fn result_main() -> Result<(), Error> {
             }
         }
 
-        if !opts.proxies.is_empty() {
+        if !opts.proxies.is_empty() || opts.unix_socket_proxy.is_some() {
             println!("Trusted proxies:");
 
             let mut out = TabWriter::new(stdout());
@@ -120,6 +270,9 @@ fn result_main() -> Result<(), Error> {
             for (network, header) in &opts.proxies {
                 writeln!(out, "{}\t{}", header, network).unwrap();
             }
+            if let Some(header) = opts.unix_socket_proxy.as_ref() {
+                writeln!(out, "{}\tUnix sockets", header).unwrap();
+            }
             writeln!(out, "URL Header\tNetwork").unwrap();
             for (network, header) in &opts.proxy_redirs {
                 writeln!(out, "{}\t{}", header, network).unwrap();
@@ -127,32 +280,78 @@ fn result_main() -> Result<(), Error> {
             out.flush().unwrap();
         }
     }
-    if !opts.path_auth_data.is_empty() && opts.loglevel < options::LogLevel::NoAuth {
-        println!("Basic authentication credentials:");
-
-        let mut out = TabWriter::new(stdout());
-        writeln!(out, "Path\tUsername\tPassword").unwrap();
+    if let (Some((names, fingerprint)), true) = (generated_tls.as_ref(), opts.loglevel < options::LogLevel::NoAuth) {
+        println!("Generated {} TLS certificate for {}, valid for {} days, with SHA-256 fingerprint {}.",
+                 opts.tls_key_type,
+                 names.join(", "),
+                 opts.tls_validity,
+                 fingerprint);
+    }
+    if (!opts.path_auth_data.is_empty() || !opts.path_htpasswd.is_empty() || !opts.path_tokens.is_empty()) && opts.loglevel < options::LogLevel::NoAuth {
+        println!("{} authentication credentials:", if opts.digest_auth { "Digest" } else { "Basic" });
 
+        // Only generated passwords are shown, since no-one would know them otherwise
+        let mut rows = BTreeMap::new();
         for (path, creds) in &opts.path_auth_data {
+            let row = rows.entry(path).or_insert_with(Vec::new);
             if let Some(ad) = creds {
                 let mut itr = ad.split(':');
-                write!(out, "/{}\t{}\t", path, itr.next().unwrap()).unwrap();
-                if let Some(p) = itr.next() {
-                    write!(out, "{}", p).unwrap();
-                }
-                writeln!(out).unwrap();
-            } else {
+                row.push((itr.next().unwrap().to_string(),
+                          match itr.next() {
+                              Some(p) if generated_path_auth.contains_key(path) => p.to_string(),
+                              Some(_) => "(as specified)".to_string(),
+                              None if opts.client_ca.is_some() => "(client certificate)".to_string(),
+                              None => String::new(),
+                          }));
+            }
+        }
+        for (path, ((htpasswd, _), users)) in &opts.path_htpasswd {
+            rows.entry(path).or_insert_with(Vec::new).extend(users.keys().map(|username| (username.clone(), format!("(from {})", htpasswd))));
+        }
+        for (path, ((tokens, _), names)) in &opts.path_tokens {
+            rows.entry(path).or_insert_with(Vec::new).extend(names.keys().map(|name| (name.clone(), format!("(token from {})", tokens))));
+        }
+
+        let mut out = TabWriter::new(stdout());
+        writeln!(out, "Path\tUsername\tPassword").unwrap();
+
+        for (path, users) in rows {
+            if users.is_empty() {
                 writeln!(out, "/{}\t\t", path).unwrap();
             }
+            for (username, password) in users {
+                writeln!(out, "/{}\t{}\t{}", path, username, password).unwrap();
+            }
         }
 
         out.flush().unwrap();
     }
+    if let (Some((_, secret)), false, true) = (opts.share_secret.as_ref(), opts.shares.is_empty(), opts.loglevel < options::LogLevel::NoAuth) {
+        println!("Share links:");
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut out = TabWriter::new(stdout());
+        writeln!(out, "Path\tExpires\tLink").unwrap();
+        for (path, age) in &opts.shares {
+            writeln!(out,
+                     "/{}\t{}\t{}",
+                     path,
+                     ops::share_expiry(now + age),
+                     ops::share_link_url(secret, path, now + age, opts.hosted_directory.1.join(path).is_dir()))
+                .unwrap();
+        }
+        out.flush().unwrap();
+    }
     if opts.loglevel < options::LogLevel::NoStartup {
         println!("Ctrl-C to stop.");
         println!();
     }
-    let Options { encoded_prune: opts_encoded_prune, temp_directory: opts_temp_directory, generate_tls: opts_generate_tls, .. } = opts;
+    let Options { encoded_prune: opts_encoded_prune,
+                  temp_directory: opts_temp_directory,
+                  generate_tls: opts_generate_tls,
+                  config_file: opts_config_file,
+                  unix_sockets: opts_unix_sockets,
+                  .. } = opts;
 
     static END_HANDLER: Condvar = Condvar::new();
     static END_HANDLER_MUTEX: Mutex<bool> = Mutex::new(true);
@@ -162,6 +361,25 @@ fn result_main() -> Result<(), Error> {
             END_HANDLER.notify_one();
         })
         .unwrap();
+    let tls_certificates = tls_certificates.filter(|_| !opts_generate_tls);
+    let access_log_file = handler.access_log.as_ref().filter(|log| log.path.is_some());
+    if log_file.is_some() || access_log_file.is_some() || opts_config_file.is_some() || tls_certificates.is_some() {
+        util::catch_hangup();
+    }
+    if let Some(log_file) = log_file {
+        thread::spawn(move || log_file.maintain());
+    }
+    if let Some(access_log) = access_log_file {
+        thread::spawn(move || access_log.maintain());
+    }
+    if let Some(config_file) = opts_config_file {
+        let http_handler = &handler.handler.handler;
+        thread::spawn(move || http_handler.watch_config(config_file, generated_path_auth));
+    }
+    if let Some(certs) = tls_certificates {
+        let http_handler = &handler.handler.handler;
+        thread::spawn(move || http_handler.watch_tls(certs));
+    }
     if opts_encoded_prune.is_some() {
         loop {
             if !END_HANDLER.wait_timeout_while(END_HANDLER_MUTEX.lock().unwrap(),
@@ -179,7 +397,15 @@ fn result_main() -> Result<(), Error> {
         drop(END_HANDLER.wait_while(END_HANDLER_MUTEX.lock().unwrap(), |keepgoing| *keepgoing).unwrap());
     }
 
-    responder.close().unwrap();
+    for (mut responder, _, _, _) in responders {
+        responder.close().unwrap();
+    }
+    for (_, path) in opts_unix_sockets {
+        let _ = fs::remove_file(path);
+    }
+    if let Some(mut metrics_responder) = metrics_responder {
+        metrics_responder.close().unwrap();
+    }
     handler.handler.handler.clean_temp_dirs(&opts_temp_directory, opts_generate_tls);
     Ok(())
 }
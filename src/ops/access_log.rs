@@ -0,0 +1,260 @@
+use chrono::{SecondsFormat, DateTime, Local};
+use iron::{headers, status, Response};
+use std::io::{Result as IoResult, Write};
+use std::time::{Duration, Instant};
+use self::super::super::util::hangups;
+use std::fs::{OpenOptions, File};
+use iron::response::WriteBody;
+use self::super::{ConfiguredRequest, AddressWriter};
+use std::path::{PathBuf, Path};
+use serde_json::Value;
+use std::sync::Mutex;
+use std::net::IpAddr;
+use std::thread;
+use std::fmt;
+
+
+/// How often to check whether the access log needs to be reopened
+const ACCESS_LOG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AccessLogFormat {
+    /// Apache Combined Log Format
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+impl AccessLogFormat {
+    pub fn from_name(name: &str) -> Option<AccessLogFormat> {
+        match name {
+            "combined" => Some(AccessLogFormat::Combined),
+            "json" => Some(AccessLogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for AccessLogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AccessLogFormat::Combined => "Combined Log Format",
+            AccessLogFormat::Json => "JSON lines",
+        })
+    }
+}
+
+
+/// Writes one record per request, after its response body is sent
+pub struct AccessLog {
+    pub format: AccessLogFormat,
+    /// The file written to, unless it's standard output
+    pub path: Option<(String, PathBuf)>,
+    pub output: Mutex<Box<dyn Write + Send>>,
+}
+
+/// Everything about a request that's known before its response body is sent
+struct AccessRecord {
+    time: DateTime<Local>,
+    client: IpAddr,
+    user: Option<String>,
+    method: String,
+    target: String,
+    version: String,
+    status: u16,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl AccessLog {
+    pub fn new(format: AccessLogFormat, output: Box<dyn Write + Send>) -> AccessLog {
+        AccessLog {
+            format,
+            path: None,
+            output: Mutex::new(output),
+        }
+    }
+
+    /// Append to `path`
+    pub fn open(format: AccessLogFormat, path: (String, PathBuf)) -> IoResult<AccessLog> {
+        let file = AccessLog::open_file(&path.1)?;
+        Ok(AccessLog {
+            format,
+            path: Some(path),
+            output: Mutex::new(Box::new(file)),
+        })
+    }
+
+    /// Reopen the file on SIGHUP, forever
+    pub fn maintain(&self) -> ! {
+        let mut last_hangups = hangups();
+        loop {
+            thread::sleep(ACCESS_LOG_CHECK_INTERVAL);
+
+            let now_hangups = hangups();
+            if now_hangups == last_hangups {
+                continue;
+            }
+            last_hangups = now_hangups;
+
+            if let Some(path) = self.path.as_ref() {
+                match AccessLog::open_file(&path.1) {
+                    Ok(file) => *self.output.lock().expect("Access log poisoned") = Box::new(file),
+                    Err(err) => eprintln!("Reopening access log {}: {}", path.0, err),
+                }
+            }
+        }
+    }
+
+    fn open_file(path: &Path) -> IoResult<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    /// Log `resp` to `req`, which started being handled at `start`, once its body, if any, is sent
+    pub fn log(&'static self, req: &ConfiguredRequest, resp: Response, start: Instant) -> Response {
+        let url = req.url.as_ref();
+        let record = AccessRecord {
+            time: Local::now(),
+            client: AddressWriter {
+                    request: req,
+                    config: req.config.clone(),
+                    log: (false, false, false),
+                    just_spaces: false,
+                }
+                .client_ip(),
+            user: req.user.clone(),
+            method: req.method.to_string(),
+            target: match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_string(),
+            },
+            version: req.version.to_string(),
+            status: resp.status.unwrap_or(status::NotFound).to_u16(),
+            referer: req.headers.get::<headers::Referer>().map(|r| r.0.clone()),
+            user_agent: req.headers.get::<headers::UserAgent>().map(|ua| ua.0.clone()),
+        };
+
+        match resp.body {
+            Some(body) => {
+                Response {
+                    body: Some(Box::new(AccessLogBody {
+                        log: self,
+                        record: Some(record),
+                        start,
+                        bytes: 0,
+                        underlying: body,
+                    })),
+                    ..resp
+                }
+            }
+            None => {
+                self.write(&record, 0, start.elapsed());
+                resp
+            }
+        }
+    }
+
+    fn write(&self, record: &AccessRecord, bytes: u64, duration: Duration) {
+        let line = match self.format {
+            AccessLogFormat::Combined => {
+                format!("{} - {} [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"\n",
+                        record.client,
+                        record.user.as_deref().map(ClfEscaped).map(|u| u.to_string()).unwrap_or_else(|| "-".to_string()),
+                        record.time.format("%d/%b/%Y:%H:%M:%S %z"),
+                        record.method,
+                        ClfEscaped(&record.target),
+                        record.version,
+                        record.status,
+                        if bytes == 0 {
+                            "-".to_string()
+                        } else {
+                            bytes.to_string()
+                        },
+                        ClfEscaped(record.referer.as_deref().unwrap_or("-")),
+                        ClfEscaped(record.user_agent.as_deref().unwrap_or("-")))
+            }
+            AccessLogFormat::Json => {
+                format!("{{\"time\":{},\"client\":{},\"user\":{},\"method\":{},\"path\":{},\"protocol\":{},\"status\":{},\"bytes\":{},\"duration_ms\":{},\
+                         \"referer\":{},\"user_agent\":{}}}\n",
+                        Value::from(record.time.to_rfc3339_opts(SecondsFormat::Millis, false)),
+                        Value::from(record.client.to_string()),
+                        Value::from(record.user.as_deref()),
+                        Value::from(&record.method[..]),
+                        Value::from(&record.target[..]),
+                        Value::from(&record.version[..]),
+                        record.status,
+                        bytes,
+                        Value::from(duration.as_micros() as f64 / 1000f64),
+                        Value::from(record.referer.as_deref()),
+                        Value::from(record.user_agent.as_deref()))
+            }
+        };
+
+        // Nowhere to report failing to write the log to
+        let _ = self.output.lock().expect("Access log poisoned").write_all(line.as_bytes());
+    }
+}
+
+
+struct AccessLogBody {
+    log: &'static AccessLog,
+    /// Taken when written
+    record: Option<AccessRecord>,
+    start: Instant,
+    bytes: u64,
+    underlying: Box<dyn WriteBody>,
+}
+
+impl WriteBody for AccessLogBody {
+    fn write_body(&mut self, res: &mut dyn Write) -> IoResult<()> {
+        self.underlying.write_body(&mut CountingWriter {
+            bytes: &mut self.bytes,
+            underlying: res,
+        })
+    }
+}
+
+/// Dropped once the body's sent, or failed to
+impl Drop for AccessLogBody {
+    fn drop(&mut self) {
+        if let Some(record) = self.record.take() {
+            self.log.write(&record, self.bytes, self.start.elapsed());
+        }
+    }
+}
+
+struct CountingWriter<'b, 'u> {
+    bytes: &'b mut u64,
+    underlying: &'u mut dyn Write,
+}
+
+impl<'b, 'u> Write for CountingWriter<'b, 'u> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let written = self.underlying.write(buf)?;
+        *self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.underlying.flush()
+    }
+}
+
+
+/// Escape quotes, backslashes, and control characters like Apache does in its logs
+struct ClfEscaped<'s>(&'s str);
+
+impl<'s> fmt::Display for ClfEscaped<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                c if c.is_control() && c.is_ascii() => write!(f, "\\x{:02x}", c as u8)?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -15,7 +15,7 @@ use std::os::unix::fs::{PermissionsExt, MetadataExt};
 use std::convert::{TryFrom, TryInto};
 use std::path::{PathBuf, Path};
 use iron::response::WriteBody;
-use self::super::HttpHandler;
+use self::super::{ConcurrencyLimitedBody, ConfiguredRequest, HttpHandler, Metrics};
 use iron::modifiers::Header;
 use chrono::{DateTime, Utc};
 use std::time::SystemTime;
@@ -243,7 +243,7 @@ impl WriteBody for WriteArchiveBody {
 impl HttpHandler {
     /// <form method=post enctype=text/plain> with sentinels matched in generated indices
     /// to avoid pretending we actually support POSTs by accident
-    pub(super) fn parse_post_archive(&self, req: &mut Request) -> Option<(ArchiveType, Mime)> {
+    pub(super) fn parse_post_archive(&self, req: &mut ConfiguredRequest) -> Option<(ArchiveType, Mime)> {
         // text/plain
         if req.headers.get() == Some(&headers::ContentType(Mime(MimeTopLevel::Text, MimeSubLevel::Plain, Default::default()))) {
             #[allow(invalid_value)]
@@ -275,14 +275,14 @@ impl HttpHandler {
     }
 
     /// If Accept: contains application/([x-]tar|zip|x-zip-compressed), then return one match, regardless of quality
-    pub(super) fn parse_get_accept_archive(&self, req: &mut Request) -> Option<(ArchiveType, Mime)> {
+    pub(super) fn parse_get_accept_archive(&self, req: &mut ConfiguredRequest) -> Option<(ArchiveType, Mime)> {
         req.headers
             .get_mut::<headers::Accept>()
             .and_then(|accept| mem::take(&mut accept.0).into_iter().map(|q| q.item).find_map(|m| ArchiveType::from_mime(&m).map(|at| (at, m))))
     }
 
     /// As above or GET X-HTTP-Archive: tar|zip
-    pub(super) fn handle_get_archive(&self, req: &mut Request, (archive_type, mime): (ArchiveType, Mime)) -> IronResult<Response> {
+    pub(super) fn handle_get_archive(&self, req: &mut ConfiguredRequest, (archive_type, mime): (ArchiveType, Mime)) -> IronResult<Response> {
         let (req_p, symlink, url_err) = self.parse_requested_path(req);
         if url_err {
             return self.handle_invalid_url(req, "<p>Percent-encoding decoded to invalid UTF-8.</p>");
@@ -301,23 +301,34 @@ impl HttpHandler {
         attachment += ".";
         attachment += archive_type.suffix();
 
-        Ok(Response::with((status::Ok,
-                           Header(headers::Server(USER_AGENT.into())),
-                           Header(ContentDisposition::Attachment(attachment)),
-                           mime,
-                           Box::new(WriteArchiveBody(self.log,
-                                                     if self.log.0 {
-                                                         self.remote_addresses(req).to_string()
-                                                     } else {
-                                                         String::new()
-                                                     },
-                                                     archive_type,
-                                                     req_p,
-                                                     match archive_type {
-                                                         ArchiveType::Tar => write_tar_body,
-                                                         ArchiveType::Zip => {
-                                                             [write_zip_body_no_encoding, write_zip_body_yes_encoding][self.encoded_temp_dir.is_some() as usize]
-                                                         }
-                                                     })) as Box<WriteBody>)))
+        let slot = match self.concurrency.enter_archive() {
+            Ok(slot) => slot,
+            Err(limit) => return self.handle_overloaded(req, limit),
+        };
+        let archives = match archive_type {
+            ArchiveType::Tar => &self.metrics.tar_archives,
+            ArchiveType::Zip => &self.metrics.zip_archives,
+        };
+        Metrics::add(archives, 1);
+
+        let mut body = Box::new(WriteArchiveBody(self.log,
+                                                 if self.log.0 {
+                                                     self.remote_addresses(req).to_string()
+                                                 } else {
+                                                     String::new()
+                                                 },
+                                                 archive_type,
+                                                 req_p,
+                                                 match archive_type {
+                                                     ArchiveType::Tar => write_tar_body,
+                                                     ArchiveType::Zip => {
+                                                         [write_zip_body_no_encoding, write_zip_body_yes_encoding][self.encoded_temp_dir.is_some() as usize]
+                                                     }
+                                                 })) as Box<dyn WriteBody>;
+        if let Some(slot) = slot {
+            body = Box::new(ConcurrencyLimitedBody(slot, body));
+        }
+
+        Ok(Response::with((status::Ok, Header(headers::Server(USER_AGENT.into())), Header(ContentDisposition::Attachment(attachment)), mime, body)))
     }
 }
@@ -0,0 +1,554 @@
+use self::super::super::util::{Destination, RetryAfter, verify_password_hash, percent_decode, error_html, url_path};
+use iron::{headers, status, method, IronResult, Response};
+use std::net::{Ipv6Addr, IpAddr};
+use iron::modifiers::Header;
+use std::hash::{BuildHasher, RandomState};
+use std::collections::{BTreeMap, BTreeSet};
+use self::super::{ConfiguredRequest, HandlerConfig, HttpHandler, ArchiveType, Metrics};
+use std::time::{Duration, Instant};
+use sha2::{Digest, Sha256};
+use md5::Md5;
+use blake3;
+
+
+/// Digest nonces are good for this long, after which clients are asked to retry with a fresh one
+const DIGEST_NONCE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+/// Remember at most this many Digest nonces, forgetting the oldest first
+const DIGEST_NONCE_LIMIT: usize = 0x10000;
+/// Unused Digest nonce counts this far below the highest one are still accepted,
+/// since parallel requests on the same nonce can arrive out of order
+const DIGEST_NC_WINDOW: u64 = 64;
+
+/// Clients are locked out for this long after too many failed logins, doubling with each one after that
+const LOGIN_LOCKOUT_BASE: Duration = Duration::from_secs(30);
+/// up to this long
+const LOGIN_LOCKOUT_MAX: Duration = Duration::from_secs(60 * 60);
+/// Failed logins are forgotten after this long without any more
+const LOGIN_FAILURE_MEMORY: Duration = Duration::from_secs(24 * 60 * 60);
+/// Remember failed logins from at most this many clients, forgetting the oldest first
+const LOGIN_FAILURE_LIMIT: usize = 0x10000;
+
+
+/// What a user needs to supply to authenticate
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum AuthSecret {
+    /// Password from the commandline, compared as-is
+    Plain(Option<String>),
+    /// crypt(3)-style password hash from an htpasswd file
+    Hashed(String),
+    /// Bearer token from a token file, hashed so it's compared in constant time
+    Token(blake3::Hash),
+}
+
+/// `username` -> secret, for everyone allowed under a path
+pub type AuthUsers = BTreeMap<String, AuthSecret>;
+
+/// Recent failed logins from one client
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub struct LoginFailures {
+    pub count: u64,
+    pub last: Instant,
+}
+
+impl LoginFailures {
+    /// How much longer the client is locked out for, if at all, after `attempts` failures are allowed
+    fn lockout(&self, attempts: u64) -> Option<Duration> {
+        if self.count < attempts {
+            return None;
+        }
+
+        let lockout = LOGIN_LOCKOUT_BASE.checked_mul(1 << (self.count - attempts).min(31)).unwrap_or(LOGIN_LOCKOUT_MAX).min(LOGIN_LOCKOUT_MAX);
+        lockout.checked_sub(self.last.elapsed()).filter(|left| !left.is_zero())
+    }
+}
+
+/// How a request's credentials fared
+enum AuthOutcome {
+    Valid(String),
+    /// Who they claimed to be, if anyone, and whether only the Digest nonce was out of date
+    Invalid(Option<String>, bool),
+    Missing,
+}
+
+
+/// What an ACL entry lets its subject do
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct AclRights {
+    /// GET, HEAD, PROPFIND, archives, and the source of COPY
+    pub read: bool,
+    /// PUT, PATCH, MKCOL, PROPPATCH, and the destination of COPY and MOVE
+    pub write: bool,
+    /// DELETE and the source of MOVE
+    pub delete: bool,
+}
+
+impl AclRights {
+    pub const NONE: AclRights = AclRights { read: false, write: false, delete: false };
+    pub const READ: AclRights = AclRights { read: true, ..AclRights::NONE };
+    pub const WRITE: AclRights = AclRights { write: true, ..AclRights::NONE };
+    pub const DELETE: AclRights = AclRights { delete: true, ..AclRights::NONE };
+    pub const ALL: AclRights = AclRights { read: true, write: true, delete: true };
+
+    pub fn union(self, other: AclRights) -> AclRights {
+        AclRights {
+            read: self.read || other.read,
+            write: self.write || other.write,
+            delete: self.delete || other.delete,
+        }
+    }
+
+    pub fn intersection(self, other: AclRights) -> AclRights {
+        AclRights {
+            read: self.read && other.read,
+            write: self.write && other.write,
+            delete: self.delete && other.delete,
+        }
+    }
+
+    pub fn covers(self, needed: AclRights) -> bool {
+        self.intersection(needed) == needed
+    }
+}
+
+/// Who an ACL entry applies to
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AclSubject {
+    /// `*`: every request, authenticated or not
+    Anyone,
+    User(String),
+    /// `@name`
+    Group(String),
+}
+
+/// Per-user rights from an `--acl` file
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Default)]
+pub struct Acl {
+    /// Group name -> member usernames
+    pub groups: BTreeMap<String, BTreeSet<String>>,
+    /// Normalised path -> who gets what there
+    pub rules: BTreeMap<String, Vec<(AclSubject, AclRights)>>,
+}
+
+impl Acl {
+    /// Get the rights `user` has under `path`.
+    ///
+    /// These come from the deepest path with an entry applying to `user`, with all such entries there combined;
+    /// if there's none, up to and including the root, `user` has no rights at all.
+    pub fn rights(&self, user: Option<&str>, path: &str) -> AclRights {
+        let mut path = path.trim_matches('/');
+        loop {
+            if let Some(rules) = self.rules.get(path) {
+                let mut applicable = rules.iter().filter(|(subject, _)| self.applies(subject, user)).map(|&(_, rights)| rights).peekable();
+                if applicable.peek().is_some() {
+                    return applicable.fold(AclRights::NONE, AclRights::union);
+                }
+            }
+
+            if path.is_empty() {
+                return AclRights::NONE;
+            }
+            path = &path[..path.rfind('/').unwrap_or(0)];
+        }
+    }
+
+    /// Get the rights `user` has under `path` and everywhere beneath it, as needed to archive a whole directory.
+    pub fn rights_recursive(&self, user: Option<&str>, path: &str) -> AclRights {
+        let path = path.trim_matches('/');
+        self.rules
+            .keys()
+            .filter(|rule_path| if path.is_empty() {
+                !rule_path.is_empty()
+            } else {
+                rule_path.len() > path.len() && rule_path.starts_with(path) && rule_path.as_bytes()[path.len()] == b'/'
+            })
+            .fold(self.rights(user, path), |rights, rule_path| rights.intersection(self.rights(user, rule_path)))
+    }
+
+    fn applies(&self, subject: &AclSubject, user: Option<&str>) -> bool {
+        match (subject, user) {
+            (AclSubject::Anyone, _) => true,
+            (AclSubject::User(name), Some(user)) => name == user,
+            (AclSubject::Group(group), Some(user)) => self.groups.get(group).map(|members| members.contains(user)).unwrap_or(false),
+            (_, None) => false,
+        }
+    }
+}
+
+
+impl HttpHandler {
+    pub(super) fn verify_auth(&self, req: &mut ConfiguredRequest) -> IronResult<Option<Response>> {
+        let config = req.config.clone();
+        let (realm, auth) = if let Some(scope) = config.auth_scope(req.url.as_ref().path()) {
+            scope
+        } else {
+            return Ok(None);
+        };
+
+        if req.user.as_ref().map(|user| auth.contains_key(user)).unwrap_or(false) {
+            log!(self.log,
+                 "{} correctly authorised to {red}{}{reset} {yellow}{}{reset} by client certificate",
+                 self.remote_addresses(req),
+                 req.method,
+                 req.url);
+            return Ok(None);
+        }
+
+        let client = login_client(self.remote_addresses(req).client_ip());
+        if let Some(left) = self.login_lockout(&config, client) {
+            let left = left.as_secs() + (left.subsec_nanos() != 0) as u64;
+            log!(self.log,
+                 "{} requested to {red}{}{reset} {yellow}{}{reset} while locked out for {}s more",
+                 self.remote_addresses(req),
+                 req.method,
+                 req.url,
+                 left);
+            return Ok(Some(Response::with((status::TooManyRequests, Header(RetryAfter(left)), "Too many failed logins, try again later.\n"))));
+        }
+
+        let authorization = req.headers.get_raw("Authorization").and_then(|values| values.first()).map(|value| String::from_utf8_lossy(value).into_owned());
+        let outcome = match authorization.as_deref().map(|a| a.trim().split_once(' ').unwrap_or((a, ""))) {
+            Some((scheme, _)) if scheme.eq_ignore_ascii_case("basic") && !config.digest_auth => {
+                match req.headers.get() {
+                    Some(headers::Authorization(headers::Basic { username, password })) => {
+                        let pwd = password.as_deref().filter(|p| !p.is_empty());
+
+                        let valid = match auth.get(username) {
+                            Some(AuthSecret::Plain(None)) if self.client_cert_user.is_some() => false,
+                            Some(AuthSecret::Plain(secret)) => secret.as_deref() == pwd,
+                            Some(AuthSecret::Hashed(hash)) => self.password_hash_matches(pwd.unwrap_or(""), hash),
+                            Some(AuthSecret::Token(_)) | None => false,
+                        };
+                        if valid {
+                            AuthOutcome::Valid(username.clone())
+                        } else {
+                            AuthOutcome::Invalid(Some(username.clone()), false)
+                        }
+                    }
+                    None => AuthOutcome::Invalid(None, false),
+                }
+            }
+            Some((scheme, params)) if scheme.eq_ignore_ascii_case("digest") && config.digest_auth => self.verify_digest(req, realm, auth, params),
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                let token = AuthSecret::Token(blake3::hash(token.trim().as_bytes()));
+                match auth.iter().find(|(_, secret)| **secret == token) {
+                    Some((name, _)) => AuthOutcome::Valid(name.clone()),
+                    None => AuthOutcome::Invalid(None, false),
+                }
+            }
+            _ => AuthOutcome::Missing,
+        };
+
+        match outcome {
+            AuthOutcome::Valid(username) => {
+                if config.login_attempts.is_some() {
+                    self.login_failures.lock().expect("Failed to get failed logins").remove(&client);
+                }
+                req.user = Some(username);
+                log!(self.log,
+                     "{} correctly authorised to {red}{}{reset} {yellow}{}{reset}",
+                     self.remote_addresses(req),
+                     req.method,
+                     req.url);
+
+                Ok(None)
+            }
+            AuthOutcome::Invalid(username, stale) => {
+                log!(self.log,
+                     "{} requested to {red}{}{reset} {yellow}{}{reset} with {} credentials{}{}{}",
+                     self.remote_addresses(req),
+                     req.method,
+                     req.url,
+                     if stale { "stale" } else { "invalid" },
+                     if username.is_some() { " for \"" } else { "" },
+                     username.as_deref().unwrap_or(""),
+                     if username.is_some() { "\"" } else { "" });
+                if !stale {
+                    Metrics::add(&self.metrics.auth_failures, 1);
+                    self.login_failed(&config, req, client);
+                }
+
+                Ok(Some(self.auth_challenge(&config, realm, auth, stale, "Supplied credentials invalid.\n")))
+            }
+            AuthOutcome::Missing => {
+                log!(self.log,
+                     "{} requested to {red}{}{reset} {yellow}{}{reset} without authorisation",
+                     self.remote_addresses(req),
+                     req.method,
+                     req.url);
+
+                Ok(Some(self.auth_challenge(&config, realm, auth, false, "Credentials required.\n")))
+            }
+        }
+    }
+
+    /// Check an RFC 7616 `Authorization: Digest` response to a challenge from `auth_challenge()`.
+    ///
+    /// Each nonce count can only be used once per nonce, so captured requests can't be replayed;
+    /// reusing one only gets the client asked to retry with a fresh nonce, since the credentials were right.
+    fn verify_digest(&self, req: &ConfiguredRequest, realm: &str, auth: &AuthUsers, params: &str) -> AuthOutcome {
+        let params = parse_auth_params(params);
+        let param = |name| params.get(name).map(String::as_str);
+
+        let username = match param("username") {
+            Some(username) => username,
+            None => return AuthOutcome::Invalid(None, false),
+        };
+        let invalid = || AuthOutcome::Invalid(Some(username.to_string()), false);
+
+        let hash: fn(&str) -> String = match param("algorithm").unwrap_or("MD5") {
+            algo if algo.eq_ignore_ascii_case("SHA-256") => |data| format!("{:x}", Sha256::digest(data.as_bytes())),
+            algo if algo.eq_ignore_ascii_case("MD5") => |data| format!("{:x}", Md5::digest(data.as_bytes())),
+            _ => return invalid(),
+        };
+        let password = match auth.get(username) {
+            Some(AuthSecret::Plain(None)) if self.client_cert_user.is_some() => return invalid(),
+            Some(AuthSecret::Plain(password)) => password.as_deref().unwrap_or(""),
+            _ => return invalid(),
+        };
+        let (nonce, uri, nc_s, cnonce, response) = match (param("nonce"), param("uri"), param("nc"), param("cnonce"), param("response")) {
+            (Some(nonce), Some(uri), Some(nc), Some(cnonce), Some(response)) => (nonce, uri, nc, cnonce, response),
+            _ => return invalid(),
+        };
+        let nc = match u64::from_str_radix(nc_s, 16) {
+            Ok(nc) => nc,
+            Err(_) => return invalid(),
+        };
+        let uri_path = uri.split('?').next().unwrap_or("");
+        let uri_path = uri_path.find("://").and_then(|scheme| uri_path[scheme + 3..].find('/').map(|path| &uri_path[scheme + 3 + path..])).unwrap_or(uri_path);
+        if param("realm") != Some(&format!("/{}", realm)) || param("qop") != Some("auth") ||
+           percent_decode(uri_path).as_deref() != Some(&*url_path(&req.url)) {
+            return invalid();
+        }
+
+        let ha1 = hash(&format!("{}:/{}:{}", username, realm, password));
+        let ha2 = hash(&format!("{}:{}", req.method, uri));
+        let expected = hash(&format!("{}:{}:{}:{}:auth:{}", ha1, nonce, nc_s, cnonce, ha2));
+        // blake3::Hash compares in constant time
+        if blake3::hash(expected.as_bytes()) != blake3::hash(response.to_ascii_lowercase().as_bytes()) {
+            return invalid();
+        }
+
+        let mut nonces = self.digest_nonces.lock().expect("Failed to get Digest nonces");
+        match nonces.get_mut(nonce) {
+            Some((issued, ..)) if issued.elapsed() > DIGEST_NONCE_LIFETIME => {
+                nonces.remove(nonce);
+                AuthOutcome::Invalid(Some(username.to_string()), true)
+            }
+            Some((_, highest_nc, used_ncs)) if nc > *highest_nc => {
+                *used_ncs = if nc - *highest_nc < DIGEST_NC_WINDOW {
+                    (*used_ncs << (nc - *highest_nc)) | 1
+                } else {
+                    1
+                };
+                *highest_nc = nc;
+                AuthOutcome::Valid(username.to_string())
+            }
+            Some((_, highest_nc, used_ncs)) if *highest_nc - nc < DIGEST_NC_WINDOW && *used_ncs & (1 << (*highest_nc - nc)) == 0 => {
+                *used_ncs |= 1 << (*highest_nc - nc);
+                AuthOutcome::Valid(username.to_string())
+            }
+            Some(_) => AuthOutcome::Invalid(Some(username.to_string()), true),
+            // Forgotten or from before a restart; the credentials are right, so the client can retry without asking the user
+            None => AuthOutcome::Invalid(Some(username.to_string()), true),
+        }
+    }
+
+    /// A 401 asking for whatever credentials the users under `realm` have:
+    /// passwords via Basic or Digest authentication and tokens via Bearer authentication.
+    fn auth_challenge(&self, config: &HandlerConfig, realm: &str, auth: &AuthUsers, stale: bool, body: &'static str) -> Response {
+        let realm = format!("\"/{}\"", realm.replace('\\', "\\\\").replace('"', "\\\""));
+
+        let mut challenges = vec![];
+        if auth.is_empty() || auth.values().any(|secret| !matches!(secret, AuthSecret::Token(_))) {
+            if config.digest_auth {
+                let nonce = self.digest_nonce();
+                for algorithm in ["SHA-256", "MD5"] {
+                    challenges.push(format!("Digest realm={}, qop=\"auth\", algorithm={}, nonce=\"{}\"{}",
+                                            realm,
+                                            algorithm,
+                                            nonce,
+                                            if stale { ", stale=true" } else { "" }));
+                }
+            } else {
+                challenges.push("basic".to_string());
+            }
+        }
+        if auth.values().any(|secret| matches!(secret, AuthSecret::Token(_))) {
+            challenges.push(format!("Bearer realm={}", realm));
+        }
+
+        let mut resp = Response::with((status::Unauthorized, body));
+        resp.headers.set_raw("WWW-Authenticate", challenges.into_iter().map(|challenge| challenge.into_bytes().into()).collect());
+        resp
+    }
+
+    fn login_lockout(&self, config: &HandlerConfig, client: IpAddr) -> Option<Duration> {
+        let attempts = config.login_attempts?.get();
+        self.login_failures.lock().expect("Failed to get failed logins").get(&client).and_then(|failures| failures.lockout(attempts))
+    }
+
+    fn login_failed(&self, config: &HandlerConfig, req: &ConfiguredRequest, client: IpAddr) {
+        let attempts = match config.login_attempts {
+            Some(attempts) => attempts.get(),
+            None => return,
+        };
+
+        let mut failures = self.login_failures.lock().expect("Failed to get failed logins");
+        if failures.len() >= LOGIN_FAILURE_LIMIT && !failures.contains_key(&client) {
+            failures.retain(|_, failures| failures.last.elapsed() < LOGIN_FAILURE_MEMORY);
+            if failures.len() >= LOGIN_FAILURE_LIMIT {
+                if let Some(oldest) = failures.iter().min_by_key(|(_, failures)| failures.last).map(|(client, _)| *client) {
+                    failures.remove(&oldest);
+                }
+            }
+        }
+
+        let now = Instant::now();
+        let failures = failures.entry(client).or_insert(LoginFailures { count: 0, last: now });
+        if now.duration_since(failures.last) >= LOGIN_FAILURE_MEMORY {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = now;
+
+        if let Some(lockout) = failures.lockout(attempts) {
+            log!(self.log,
+                 "{} locked out for {}s after {} failed logins",
+                 self.remote_addresses(req),
+                 lockout.as_secs() + (lockout.subsec_nanos() != 0) as u64,
+                 failures.count);
+        }
+    }
+
+    fn digest_nonce(&self) -> String {
+        let rnd = RandomState::new();
+        let nonce = format!("{:016x}{:016x}", rnd.hash_one(0), rnd.hash_one(1));
+
+        let mut nonces = self.digest_nonces.lock().expect("Failed to get Digest nonces");
+        nonces.retain(|_, (issued, ..)| issued.elapsed() <= DIGEST_NONCE_LIFETIME);
+        if nonces.len() >= DIGEST_NONCE_LIMIT {
+            if let Some(oldest) = nonces.iter().min_by_key(|(_, (issued, ..))| *issued).map(|(nonce, _)| nonce.clone()) {
+                nonces.remove(&oldest);
+            }
+        }
+        // Counts start at 1
+        nonces.insert(nonce.clone(), (Instant::now(), 0, 1));
+        nonce
+    }
+
+    pub(super) fn verify_acl(&self, req: &mut ConfiguredRequest, acl: &Acl) -> IronResult<Option<Response>> {
+        let (needed, destination_needed) = match req.method {
+            method::Options | method::Trace => (AclRights::NONE, AclRights::NONE),
+            method::Put | method::Patch | method::DavMkcol | method::DavProppatch | method::DavLock | method::DavUnlock => (AclRights::WRITE, AclRights::NONE),
+            method::Delete => (AclRights::DELETE, AclRights::NONE),
+            method::DavCopy => (AclRights::READ, AclRights::WRITE),
+            method::DavMove => (AclRights::DELETE, AclRights::WRITE),
+            _ => (AclRights::READ, AclRights::NONE),
+        };
+
+        let path = url_path(&req.url);
+        let user = req.user.as_deref();
+        let archive = self.archives &&
+                      (req.method == method::Post ||
+                       req.headers.get::<headers::Accept>().map(|accept| accept.0.iter().any(|q| ArchiveType::from_mime(&q.item).is_some())).unwrap_or(false));
+        let mut allowed = if archive {
+                acl.rights_recursive(user, &path)
+            } else {
+                acl.rights(user, &path)
+            }
+            .covers(needed);
+        if allowed && destination_needed != AclRights::NONE {
+            if let Some(Destination(dest)) = req.headers.get::<Destination>() {
+                allowed = acl.rights(user, &percent_decode(dest.path()).unwrap_or_default()).covers(destination_needed);
+            }
+        }
+        if allowed {
+            return Ok(None);
+        }
+
+        log!(self.log,
+             "{} was denied {red}{}{reset} {yellow}{}{reset}{}{}{} by the ACL",
+             self.remote_addresses(req),
+             req.method,
+             path,
+             if user.is_some() { " as \"" } else { "" },
+             user.unwrap_or(""),
+             if user.is_some() { "\"" } else { "" });
+
+        // Logging in might help, so ask for credentials
+        if user.is_none() {
+            let config = req.config.clone();
+            if let Some((realm, auth)) = config.auth_scope(req.url.as_ref().path()) {
+                return Ok(Some(self.auth_challenge(&config, realm, auth, false, "Credentials required.\n")));
+            }
+        }
+
+        let resp_text = error_html("403 Forbidden",
+                                   format_args!("You may not {} {}.", req.method, path),
+                                   "<p>Ask the server administrator for access.</p>");
+        self.handle_generated_response_encoding(req, status::Forbidden, resp_text).map(Some)
+    }
+
+    /// bcrypt and Argon2 are slow on purpose, so only pay for each correct password once,
+    /// instead of on every request a browser makes.
+    fn password_hash_matches(&self, password: &str, hash: &str) -> bool {
+        let key = blake3::Hasher::new().update(hash.as_bytes()).update(b"\0").update(password.as_bytes()).finalize();
+        if self.verified_passwords.read().expect("Failed to get verified passwords").contains(&key) {
+            return true;
+        }
+
+        let valid = verify_password_hash(password, hash);
+        if valid {
+            self.verified_passwords.write().expect("Failed to add verified password").insert(key);
+        }
+        valid
+    }
+}
+
+
+/// Failed logins are counted per IPv4 address, but per /64 for IPv6, since that's what hosts usually get
+fn login_client(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & !0xFFFF_FFFF_FFFF_FFFF)),
+        },
+    }
+}
+
+
+/// Parse `key=value, key="quoted \"value\""` auth-params, with lower-case keys.
+fn parse_auth_params(mut s: &str) -> BTreeMap<String, String> {
+    let mut params = BTreeMap::new();
+    loop {
+        s = s.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+        let (key, rest) = match s.split_once('=') {
+            Some(kv) => kv,
+            None => return params,
+        };
+        let rest = rest.trim_start();
+
+        let (value, rest) = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut value = String::new();
+            let mut end = quoted.len();
+            let mut chars = quoted.char_indices();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                    c => value.push(c),
+                }
+            }
+            (value, &quoted[end..])
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            (rest[..end].trim().to_string(), &rest[end..])
+        };
+        params.insert(key.trim().to_ascii_lowercase(), value);
+        s = rest;
+    }
+}
@@ -1,53 +1,174 @@
-use iron::{AfterMiddleware, IronResult, Response, Handler, Request};
-use std::num::{NonZeroUsize, NonZeroU64};
-use std::io::{Result as IoResult, Write};
+use iron::{IronResult, Response, Handler, Request};
+use std::io::{Result as IoResult, Write, Read};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use iron::response::WriteBody;
-use std::time::Duration;
+use std::sync::{Mutex, Arc};
+use self::super::{ConfiguredRequest, AddressWriter, PruneChain, AccessLog};
+use std::num::NonZeroU64;
+use std::net::IpAddr;
+use std::hash::Hash;
 use std::thread;
+use std::cmp;
 
 
-pub const DEFAULT_SLEEP: Duration = Duration::from_millis(1);
-
-
-#[derive(Hash, PartialEq, Eq, PartialOrd, Ord)]
-pub struct SimpleChain<H: Send + Sync + 'static, Am: AfterMiddleware>
-    where &'static H: Handler
-{
-    pub handler: H,
-    pub after: Option<Am>,
+pub struct SimpleChain {
+    pub handler: PruneChain,
+    pub after: Option<LimitBandwidthMiddleware>,
+    pub access_log: Option<AccessLog>,
 }
 
-impl<H: Send + Sync + 'static, Am: AfterMiddleware> Handler for &'static SimpleChain<H, Am>
-    where &'static H: Handler
-{
+impl Handler for &'static SimpleChain {
     fn handle(&self, req: &mut Request) -> IronResult<Response> {
-        let resp = (&self.handler).handle(req)?;
-        match self.after.as_ref() {
+        let start = Instant::now();
+        let chain: &'static SimpleChain = self;
+        let req = &mut chain.handler.handler.configure(req);
+        let resp = chain.handler.handle(req).and_then(|resp| match chain.after.as_ref() {
             Some(am) => am.after(req, resp),
             None => Ok(resp),
+        });
+
+        match chain.access_log.as_ref() {
+            Some(log) => {
+                resp.map(|resp| log.log(req, resp, start)).map_err(|mut err| {
+                    err.response = log.log(req, err.response, start);
+                    err
+                })
+            }
+            None => resp,
         }
     }
 }
 
 
 
-#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
-pub struct LimitBandwidthMiddleware {
-    pub bandwidth: NonZeroU64,
+/// Shares `rate` bytes per second between everyone taking from it, allowing bursts of up to a second's worth
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: NonZeroU64,
+    /// Bytes that can be sent right away (negative if owed), and when that was last updated
+    state: Mutex<(f64, Instant)>,
 }
 
-impl LimitBandwidthMiddleware {
-    pub fn new(bandwidth: NonZeroU64) -> LimitBandwidthMiddleware {
-        LimitBandwidthMiddleware { bandwidth: bandwidth }
+impl TokenBucket {
+    pub fn new(rate: NonZeroU64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            state: Mutex::new((rate.get() as f64, Instant::now())),
+        }
+    }
+
+    /// Take `len` bytes' worth, returning how long to wait before sending them
+    pub fn take(&self, len: usize) -> Duration {
+        let rate = self.rate.get() as f64;
+        let mut state = self.state.lock().expect("Token bucket poisoned");
+        let now = Instant::now();
+        state.0 = (state.0 + now.duration_since(state.1).as_secs_f64() * rate).min(rate) - len as f64;
+        state.1 = now;
+
+        if state.0 < 0f64 {
+            Duration::from_secs_f64(-state.0 / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// Whether nothing was taken in the last second, and forgetting this bucket wouldn't change anything
+    fn idle(&self) -> bool {
+        self.state.lock().expect("Token bucket poisoned").1.elapsed() >= Duration::from_secs(1)
+    }
+}
+
+
+/// Limits on everything going one way: each request, client, and user gets their own bucket, and all of them share the total one
+#[derive(Debug)]
+pub struct BandwidthLimits {
+    pub request: Option<NonZeroU64>,
+    pub client: Option<NonZeroU64>,
+    pub user: Option<NonZeroU64>,
+    pub total: Option<Arc<TokenBucket>>,
+    clients: Mutex<HashMap<IpAddr, Arc<TokenBucket>>>,
+    users: Mutex<HashMap<String, Arc<TokenBucket>>>,
+}
+
+impl BandwidthLimits {
+    /// Get the limits, or `None` if there aren't any
+    pub fn new(request: Option<NonZeroU64>, client: Option<NonZeroU64>, user: Option<NonZeroU64>, total: Option<NonZeroU64>) -> Option<BandwidthLimits> {
+        if request.is_none() && client.is_none() && user.is_none() && total.is_none() {
+            return None;
+        }
+
+        Some(BandwidthLimits {
+            request,
+            client,
+            user,
+            total: total.map(TokenBucket::new).map(Arc::new),
+            clients: Default::default(),
+            users: Default::default(),
+        })
+    }
+
+    /// Get all the buckets a request from `client` as `user` needs to take from
+    pub fn buckets(&self, client: IpAddr, user: Option<&str>) -> Vec<Arc<TokenBucket>> {
+        let mut buckets = Vec::with_capacity(4);
+        buckets.extend(self.request.map(TokenBucket::new).map(Arc::new));
+        if let Some(rate) = self.client {
+            buckets.push(BandwidthLimits::shared_bucket(&self.clients, client, rate));
+        }
+        if let (Some(rate), Some(user)) = (self.user, user) {
+            buckets.push(BandwidthLimits::shared_bucket(&self.users, user.to_string(), rate));
+        }
+        buckets.extend(self.total.clone());
+        buckets
+    }
+
+    fn shared_bucket<K: Hash + Eq>(buckets: &Mutex<HashMap<K, Arc<TokenBucket>>>, key: K, rate: NonZeroU64) -> Arc<TokenBucket> {
+        let mut buckets = buckets.lock().expect("Token buckets poisoned");
+        if buckets.len() >= 1024 && !buckets.contains_key(&key) {
+            buckets.retain(|_, bucket| Arc::strong_count(bucket) > 1 || !bucket.idle());
+        }
+        buckets.entry(key).or_insert_with(|| Arc::new(TokenBucket::new(rate))).clone()
+    }
+}
+
+/// Move at most a hundredth of the slowest bucket's rate (but no more than 64KiB) at once, so they're taken from smoothly
+fn chunk_len(buckets: &[Arc<TokenBucket>]) -> usize {
+    buckets.iter().map(|bucket| bucket.rate.get() / 100).min().unwrap_or(u64::MAX).clamp(1, 64 * 1024) as usize
+}
+
+fn wait_for(buckets: &[Arc<TokenBucket>], len: usize) {
+    if let Some(wait) = buckets.iter().map(|bucket| bucket.take(len)).max().filter(|wait| !wait.is_zero()) {
+        thread::sleep(wait);
     }
 }
 
-impl AfterMiddleware for LimitBandwidthMiddleware {
-    fn after(&self, _: &mut Request, res: Response) -> IronResult<Response> {
+
+/// Limits response bodies, from `--request-bandwidth`, `--client-bandwidth`, `--user-bandwidth`, and `--total-bandwidth`
+///
+/// The limits are taken from the config snapshot the request was handled under.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub struct LimitBandwidthMiddleware;
+
+impl LimitBandwidthMiddleware {
+    pub fn after(&self, req: &mut ConfiguredRequest, res: Response) -> IronResult<Response> {
+        let config = req.config.clone();
+        let limits = match config.bandwidth.as_ref() {
+            Some(limits) => limits,
+            None => return Ok(res),
+        };
+        let client = AddressWriter {
+                request: req,
+                config: config.clone(),
+                log: (false, false, false),
+                just_spaces: false,
+            }
+            .client_ip();
+        let buckets = limits.buckets(client, req.user.as_deref());
+
         Ok(Response {
             body: res.body.map(|body| {
                 Box::new(LimitBandwidthWriteBody {
-                    bandwidth: self.bandwidth,
+                    buckets,
                     underlying: body,
                 }) as Box<dyn WriteBody>
             }),
@@ -58,33 +179,34 @@ impl AfterMiddleware for LimitBandwidthMiddleware {
 
 
 struct LimitBandwidthWriteBody {
-    bandwidth: NonZeroU64,
+    buckets: Vec<Arc<TokenBucket>>,
     underlying: Box<dyn WriteBody>,
 }
 
 impl WriteBody for LimitBandwidthWriteBody {
     fn write_body(&mut self, res: &mut dyn Write) -> IoResult<()> {
-        self.underlying.write_body(&mut LimitBandwidthWriter::new(self.bandwidth, res))
+        self.underlying.write_body(&mut LimitBandwidthWriter::new(&self.buckets, res))
     }
 }
 
 
-struct LimitBandwidthWriter<'o> {
-    chunk_len: NonZeroUsize,
+struct LimitBandwidthWriter<'b, 'o> {
+    buckets: &'b [Arc<TokenBucket>],
+    chunk_len: usize,
     output: &'o mut dyn Write,
 }
 
-impl<'o> LimitBandwidthWriter<'o> {
-    fn new(bandwidth: NonZeroU64, output: &'o mut dyn Write) -> LimitBandwidthWriter<'o> {
+impl<'b, 'o> LimitBandwidthWriter<'b, 'o> {
+    fn new(buckets: &'b [Arc<TokenBucket>], output: &'o mut dyn Write) -> LimitBandwidthWriter<'b, 'o> {
         LimitBandwidthWriter {
-            // bandwidth / (1000 / DEFAULT_SLEEP_MS)
-            chunk_len: NonZeroUsize::new(bandwidth.get() as usize * DEFAULT_SLEEP.as_millis() as usize / 1000).unwrap_or(NonZeroUsize::new(1).unwrap()),
+            buckets,
+            chunk_len: chunk_len(buckets),
             output: output,
         }
     }
 }
 
-impl<'o> Write for LimitBandwidthWriter<'o> {
+impl<'b, 'o> Write for LimitBandwidthWriter<'b, 'o> {
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
         self.write_all(buf)?;
         Ok(buf.len())
@@ -95,12 +217,40 @@ impl<'o> Write for LimitBandwidthWriter<'o> {
     }
 
     fn write_all(&mut self, buf: &[u8]) -> IoResult<()> {
-        for chunk in buf.chunks(self.chunk_len.get()) {
+        for chunk in buf.chunks(self.chunk_len) {
+            wait_for(self.buckets, chunk.len());
             self.output.write_all(chunk)?;
             self.output.flush()?;
-            thread::sleep(DEFAULT_SLEEP);
         }
 
         Ok(())
     }
 }
+
+
+/// Limits request bodies, from `--request-upload-bandwidth`, `--client-upload-bandwidth`, `--user-upload-bandwidth`, and
+/// `--total-upload-bandwidth`
+pub struct LimitBandwidthReader<R: Read> {
+    buckets: Vec<Arc<TokenBucket>>,
+    chunk_len: usize,
+    input: R,
+}
+
+impl<R: Read> LimitBandwidthReader<R> {
+    pub fn new(buckets: Vec<Arc<TokenBucket>>, input: R) -> LimitBandwidthReader<R> {
+        LimitBandwidthReader {
+            chunk_len: chunk_len(&buckets),
+            buckets,
+            input,
+        }
+    }
+}
+
+impl<R: Read> Read for LimitBandwidthReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let len = cmp::min(buf.len(), self.chunk_len);
+        let read = self.input.read(&mut buf[..len])?;
+        wait_for(&self.buckets, read);
+        Ok(read)
+    }
+}
@@ -0,0 +1,152 @@
+use self::super::super::util::{RetryAfter, error_html};
+use iron::{status, IronResult, Response};
+use std::io::{Result as IoResult, Write};
+use iron::response::WriteBody;
+use std::collections::HashMap;
+use self::super::{ConfiguredRequest, HttpHandler};
+use std::sync::{Mutex, Arc};
+use std::num::NonZeroU64;
+use std::net::IpAddr;
+
+
+/// How long to ask clients turned away to wait before retrying, in seconds
+pub const OVERLOADED_RETRY_AFTER: u64 = 5;
+
+
+/// What's being served right now
+#[derive(Debug, Default)]
+struct Concurrency {
+    requests: u64,
+    clients: HashMap<IpAddr, u64>,
+    archives: u64,
+}
+
+/// Limits on how much can be served at once, from `--max-requests`, `--max-client-requests`, and `--max-archives`
+#[derive(Debug)]
+pub struct ConcurrencyLimits {
+    pub requests: Option<NonZeroU64>,
+    pub client_requests: Option<NonZeroU64>,
+    pub archives: Option<NonZeroU64>,
+    current: Arc<Mutex<Concurrency>>,
+}
+
+/// The limit a request ran into
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum ConcurrencyLimit {
+    Requests,
+    ClientRequests,
+    Archives,
+}
+
+/// Counted against the limits until dropped, which happens after the response body is written, if it has one
+#[derive(Debug)]
+pub struct ConcurrencySlot {
+    current: Arc<Mutex<Concurrency>>,
+    /// `None` for archives
+    client: Option<IpAddr>,
+}
+
+impl ConcurrencyLimits {
+    pub fn new(requests: Option<NonZeroU64>, client_requests: Option<NonZeroU64>, archives: Option<NonZeroU64>) -> ConcurrencyLimits {
+        ConcurrencyLimits {
+            requests,
+            client_requests,
+            archives,
+            current: Default::default(),
+        }
+    }
+
+    /// Start serving a request from `client`, or `None` if there's no limit to count it against
+    pub fn enter_request(&self, client: IpAddr) -> Result<Option<ConcurrencySlot>, ConcurrencyLimit> {
+        if self.requests.is_none() && self.client_requests.is_none() {
+            return Ok(None);
+        }
+
+        let mut current = self.current.lock().expect("Concurrency counts poisoned");
+        if self.requests.map(|max| current.requests >= max.get()).unwrap_or(false) {
+            return Err(ConcurrencyLimit::Requests);
+        }
+        if self.client_requests.map(|max| current.clients.get(&client).cloned().unwrap_or(0) >= max.get()).unwrap_or(false) {
+            return Err(ConcurrencyLimit::ClientRequests);
+        }
+
+        current.requests += 1;
+        *current.clients.entry(client).or_insert(0) += 1;
+        Ok(Some(ConcurrencySlot {
+            current: self.current.clone(),
+            client: Some(client),
+        }))
+    }
+
+    /// Start generating an archive, or `None` if there's no limit to count it against
+    pub fn enter_archive(&self) -> Result<Option<ConcurrencySlot>, ConcurrencyLimit> {
+        let max = match self.archives {
+            Some(max) => max.get(),
+            None => return Ok(None),
+        };
+
+        let mut current = self.current.lock().expect("Concurrency counts poisoned");
+        if current.archives >= max {
+            return Err(ConcurrencyLimit::Archives);
+        }
+
+        current.archives += 1;
+        Ok(Some(ConcurrencySlot {
+            current: self.current.clone(),
+            client: None,
+        }))
+    }
+}
+
+impl Drop for ConcurrencySlot {
+    fn drop(&mut self) {
+        let mut current = self.current.lock().expect("Concurrency counts poisoned");
+        match self.client {
+            Some(client) => {
+                current.requests -= 1;
+                if let Some(count) = current.clients.get_mut(&client) {
+                    *count -= 1;
+                    if *count == 0 {
+                        current.clients.remove(&client);
+                    }
+                }
+            }
+            None => current.archives -= 1,
+        }
+    }
+}
+
+
+/// Keeps the slot taken until the body's written
+pub struct ConcurrencyLimitedBody(pub ConcurrencySlot, pub Box<dyn WriteBody>);
+
+impl WriteBody for ConcurrencyLimitedBody {
+    fn write_body(&mut self, res: &mut dyn Write) -> IoResult<()> {
+        self.1.write_body(res)
+    }
+}
+
+
+impl HttpHandler {
+    pub(super) fn handle_overloaded(&self, req: &mut ConfiguredRequest, limit: ConcurrencyLimit) -> IronResult<Response> {
+        let what = match limit {
+            ConcurrencyLimit::Requests => "too many requests at once",
+            ConcurrencyLimit::ClientRequests => "too many requests at once from it",
+            ConcurrencyLimit::Archives => "too many archives at once",
+        };
+        log!(self.log,
+             "{} was turned away from {red}{}{reset} {yellow}{}{reset}: {}",
+             self.remote_addresses(req),
+             req.method,
+             req.url,
+             what);
+
+        let resp_text = error_html("503 Service Unavailable",
+                                   format_args!("The server is handling {}.", what.replace(" from it", " from you")),
+                                   format_args!("<p>Try again in {} seconds.</p>", OVERLOADED_RETRY_AFTER));
+        self.handle_generated_response_encoding(req, status::ServiceUnavailable, resp_text).map(|mut resp| {
+            resp.headers.set(RetryAfter(OVERLOADED_RETRY_AFTER));
+            resp
+        })
+    }
+}
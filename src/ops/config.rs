@@ -0,0 +1,185 @@
+use self::super::{BandwidthLimits, AuthSecret, AclRights, AuthUsers, Acl, HttpHandler, generate_auth_data};
+use self::super::super::util::hangups;
+use self::super::super::Options;
+use std::path::{PathBuf, Path};
+use std::collections::BTreeMap;
+use std::num::NonZeroU64;
+use std::time::Duration;
+use std::ffi::OsString;
+use iron::mime::Mime;
+use std::{fs, mem};
+use std::ops::{DerefMut, Deref};
+use iron::Request;
+use cidr::IpCidr;
+use std::sync::Arc;
+use std::thread;
+use blake3;
+
+
+/// How often to check whether the config file was changed or SIGHUP received
+const CONFIG_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+
+/// The parts of `HttpHandler` that can be reloaded from the config file.
+///
+/// Each request keeps the snapshot it started with, even if it's reloaded in the meantime.
+#[derive(Debug)]
+pub struct HandlerConfig {
+    pub global_auth_data: Option<AuthUsers>,
+    pub path_auth_data: BTreeMap<String, Option<AuthUsers>>,
+    pub digest_auth: bool,
+    pub login_attempts: Option<NonZeroU64>,
+    pub acl: Option<Acl>,
+    pub share_secret: Option<Vec<u8>>,
+    pub proxies: BTreeMap<IpCidr, String>,
+    pub proxy_redirs: BTreeMap<IpCidr, String>,
+    pub unix_socket_proxy: Option<String>,
+    pub mime_type_overrides: BTreeMap<OsString, Mime>,
+    pub additional_headers: Vec<(String, Vec<u8>)>,
+    pub bandwidth: Option<BandwidthLimits>,
+    pub upload_bandwidth: Option<BandwidthLimits>,
+}
+
+impl HandlerConfig {
+    pub fn new(opts: &Options) -> HandlerConfig {
+        let mut path_auth_data = BTreeMap::new();
+        for (path, creds) in &opts.path_auth_data {
+            let creds = creds.as_ref()
+                .map(|auth| {
+                    let mut itr = auth.split_terminator(':');
+                    AuthUsers::from([(itr.next().unwrap().to_string(), AuthSecret::Plain(itr.next().map(str::to_string)))])
+                });
+            path_auth_data.insert(path.to_string(), creds);
+        }
+        for (path, (_, users)) in &opts.path_htpasswd {
+            path_auth_data.insert(path.to_string(),
+                                  Some(users.iter().map(|(username, hash)| (username.clone(), AuthSecret::Hashed(hash.clone()))).collect()));
+        }
+        for (path, (_, tokens)) in &opts.path_tokens {
+            path_auth_data.entry(path.to_string())
+                .or_insert_with(|| Some(AuthUsers::new()))
+                .get_or_insert_with(AuthUsers::new)
+                .extend(tokens.iter().map(|(name, token)| (name.clone(), AuthSecret::Token(blake3::hash(token.as_bytes())))));
+        }
+        let global_auth_data = path_auth_data.remove("").flatten();
+
+        HandlerConfig {
+            global_auth_data,
+            path_auth_data,
+            digest_auth: opts.digest_auth,
+            login_attempts: opts.login_attempts,
+            acl: opts.acl.as_ref().map(|(_, acl)| acl.clone()),
+            share_secret: opts.share_secret.as_ref().map(|(_, secret)| secret.clone()),
+            proxies: opts.proxies.clone(),
+            proxy_redirs: opts.proxy_redirs.clone(),
+            unix_socket_proxy: opts.unix_socket_proxy.clone(),
+            mime_type_overrides: opts.mime_type_overrides.clone(),
+            additional_headers: opts.additional_headers.clone(),
+            bandwidth: BandwidthLimits::new(opts.request_bandwidth, opts.client_bandwidth, opts.user_bandwidth, opts.total_bandwidth),
+            upload_bandwidth: BandwidthLimits::new(opts.request_upload_bandwidth,
+                                                   opts.client_upload_bandwidth,
+                                                   opts.user_upload_bandwidth,
+                                                   opts.total_upload_bandwidth),
+        }
+    }
+
+    pub fn has_auth(&self) -> bool {
+        self.global_auth_data.is_some() || !self.path_auth_data.is_empty()
+    }
+
+    /// Find the deepest path with authentication configured above `path`, and who's allowed there,
+    /// or `None` if it's explicitly disabled or there's none.
+    pub fn auth_scope(&self, path: &str) -> Option<(&str, &AuthUsers)> {
+        let mut path = path.trim_matches('/');
+        while !path.is_empty() {
+            if let Some((scope, pad)) = self.path_auth_data.get_key_value(path) {
+                return pad.as_ref().map(|pad| (&scope[..], pad));
+            }
+
+            path = &path[..path.rfind('/').unwrap_or(0)];
+        }
+
+        self.global_auth_data.as_ref().map(|gad| ("", gad))
+    }
+
+    /// Get the rights `user` has under `path`, which are all of them without `--acl`.
+    pub fn acl_rights(&self, user: Option<&str>, path: &str) -> AclRights {
+        self.acl.as_ref().map(|acl| acl.rights(user, path)).unwrap_or(AclRights::ALL)
+    }
+}
+
+
+/// A request, along with the config snapshot it's handled under
+pub struct ConfiguredRequest<'r, 'a: 'r, 'b: 'a> {
+    pub request: &'r mut Request<'a, 'b>,
+    pub config: Arc<HandlerConfig>,
+}
+
+impl<'r, 'a, 'b> Deref for ConfiguredRequest<'r, 'a, 'b> {
+    type Target = Request<'a, 'b>;
+
+    fn deref(&self) -> &Request<'a, 'b> {
+        self.request
+    }
+}
+
+impl<'r, 'a, 'b> DerefMut for ConfiguredRequest<'r, 'a, 'b> {
+    fn deref_mut(&mut self) -> &mut Request<'a, 'b> {
+        self.request
+    }
+}
+
+
+impl HttpHandler {
+    /// The config new requests are handled under
+    pub(super) fn config(&self) -> Arc<HandlerConfig> {
+        self.config.read().expect("Config poisoned").clone()
+    }
+
+    /// Handle `req` under the current config, even if it's reloaded in the meantime
+    pub(super) fn configure<'r, 'a, 'b>(&self, req: &'r mut Request<'a, 'b>) -> ConfiguredRequest<'r, 'a, 'b> {
+        ConfiguredRequest {
+            request: req,
+            config: self.config(),
+        }
+    }
+
+    /// Reload from the `--config` file when it's modified or on SIGHUP, forever
+    ///
+    /// Paths in `generated_path_auth` keep their credentials, new `--gen-path-auth` paths get new ones.
+    pub fn watch_config(&self, config_file: (String, PathBuf), mut generated_path_auth: BTreeMap<String, String>) -> ! {
+        let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        let mut last_modified = modified(&config_file.1);
+        let mut last_hangups = hangups();
+        loop {
+            thread::sleep(CONFIG_FILE_CHECK_INTERVAL);
+
+            let (now_modified, now_hangups) = (modified(&config_file.1), hangups());
+            if now_modified == last_modified && now_hangups == last_hangups {
+                continue;
+            }
+            last_modified = now_modified;
+            last_hangups = now_hangups;
+
+            match Options::load() {
+                Ok(mut opts) => {
+                    for path in mem::take(&mut opts.generate_path_auth) {
+                        let creds = generated_path_auth.entry(path.clone())
+                            .or_insert_with(|| {
+                                let creds = generate_auth_data();
+                                log!(self.log, "Generated credentials {} for {magenta}/{}{reset}", creds, path);
+                                creds
+                            })
+                            .clone();
+                        opts.path_auth_data.insert(path, Some(creds));
+                    }
+
+                    // Requests already being handled keep the old one
+                    *self.config.write().expect("Config poisoned") = Arc::new(HandlerConfig::new(&opts));
+                    log!(self.log, "Reloaded config file {magenta}{}{reset}", config_file.0);
+                }
+                Err(err) => log!(self.log, "Reloading config file {magenta}{}{reset} failed: {red}{}{reset}", config_file.0, err.message.trim()),
+            }
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use socket2::{Protocol as SocketProtocol, Domain, Socket, Type};
+use iron::{headers, status, IronResult, Listening, Response, Protocol, Handler, Request, Iron};
+#[cfg(not(target_os = "windows"))]
+use self::super::super::util::UnixSocketListener;
+#[cfg(not(target_os = "windows"))]
+use std::os::unix::net::UnixListener;
+use hyper::net::{HttpsListener, HttpListener};
+use self::super::super::util::USER_AGENT;
+use std::net::{SocketAddr, TcpListener};
+use std::io::Result as IoResult;
+use iron::modifiers::Header;
+use self::super::super::Error;
+use self::super::{ConfiguredRequest, HttpHandler, TlsServer};
+#[cfg(not(target_os = "windows"))]
+use std::path::{PathBuf, Path};
+#[cfg(not(target_os = "windows"))]
+use std::os::fd::OwnedFd;
+
+
+/// Bind a TCP socket to `addr`; an IPv6 one accepts IPv4 connections too, unless `only_v6`
+pub fn bind_tcp(addr: SocketAddr, only_v6: bool) -> IoResult<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(SocketProtocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    #[cfg(not(target_os = "windows"))]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// Whether an IPv6 `addr` has to leave IPv4 to another of `addrs`, because it's also listening on its port
+pub fn needs_only_v6<'a, I: IntoIterator<Item = &'a SocketAddr>>(addr: SocketAddr, addrs: I) -> bool {
+    addr.is_ipv6() && addrs.into_iter().any(|other| other.is_ipv4() && other.port() == addr.port())
+}
+
+/// Serve `handler` on `listener`, over HTTPS if `tls`
+pub fn listen<H: Handler>(handler: H, listener: TcpListener, tls: &Option<TlsServer>) -> Result<Listening, Error> {
+    let listener = HttpListener::from(listener);
+    if let Some(server) = tls.as_ref() {
+            Iron::new(handler).listen(HttpsListener::with_listener(listener, server.clone()), Protocol::http())
+        } else {
+            Iron::new(handler).listen(listener, Protocol::http())
+        }
+        .map_err(|err| Error(format!("Starting server: {}", err)))
+}
+
+
+/// A socket systemd passed via `LISTEN_FDS`
+pub enum ActivationSocket {
+    Tcp(TcpListener),
+    /// And its path, if any
+    #[cfg(not(target_os = "windows"))]
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl ActivationSocket {
+    #[cfg(not(target_os = "windows"))]
+    pub fn from_socket(socket: Socket) -> IoResult<ActivationSocket> {
+        let addr = socket.local_addr()?;
+        Ok(if addr.is_unix() {
+            ActivationSocket::Unix(OwnedFd::from(socket).into(), addr.as_pathname().map(Path::to_path_buf))
+        } else {
+            ActivationSocket::Tcp(socket.into())
+        })
+    }
+
+    #[cfg(target_os = "windows")]
+    pub fn from_socket(socket: Socket) -> IoResult<ActivationSocket> {
+        Ok(ActivationSocket::Tcp(socket.into()))
+    }
+}
+
+
+/// Serve `handler` on `listener`, always over plain HTTP, since whatever's in front of it does TLS
+#[cfg(not(target_os = "windows"))]
+pub fn listen_unix<H: Handler>(handler: H, listener: UnixListener) -> Result<Listening, Error> {
+    Iron::new(handler).listen(UnixSocketListener::from(listener), Protocol::http()).map_err(|err| Error(format!("Starting server: {}", err)))
+}
+
+
+/// Redirects everything to HTTPS on the port, for plain HTTP `--listen` addresses with `--redirect-https`
+pub struct HttpsRedirectHandler(pub &'static HttpHandler, pub u16);
+
+impl Handler for HttpsRedirectHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        self.0.handle_https_redirect(&mut self.0.configure(req), self.1)
+    }
+}
+
+
+impl HttpHandler {
+    fn handle_https_redirect(&self, req: &mut ConfiguredRequest, port: u16) -> IronResult<Response> {
+        let mut new_url = req.url.as_ref().clone();
+        let _ = new_url.set_scheme("https");
+        let _ = new_url.set_port(if port == 443 { None } else { Some(port) });
+        log!(self.log,
+             "Redirecting {} to {yellow}{}{reset} - HTTPS only",
+             self.remote_addresses(req),
+             new_url);
+
+        Ok(Response::with((status::PermanentRedirect, Header(headers::Server(USER_AGENT.into())), Header(headers::Location(new_url.to_string())))))
+    }
+}
@@ -0,0 +1,502 @@
+//! WebDAV class 2 write locks, as specified in
+//! https://tools.ietf.org/html/rfc4918#section-6 and
+//! https://tools.ietf.org/html/rfc4918#section-9.10
+
+
+use self::super::super::util::{Depth, If, IfCondition, LockToken, Timeout, error_html, escape_specials, file_etag, is_nonexistent_descendant_of,
+                               WEBDAV_XML_NAMESPACE_DAV};
+use self::super::webdav::{default_xml_parser_config, intialise_xml_output, text_xml_charset_utf8};
+use xml::reader::{EventReader as XmlReader, XmlEvent as XmlREvent};
+use xml::writer::{EventWriter as XmlWriter, XmlEvent as XmlWEvent, Error as XmlWError};
+use xml::common::{TextPosition as XmlTextPosition, Position};
+use std::hash::{BuildHasher, RandomState};
+use std::sync::MutexGuard;
+use iron::modifiers::Header;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use iron::{status, IronResult, Response, Request};
+use std::path::{PathBuf, Path};
+use self::super::{ConfiguredRequest, HttpHandler, WebDavLevel};
+use std::fs::File;
+use std::io::Write;
+use std::cmp;
+
+
+/// Locks expire after a week at most, even if requested with a longer or infinite timeout, so abandoned ones don't pile up
+const MAX_WEBDAV_LOCK_TIMEOUT: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+
+/// An active write lock, by its token in `HttpHandler::webdav_locks`
+#[derive(Debug, Clone)]
+pub struct WebDavLock {
+    /// The lock root
+    pub path: PathBuf,
+    /// The URL the lock root was locked at
+    pub url: String,
+    /// `Depth::Zero` or `Depth::Infinity`
+    pub depth: Depth,
+    pub exclusive: bool,
+    /// Contents of the `<owner>` element, returned verbatim
+    pub owner: Vec<XmlREvent>,
+    /// Who took the lock, if they authenticated; only they can then use it
+    pub user: Option<String>,
+    /// In seconds, as requested, `None` for infinite
+    pub timeout: Option<u64>,
+    /// `timeout`, capped at `MAX_WEBDAV_LOCK_TIMEOUT` from when it was taken or last refreshed
+    pub expires: Option<Instant>,
+}
+
+impl WebDavLock {
+    /// Whether `path` is the lock root or, for `Depth: infinity` locks, under it
+    pub fn covers(&self, path: &Path) -> bool {
+        self.path == path || (self.depth == Depth::Infinity && path.starts_with(&self.path))
+    }
+
+    fn usable_by(&self, user: Option<&str>) -> bool {
+        self.user.is_none() || self.user.as_deref() == user
+    }
+
+    fn refresh(&mut self, timeout: Option<u64>) {
+        self.timeout = timeout;
+        self.expires = Instant::now().checked_add(cmp::min(timeout.map(Duration::from_secs).unwrap_or(MAX_WEBDAV_LOCK_TIMEOUT), MAX_WEBDAV_LOCK_TIMEOUT));
+    }
+
+    fn remaining(&self) -> Timeout {
+        Timeout(self.expires.map(|exp| exp.saturating_duration_since(Instant::now())).map(|left| left.as_secs() + (left.subsec_nanos() != 0) as u64))
+    }
+}
+
+
+impl HttpHandler {
+    /// All unexpired locks
+    pub(super) fn webdav_locks(&self) -> MutexGuard<'_, HashMap<String, WebDavLock>> {
+        let mut locks = self.webdav_locks.lock().expect("Failed to get WebDAV locks");
+        let now = Instant::now();
+        locks.retain(|_, lock| lock.expires.map(|exp| exp > now).unwrap_or(true));
+        locks
+    }
+
+    /// Forget locks at and under a path that was just removed
+    pub(super) fn drop_webdav_locks(&self, path: &Path) {
+        self.webdav_locks().retain(|_, lock| !lock.path.starts_with(path));
+    }
+
+    /// Check that the request's `If` header holds, and that it submits the tokens for all locks on `path`,
+    /// which is also checked for locks on its parent and, recursively, its children if `structural`
+    /// (i.e. it's being created, removed, or overwritten by a collection).
+    ///
+    /// Returns the response to send if it doesn't.
+    pub(super) fn check_webdav_locks(&self, req: &mut ConfiguredRequest, path: &Path, structural: bool) -> Option<IronResult<Response>> {
+        if self.webdav < WebDavLevel::All {
+            return None;
+        }
+        if let Some(resp) = self.check_webdav_if(req) {
+            return Some(resp);
+        }
+
+        let locks = self.webdav_locks();
+        if locks.is_empty() {
+            return None;
+        }
+
+        let submitted = req.headers.get::<If>().map(|ifh| ifh.state_tokens().collect::<Vec<_>>()).unwrap_or_default();
+        let user = req.user.as_deref();
+        let satisfied = |token: &str, lock: &WebDavLock| {
+            (submitted.contains(&token) && lock.usable_by(user)) ||
+            (!lock.exclusive &&
+             submitted.iter()
+                .flat_map(|tok| locks.get(*tok))
+                .any(|other| !other.exclusive && other.path == lock.path && other.usable_by(user)))
+        };
+
+        let unsatisfied = locks.iter()
+            .filter(|(_, lock)| lock.covers(path) || (structural && (lock.path.starts_with(path) || Some(lock.path.as_path()) == path.parent())))
+            .find(|(token, lock)| !satisfied(token, lock))
+            .map(|(_, lock)| lock.url.clone());
+        drop(locks);
+
+        unsatisfied.map(|root| {
+            log!(self.log,
+                 "{} requested to {red}{}{reset} {yellow}{}{reset} but it's locked at {magenta}{}{reset}",
+                 self.remote_addresses(req),
+                 req.method,
+                 path.display(),
+                 root);
+
+            Ok(Response::with((status::Locked,
+                               webdav_error_xml("lock-token-submitted", Some(&root)).expect("Couldn't write WebDAV error XML"),
+                               text_xml_charset_utf8())))
+        })
+    }
+
+    /// Check that the request's `If` header, if any, holds, returning the response to send if it doesn't
+    fn check_webdav_if(&self, req: &mut ConfiguredRequest) -> Option<IronResult<Response>> {
+        let holds = match req.headers.get::<If>() {
+            Some(ifh) => self.if_header_holds(req, ifh),
+            None => true,
+        };
+        if holds {
+            return None;
+        }
+
+        let (req_p, _, _) = self.parse_requested_path(req);
+        Some(self.handle_precondition_failed(req, req_p))
+    }
+
+    /// https://tools.ietf.org/html/rfc4918#section-10.4.3
+    fn if_header_holds(&self, req: &ConfiguredRequest, ifh: &If) -> bool {
+        let (req_p, _, _) = self.parse_requested_path(req);
+        let locks = self.webdav_locks();
+        ifh.0.iter().any(|list| {
+            let res_p = match list.resource.as_ref() {
+                Some(res) => {
+                    match req.url.as_ref().join(res) {
+                        Ok(res_url) => self.parse_requested_path_custom_symlink(&res_url, true).0,
+                        Err(_) => return false,
+                    }
+                }
+                None => req_p.clone(),
+            };
+
+            list.conditions.iter().all(|cond| match cond {
+                IfCondition::StateToken(not, token) => locks.get(token).map(|lock| lock.covers(&res_p)).unwrap_or(false) != *not,
+                IfCondition::ETag(not, etag) => res_p.metadata().map(|meta| file_etag(&meta) == *etag).unwrap_or(false) != *not,
+            })
+        })
+    }
+
+    pub(super) fn handle_webdav_lock(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
+        let (req_p, symlink, url_err) = self.parse_requested_path(req);
+
+        if url_err {
+            return self.handle_invalid_url(req, "<p>Percent-encoding decoded to invalid UTF-8.</p>");
+        }
+
+        if self.writes_temp_dir.is_none() {
+            return self.handle_forbidden_method(req, "-w", "write requests");
+        }
+
+        if !req_p.parent().map(|pp| pp.exists()).unwrap_or(true) ||
+           (symlink && (!self.follow_symlinks || (self.sandbox_symlinks && !is_nonexistent_descendant_of(&req_p, &self.hosted_directory.1)))) {
+            return self.handle_nonexistent_status(req, req_p, status::Conflict);
+        }
+
+        let info = match parse_lockinfo(req) {
+            Ok(info) => info,
+            Err(e) => {
+                log!(self.log,
+                     "{} tried to {red}LOCK{reset} {yellow}{}{reset} with invalid XML",
+                     self.remote_addresses(req),
+                     req_p.display());
+                return self.handle_generated_response_encoding(req, status::BadRequest, error_html("400 Bad Request", format_args!("Invalid XML: {}", e), ""));
+            }
+        };
+        let timeout = req.headers.get::<Timeout>().map(|t| t.0);
+
+        let (exclusive, owner) = match info {
+            Some(info) => info,
+            None => return self.handle_webdav_lock_refresh(req, req_p, timeout),
+        };
+
+        let depth = req.headers.get::<Depth>().copied().unwrap_or(Depth::Infinity);
+        if depth == Depth::One {
+            return self.handle_generated_response_encoding(req,
+                                                           status::BadRequest,
+                                                           error_html("400 Bad Request", format_args!("Invalid depth: {}", depth), ""));
+        }
+        let timeout = timeout.unwrap_or(None);
+
+        log!(self.log,
+             "{} requested {} {red}LOCK{reset} of {yellow}{}{reset} at depth {} for {}",
+             self.remote_addresses(req),
+             if exclusive { "exclusive" } else { "shared" },
+             req_p.display(),
+             depth,
+             Timeout(timeout));
+
+        // Conflicting locks are checked below, but creating the resource needs its parent's
+        let exists = req_p.exists();
+        if let Some(resp) = if exists {
+            self.check_webdav_if(req)
+        } else {
+            self.check_webdav_locks(req, &req_p, true)
+        } {
+            return resp;
+        }
+
+        let mut locks = self.webdav_locks();
+        let conflict = locks.values()
+            .find(|lock| (lock.covers(&req_p) || (depth == Depth::Infinity && lock.path.starts_with(&req_p))) && (lock.exclusive || exclusive))
+            .map(|lock| lock.url.clone());
+        if let Some(root) = conflict {
+            drop(locks);
+            log!(self.log,
+                 "{} couldn't {red}LOCK{reset} {yellow}{}{reset}: already locked at {magenta}{}{reset}",
+                 self.remote_addresses(req),
+                 req_p.display(),
+                 root);
+            return Ok(Response::with((status::Locked,
+                                      webdav_error_xml("no-conflicting-lock", Some(&root)).expect("Couldn't write WebDAV error XML"),
+                                      text_xml_charset_utf8())));
+        }
+
+        if !exists {
+            // https://tools.ietf.org/html/rfc4918#section-7.3
+            if File::create(&req_p).is_err() {
+                return Ok(Response::with(status::Forbidden));
+            }
+        }
+
+        let token = lock_token();
+        let mut lock = WebDavLock {
+            path: req_p,
+            url: req.url.as_ref().as_str().to_string(),
+            depth,
+            exclusive,
+            owner,
+            user: req.user.clone(),
+            timeout: None,
+            expires: None,
+        };
+        lock.refresh(timeout);
+
+        let body = lockdiscovery_xml(&token, &lock).expect("Couldn't write LOCK XML");
+        locks.insert(token.clone(), lock);
+
+        Ok(Response::with((if exists { status::Ok } else { status::Created }, Header(LockToken(token)), body, text_xml_charset_utf8())))
+    }
+
+    /// https://tools.ietf.org/html/rfc4918#section-9.10.2
+    fn handle_webdav_lock_refresh(&self, req: &mut ConfiguredRequest, req_p: PathBuf, timeout: Option<Option<u64>>) -> IronResult<Response> {
+        let user = req.user.as_deref();
+        let refreshed = req.headers.get::<If>().and_then(|ifh| {
+            let mut locks = self.webdav_locks();
+            let token = ifh.state_tokens().find(|token| locks.get(*token).map(|lock| lock.covers(&req_p) && lock.usable_by(user)).unwrap_or(false))?;
+
+            let lock = locks.get_mut(token).unwrap();
+            lock.refresh(timeout.unwrap_or(lock.timeout));
+            Some(lockdiscovery_xml(token, lock).expect("Couldn't write LOCK XML"))
+        });
+
+        match refreshed {
+            Some(body) => {
+                log!(self.log,
+                     "{} refreshed {red}LOCK{reset} of {yellow}{}{reset}",
+                     self.remote_addresses(req),
+                     req_p.display());
+
+                Ok(Response::with((status::Ok, body, text_xml_charset_utf8())))
+            }
+            None => self.handle_precondition_failed(req, req_p),
+        }
+    }
+
+    pub(super) fn handle_webdav_unlock(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
+        let (req_p, _, url_err) = self.parse_requested_path(req);
+
+        if url_err {
+            return self.handle_invalid_url(req, "<p>Percent-encoding decoded to invalid UTF-8.</p>");
+        }
+
+        if self.writes_temp_dir.is_none() {
+            return self.handle_forbidden_method(req, "-w", "write requests");
+        }
+
+        let token = match req.headers.get::<LockToken>() {
+            Some(LockToken(token)) => token.clone(),
+            None => {
+                return self.handle_generated_response_encoding(req,
+                                                               status::BadRequest,
+                                                               error_html("400 Bad Request", "UNLOCK requires a Lock-Token.", ""))
+            }
+        };
+
+        log!(self.log,
+             "{} requested to {red}UNLOCK{reset} {yellow}{}{reset}",
+             self.remote_addresses(req),
+             req_p.display());
+
+        let mut locks = self.webdav_locks();
+        if locks.get(&token).map(|lock| lock.covers(&req_p) && lock.usable_by(req.user.as_deref())).unwrap_or(false) {
+            locks.remove(&token);
+            Ok(Response::with(status::NoContent))
+        } else {
+            Ok(Response::with((status::Conflict,
+                               webdav_error_xml("lock-token-matches-request-uri", None).expect("Couldn't write WebDAV error XML"),
+                               text_xml_charset_utf8())))
+        }
+    }
+
+    /// Write `<D:lockdiscovery>` with all locks covering `path`
+    pub(super) fn write_lockdiscovery<W: Write>(&self, out: &mut XmlWriter<W>, path: &Path) -> Result<(), XmlWError> {
+        out.write(XmlWEvent::start_element((WEBDAV_XML_NAMESPACE_DAV.0, "lockdiscovery")))?;
+        for (token, lock) in self.webdav_locks().iter().filter(|(_, lock)| lock.covers(path)) {
+            write_activelock(out, token, lock)?;
+        }
+        out.write(XmlWEvent::end_element())
+    }
+}
+
+
+/// https://tools.ietf.org/html/rfc4918#section-15.10
+pub fn write_supportedlock<W: Write>(out: &mut XmlWriter<W>) -> Result<(), XmlWError> {
+    out.write(XmlWEvent::start_element((WEBDAV_XML_NAMESPACE_DAV.0, "supportedlock")))?;
+    for scope in &["exclusive", "shared"] {
+        out.write(XmlWEvent::start_element((WEBDAV_XML_NAMESPACE_DAV.0, "lockentry")))?;
+        out.write(XmlWEvent::start_element((WEBDAV_XML_NAMESPACE_DAV.0, "lockscope")))?;
+        out.write(XmlWEvent::start_element((WEBDAV_XML_NAMESPACE_DAV.0, *scope)))?;
+        out.write(XmlWEvent::end_element())?;
+        out.write(XmlWEvent::end_element())?; // lockscope
+        out.write(XmlWEvent::start_element((WEBDAV_XML_NAMESPACE_DAV.0, "locktype")))?;
+        out.write(XmlWEvent::start_element((WEBDAV_XML_NAMESPACE_DAV.0, "write")))?;
+        out.write(XmlWEvent::end_element())?;
+        out.write(XmlWEvent::end_element())?; // locktype
+        out.write(XmlWEvent::end_element())?; // lockentry
+    }
+    out.write(XmlWEvent::end_element())
+}
+
+/// https://tools.ietf.org/html/rfc4918#section-14.1
+fn write_activelock<W: Write>(out: &mut XmlWriter<W>, token: &str, lock: &WebDavLock) -> Result<(), XmlWError> {
+    out.write(XmlWEvent::start_element("D:activelock"))?;
+
+    out.write(XmlWEvent::start_element("D:locktype"))?;
+    out.write(XmlWEvent::start_element("D:write"))?;
+    out.write(XmlWEvent::end_element())?;
+    out.write(XmlWEvent::end_element())?; // locktype
+
+    out.write(XmlWEvent::start_element("D:lockscope"))?;
+    out.write(XmlWEvent::start_element(if lock.exclusive { "D:exclusive" } else { "D:shared" }))?;
+    out.write(XmlWEvent::end_element())?;
+    out.write(XmlWEvent::end_element())?; // lockscope
+
+    out.write(XmlWEvent::start_element("D:depth"))?;
+    out.write(XmlWEvent::characters(&lock.depth.to_string()))?;
+    out.write(XmlWEvent::end_element())?;
+
+    if !lock.owner.is_empty() {
+        out.write(XmlWEvent::start_element("D:owner"))?;
+        for ev in lock.owner.iter().flat_map(|ev| ev.as_writer_event()) {
+            out.write(ev)?;
+        }
+        out.write(XmlWEvent::end_element())?;
+    }
+
+    out.write(XmlWEvent::start_element("D:timeout"))?;
+    out.write(XmlWEvent::characters(&lock.remaining().to_string()))?;
+    out.write(XmlWEvent::end_element())?;
+
+    out.write(XmlWEvent::start_element("D:locktoken"))?;
+    out.write(XmlWEvent::start_element("D:href"))?;
+    out.write(XmlWEvent::characters(token))?;
+    out.write(XmlWEvent::end_element())?;
+    out.write(XmlWEvent::end_element())?; // locktoken
+
+    out.write(XmlWEvent::start_element("D:lockroot"))?;
+    out.write(XmlWEvent::start_element("D:href"))?;
+    out.write(XmlWEvent::characters(&escape_specials(&lock.url)))?;
+    out.write(XmlWEvent::end_element())?;
+    out.write(XmlWEvent::end_element())?; // lockroot
+
+    out.write(XmlWEvent::end_element())
+}
+
+/// The LOCK response body, https://tools.ietf.org/html/rfc4918#section-9.10.1
+fn lockdiscovery_xml(token: &str, lock: &WebDavLock) -> Result<Vec<u8>, XmlWError> {
+    let mut out = intialise_xml_output()?;
+    out.write(XmlWEvent::start_element("D:prop").ns(WEBDAV_XML_NAMESPACE_DAV.0, WEBDAV_XML_NAMESPACE_DAV.1))?;
+    out.write(XmlWEvent::start_element("D:lockdiscovery"))?;
+    write_activelock(&mut out, token, lock)?;
+    out.write(XmlWEvent::end_element())?; // lockdiscovery
+    out.write(XmlWEvent::end_element())?; // prop
+    Ok(out.into_inner())
+}
+
+/// A failed precondition, https://tools.ietf.org/html/rfc4918#section-16
+pub(super) fn webdav_error_xml(condition: &str, root: Option<&str>) -> Result<Vec<u8>, XmlWError> {
+    let mut out = intialise_xml_output()?;
+    out.write(XmlWEvent::start_element("D:error").ns(WEBDAV_XML_NAMESPACE_DAV.0, WEBDAV_XML_NAMESPACE_DAV.1))?;
+    out.write(XmlWEvent::start_element((WEBDAV_XML_NAMESPACE_DAV.0, condition)))?;
+    if let Some(root) = root {
+        out.write(XmlWEvent::start_element("D:href"))?;
+        out.write(XmlWEvent::characters(&escape_specials(root)))?;
+        out.write(XmlWEvent::end_element())?;
+    }
+    out.write(XmlWEvent::end_element())?;
+    out.write(XmlWEvent::end_element())?;
+    Ok(out.into_inner())
+}
+
+/// A random version 4 UUID as an `opaquelocktoken:` URI, https://tools.ietf.org/html/rfc2518#section-6.4
+fn lock_token() -> String {
+    let rnd = RandomState::new();
+    let (hi, lo) = (rnd.hash_one(0), rnd.hash_one(1));
+    format!("opaquelocktoken:{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+            hi >> 32,
+            (hi >> 16) & 0xFFFF,
+            hi & 0x0FFF,
+            ((lo >> 48) & 0x3FFF) | 0x8000,
+            lo & 0xFFFF_FFFF_FFFF)
+}
+
+/// https://tools.ietf.org/html/rfc4918#section-14.11
+///
+/// Returns whether the lock is exclusive and the contents of `<owner>`, or `None` for an empty body (a refresh)
+fn parse_lockinfo(req: &mut Request) -> Result<Option<(bool, Vec<XmlREvent>)>, String> {
+    #[derive(Debug, Copy, Clone, Hash, PartialOrd, Ord, PartialEq, Eq)]
+    enum State {
+        Start,
+        LockInfo,
+        LockScope,
+        InLockScope,
+        LockType,
+        InLockType,
+        Owner(usize),
+    }
+
+    let mut xml = XmlReader::new_with_config(&mut req.body, default_xml_parser_config());
+    let mut state = State::Start;
+    let mut exclusive = None;
+    let mut owner = vec![];
+
+    loop {
+        let event = match xml.next() {
+            Ok(event) => event,
+            Err(xre) if xre.position() == XmlTextPosition::new() && xre.msg().contains("no root element") => return Ok(None),
+            Err(xre) => return Err(xre.to_string()),
+        };
+
+        match (state, event) {
+            (State::Start, XmlREvent::StartDocument { .. }) => (),
+            (State::Start, XmlREvent::StartElement { ref name, .. }) if name.local_name == "lockinfo" => state = State::LockInfo,
+
+            (State::LockInfo, XmlREvent::StartElement { ref name, .. }) if name.local_name == "lockscope" => state = State::LockScope,
+            (State::LockInfo, XmlREvent::StartElement { ref name, .. }) if name.local_name == "locktype" => state = State::LockType,
+            (State::LockInfo, XmlREvent::StartElement { ref name, .. }) if name.local_name == "owner" => state = State::Owner(0),
+            (State::LockInfo, XmlREvent::EndElement { .. }) => return exclusive.map(|ex| Some((ex, owner))).ok_or_else(|| "Missing lockscope".to_string()),
+
+            (State::LockScope, XmlREvent::StartElement { ref name, .. }) if name.local_name == "exclusive" || name.local_name == "shared" => {
+                exclusive = Some(name.local_name == "exclusive");
+                state = State::InLockScope;
+            }
+            (State::InLockScope, XmlREvent::EndElement { .. }) => state = State::LockScope,
+            (State::LockScope, XmlREvent::EndElement { .. }) => state = State::LockInfo,
+
+            (State::LockType, XmlREvent::StartElement { ref name, .. }) if name.local_name == "write" => state = State::InLockType,
+            (State::InLockType, XmlREvent::EndElement { .. }) => state = State::LockType,
+            (State::LockType, XmlREvent::EndElement { .. }) => state = State::LockInfo,
+
+            (State::Owner(0), XmlREvent::EndElement { .. }) => state = State::LockInfo,
+            (State::Owner(depth), ev) => {
+                match ev {
+                    XmlREvent::StartElement { .. } => state = State::Owner(depth + 1),
+                    XmlREvent::EndElement { .. } => state = State::Owner(depth - 1),
+                    _ => {}
+                }
+                owner.push(ev);
+            }
+
+            (st, ev) => return Err(format!("Unexpected event {:?} during state {:?}", ev, st)),
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use iron::{status, method, IronResult, Response, Handler, Request};
+use std::io::{Result as IoResult, Write, Read};
+use iron::response::WriteBody;
+use std::collections::BTreeMap;
+use self::super::{ConfiguredRequest, HttpHandler};
+use std::fmt::Write as _;
+use iron::mime::Mime;
+use std::sync::Mutex;
+
+
+/// What's happened since startup, for `--metrics` and `--metrics-port`
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// (method, status) -> responses
+    pub requests: Mutex<BTreeMap<(String, u16), u64>>,
+    pub response_bytes: AtomicU64,
+    pub upload_bytes: AtomicU64,
+    pub tar_archives: AtomicU64,
+    pub zip_archives: AtomicU64,
+    pub auth_failures: AtomicU64,
+    pub cache_fs_hits: AtomicU64,
+    pub cache_fs_misses: AtomicU64,
+    pub cache_gen_hits: AtomicU64,
+    pub cache_gen_misses: AtomicU64,
+    pub prunes: AtomicU64,
+    pub pruned_fs_bytes: AtomicU64,
+    pub pruned_gen_bytes: AtomicU64,
+    pub prune_ns: AtomicU64,
+}
+
+impl Metrics {
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, AtomicOrdering::Relaxed);
+    }
+
+    /// Count `resp` to a `method` request, and the bytes in its body as they're sent
+    ///
+    /// Methods we don't know are all counted as "other", so clients can't make up new labels.
+    pub fn count(&'static self, method: &method::Method, resp: Response) -> Response {
+        let method = match method {
+            method::Extension(_) => "other".to_string(),
+            method => method.to_string(),
+        };
+        *self.requests
+            .lock()
+            .expect("Request metrics poisoned")
+            .entry((method, resp.status.unwrap_or(status::NotFound).to_u16()))
+            .or_insert(0) += 1;
+
+        Response {
+            body: resp.body.map(|body| {
+                Box::new(CountedBody {
+                    counter: &self.response_bytes,
+                    underlying: body,
+                }) as Box<dyn WriteBody>
+            }),
+            ..resp
+        }
+    }
+}
+
+
+/// Adds the bytes read from `input` to `counter`
+pub struct CountingReader<'c, R: Read> {
+    counter: &'c AtomicU64,
+    input: R,
+}
+
+impl<'c, R: Read> CountingReader<'c, R> {
+    pub fn new(counter: &'c AtomicU64, input: R) -> CountingReader<'c, R> {
+        CountingReader { counter, input }
+    }
+}
+
+impl<'c, R: Read> Read for CountingReader<'c, R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let read = self.input.read(buf)?;
+        Metrics::add(self.counter, read as u64);
+        Ok(read)
+    }
+}
+
+
+struct CountedBody {
+    counter: &'static AtomicU64,
+    underlying: Box<dyn WriteBody>,
+}
+
+impl WriteBody for CountedBody {
+    fn write_body(&mut self, res: &mut dyn Write) -> IoResult<()> {
+        self.underlying.write_body(&mut CountingWriter {
+            counter: self.counter,
+            output: res,
+        })
+    }
+}
+
+struct CountingWriter<'o> {
+    counter: &'static AtomicU64,
+    output: &'o mut dyn Write,
+}
+
+impl<'o> Write for CountingWriter<'o> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let written = self.output.write(buf)?;
+        Metrics::add(self.counter, written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.output.flush()
+    }
+}
+
+
+/// Serves just the metrics, on `--metrics-port`
+pub struct MetricsHandler(pub &'static HttpHandler);
+
+impl Handler for MetricsHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        self.0.handle_metrics(&mut self.0.configure(req))
+    }
+}
+
+
+impl HttpHandler {
+    pub(super) fn handle_metrics(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
+        log!(self.log, "{} was served metrics", self.remote_addresses(req));
+
+        let mut resp = Response::with((status::Ok,
+                                       "text/plain; version=0.0.4; charset=utf-8".parse::<Mime>().unwrap(),
+                                       self.metrics_text()));
+        if req.method == method::Head {
+            resp.body = None;
+        }
+        Ok(resp)
+    }
+
+    /// The Prometheus text exposition format
+    fn metrics_text(&self) -> String {
+        let m = &self.metrics;
+        let get = |counter: &AtomicU64| counter.load(AtomicOrdering::Relaxed);
+        let ratio = |hits: u64, misses: u64| if hits + misses == 0 {
+            0f64
+        } else {
+            hits as f64 / (hits + misses) as f64
+        };
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP http_requests_total Requests answered, by method and status.");
+        let _ = writeln!(out, "# TYPE http_requests_total counter");
+        for ((method, status), count) in m.requests.lock().expect("Request metrics poisoned").iter() {
+            let _ = writeln!(out, "http_requests_total{{method=\"{}\",status=\"{}\"}} {}", method, status, count);
+        }
+
+        for (name, help, value) in [("http_response_bytes_total", "Response body bytes sent.", get(&m.response_bytes)),
+                                    ("http_upload_bytes_total", "PUT and PATCH request body bytes received.", get(&m.upload_bytes)),
+                                    ("http_auth_failures_total", "Requests with invalid credentials.", get(&m.auth_failures)),
+                                    ("http_prunes_total", "Encoded cache prunes that freed anything.", get(&m.prunes))] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        let _ = writeln!(out, "# HELP http_archives_total Archives generated, by type.");
+        let _ = writeln!(out, "# TYPE http_archives_total counter");
+        let _ = writeln!(out, "http_archives_total{{type=\"tar\"}} {}", get(&m.tar_archives));
+        let _ = writeln!(out, "http_archives_total{{type=\"zip\"}} {}", get(&m.zip_archives));
+
+        let _ = writeln!(out, "# HELP http_prune_seconds_total Time spent pruning the encoded caches.");
+        let _ = writeln!(out, "# TYPE http_prune_seconds_total counter");
+        let _ = writeln!(out, "http_prune_seconds_total {}", get(&m.prune_ns) as f64 / 1_000_000_000f64);
+
+        let caches = [("filesystem", &self.cache_fs_size, get(&m.cache_fs_hits), get(&m.cache_fs_misses), get(&m.pruned_fs_bytes)),
+                      ("generated", &self.cache_gen_size, get(&m.cache_gen_hits), get(&m.cache_gen_misses), get(&m.pruned_gen_bytes))];
+        let _ = writeln!(out, "# HELP http_encoded_cache_bytes Size of the encoded cache.");
+        let _ = writeln!(out, "# TYPE http_encoded_cache_bytes gauge");
+        for (cache, size, _, _, _) in &caches {
+            let _ = writeln!(out, "http_encoded_cache_bytes{{cache=\"{}\"}} {}", cache, get(size));
+        }
+        let _ = writeln!(out, "# HELP http_encoded_cache_lookups_total Encoded cache lookups, by result.");
+        let _ = writeln!(out, "# TYPE http_encoded_cache_lookups_total counter");
+        for (cache, _, hits, misses, _) in &caches {
+            let _ = writeln!(out, "http_encoded_cache_lookups_total{{cache=\"{}\",result=\"hit\"}} {}", cache, hits);
+            let _ = writeln!(out, "http_encoded_cache_lookups_total{{cache=\"{}\",result=\"miss\"}} {}", cache, misses);
+        }
+        let _ = writeln!(out, "# HELP http_encoded_cache_hit_ratio Share of encoded cache lookups that hit.");
+        let _ = writeln!(out, "# TYPE http_encoded_cache_hit_ratio gauge");
+        for (cache, _, hits, misses, _) in &caches {
+            let _ = writeln!(out, "http_encoded_cache_hit_ratio{{cache=\"{}\"}} {}", cache, ratio(*hits, *misses));
+        }
+        let _ = writeln!(out, "# HELP http_pruned_bytes_total Bytes freed by pruning the encoded cache.");
+        let _ = writeln!(out, "# TYPE http_pruned_bytes_total counter");
+        for (cache, _, _, _, pruned) in &caches {
+            let _ = writeln!(out, "http_pruned_bytes_total{{cache=\"{}\"}} {}", cache, pruned);
+        }
+
+        out
+    }
+}
@@ -1,36 +1,37 @@
 use blake3;
 use serde_json;
-use std::net::IpAddr;
+use std::net::{SocketAddr, IpAddr};
 use serde::Serialize;
-use std::sync::RwLock;
+use std::time::Instant;
+use std::sync::{RwLock, Condvar, Mutex, Arc};
 use std::{fmt, str, mem};
-use cidr::{Cidr, IpCidr};
+use cidr::Cidr;
 use std::fs::{self, File};
 use arrayvec::ArrayString;
 use std::default::Default;
 use iron::modifiers::Header;
+use iron::response::WriteBody;
 use std::path::{PathBuf, Path};
 use std::ffi::{OsString, OsStr};
 use std::fmt::Write as FmtWrite;
 use iron::headers::EncodingType;
 use iron::url::Url as GenericUrl;
 use mime_guess::get_mime_type_opt;
-use hyper_native_tls::NativeTlsServer;
 use std::hash::{BuildHasher, RandomState};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{HashMap, HashSet};
 use self::super::{LogLevel, Options, Error};
-use std::process::{ExitStatus, Command, Child, Stdio};
 use rfsapi::{RawFsApiHeader, FilesetData, RawFileData};
 use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
-use iron::{headers, status, method, IronResult, Listening, Response, Headers, Request, Handler, Iron};
+use iron::{headers, status, method, IronResult, Listening, Response, Headers, Request, Handler};
 use std::io::{self, ErrorKind as IoErrorKind, BufReader, SeekFrom, Write, Error as IoError, Read, Seek};
 use iron::mime::{Mime, Attr as MimeAttr, Value as MimeAttrValue, SubLevel as MimeSubLevel, TopLevel as MimeTopLevel};
-use self::super::util::{HumanReadableSize, WwwAuthenticate, NoDoubleQuotes, NoHtmlLiteral, XLastModified, DisplayThree, CommaList,
-                        XOcMTime, MsAsSAnd3339, Maybe, Dav, url_path, file_etag, file_hash, set_mtime_f, is_symlink, encode_str, error_html, encode_file,
+use self::super::util::{HumanReadableSize, NoDoubleQuotes, NoHtmlLiteral, XLastModified, DisplayThree, CommaList,
+                        XOcMTime, XUploadOffset, XUploadLength, MsAsSAnd3339, Maybe, Dav, Dasl, url_path, file_etag, file_hash, set_mtime, set_mtime_f, is_symlink,
+                        encode_str, error_html, encode_file,
                         file_length, file_binary, client_mobile, percent_decode, escape_specials, precise_time_ns, file_icon_suffix, is_actually_file,
                         is_descendant_of, response_encoding, detect_file_as_dir, encoding_extension, file_time_modified, file_time_modified_p,
-                        dav_level_1_methods, get_raw_fs_metadata, encode_tail_if_trimmed, extension_is_blacklisted, directory_listing_html,
-                        directory_listing_mobile_html, is_nonexistent_descendant_of, USER_AGENT, MAX_SYMLINKS, INDEX_EXTENSIONS, MIN_ENCODING_GAIN,
+                        dav_level_2_methods, get_raw_fs_metadata, coalesce_byte_ranges, encode_tail_if_trimmed, extension_is_blacklisted, directory_listing_html,
+                        directory_listing_mobile_html, is_nonexistent_descendant_of, USER_AGENT, UNIX_SOCKET_ADDRESS, MAX_SYMLINKS, MAX_PARTIAL_UPLOAD_IDLE, INDEX_EXTENSIONS, MIN_ENCODING_GAIN,
                         MAX_ENCODING_SIZE, MIN_ENCODING_SIZE};
 
 macro_rules! log {
@@ -110,14 +111,39 @@ macro_rules! log {
     }};
 }
 
+mod auth;
 mod prune;
+mod share;
 mod webdav;
 mod archive;
 mod bandwidth;
+mod access_log;
+mod metrics;
+mod concurrency;
+mod config;
+mod listen;
+mod tls;
+mod lock;
+mod quota;
+mod search;
 
 pub use self::prune::PruneChain;
+pub use self::auth::{LoginFailures, AclSubject, AuthSecret, AclRights, AuthUsers, Acl};
 pub use self::archive::ArchiveType;
-pub use self::bandwidth::{LimitBandwidthMiddleware, SimpleChain};
+pub use self::share::{share_link_url, share_expiry};
+pub use self::bandwidth::{LimitBandwidthMiddleware, LimitBandwidthReader, BandwidthLimits, SimpleChain, TokenBucket};
+pub use self::access_log::{AccessLogFormat, AccessLog};
+pub use self::metrics::{MetricsHandler, CountingReader, Metrics};
+pub use self::concurrency::{ConcurrencyLimitedBody, ConcurrencyLimits, ConcurrencyLimit, ConcurrencySlot};
+pub use self::config::{ConfiguredRequest, HandlerConfig};
+pub use self::lock::WebDavLock;
+pub use self::tls::{TlsCertificates, ClientCertName, TlsIdentity, TlsKeyType, TlsServer, TlsStream, generate_tls_data, tls_names};
+pub use self::listen::{HttpsRedirectHandler, ActivationSocket, needs_only_v6, bind_tcp, listen};
+#[cfg(not(target_os = "windows"))]
+pub use self::listen::listen_unix;
+
+use self::quota::{QuotaUsage, file_size};
+use self::share::ShareCheck;
 
 
 type CacheT<Cnt> = HashMap<(blake3::Hash, EncodingType), (Cnt, AtomicU64)>;
@@ -140,15 +166,29 @@ pub struct HttpHandler {
     /// (at all, log_time, log_colour)
     pub log: (bool, bool, bool),
     pub webdav: WebDavLevel,
+    pub webdav_infinity: bool,
     pub archives: bool,
-    pub global_auth_data: Option<(String, Option<String>)>,
-    pub path_auth_data: BTreeMap<String, Option<(String, Option<String>)>>,
+    /// The config from startup or the last reload, which new requests are handled under
+    pub config: RwLock<Arc<HandlerConfig>>,
+    /// Hashes of password hash + password pairs that already verified
+    pub verified_passwords: RwLock<HashSet<blake3::Hash>>,
+    /// Digest nonces we handed out -> when, the highest nonce count used with them,
+    /// and which counts up to `DIGEST_NC_WINDOW` below it were used, as bits from the highest down
+    pub digest_nonces: Mutex<HashMap<String, (Instant, u64, u64)>>,
+    /// Client address (/64 for IPv6) -> its recent failed logins
+    pub login_failures: Mutex<HashMap<IpAddr, LoginFailures>>,
+    /// Which part of verified client certificates is the username, with `--client-ca`
+    pub client_cert_user: Option<ClientCertName>,
     pub writes_temp_dir: Option<(String, PathBuf)>,
+    /// Largest the hosted directory may grow to with uploads, with `--quota`
+    pub quota: Option<u64>,
+    /// What counts against `--quota`
+    pub quota_usage: Mutex<QuotaUsage>,
+    /// Notified when `quota_usage` is re-measured
+    pub quota_measured: Condvar,
+    /// WebDAV lock tokens -> their locks
+    pub webdav_locks: Mutex<HashMap<String, WebDavLock>>,
     pub encoded_temp_dir: Option<(String, PathBuf)>,
-    pub proxies: BTreeMap<IpCidr, String>,
-    pub proxy_redirs: BTreeMap<IpCidr, String>,
-    pub mime_type_overrides: BTreeMap<OsString, Mime>,
-    pub additional_headers: Vec<(String, Vec<u8>)>,
 
     pub cache_gen: RwLock<CacheT<Vec<u8>>>,
     pub cache_fs_files: RwLock<HashMap<String, blake3::Hash>>, // etag -> cache key
@@ -158,36 +198,26 @@ pub struct HttpHandler {
     pub encoded_filesystem_limit: u64,
     pub encoded_generated_limit: u64,
 
+    /// Resumable uploads currently receiving data, by partial file ID
+    pub uploads_in_progress: Mutex<HashSet<blake3::Hash>>,
+    pub concurrency: ConcurrencyLimits,
+    pub metrics: Metrics,
+    /// Normalised, without leading slash
+    pub metrics_path: Option<String>,
+
     pub allowed_methods: &'static [method::Method],
 }
 
 impl HttpHandler {
     pub fn new(opts: &Options) -> HttpHandler {
-        let mut path_auth_data = BTreeMap::new();
-        let mut global_auth_data = None;
-
-        for (path, creds) in &opts.path_auth_data {
-            let creds = creds.as_ref()
-                .map(|auth| {
-                    let mut itr = auth.split_terminator(':');
-                    (itr.next().unwrap().to_string(), itr.next().map(str::to_string))
-                });
-
-            if path == "" {
-                global_auth_data = creds;
-            } else {
-                path_auth_data.insert(path.to_string(), creds);
-            }
-        }
-
         let allowed_methods = [method::Options, method::Get, method::Head, method::Trace]
             .iter()
-            .chain(dav_level_1_methods(opts.allow_writes)
+            .chain(dav_level_2_methods(opts.allow_writes)
                 .iter()
                 .filter(|method| {
                     opts.webdav == WebDavLevel::All || (opts.webdav == WebDavLevel::MkColMoveOnly && matches!(**method, method::DavMkcol | method::DavMove))
                 }))
-            .chain([method::Put, method::Delete].iter().filter(|_| opts.allow_writes))
+            .chain([method::Put, method::Patch, method::Delete].iter().filter(|_| opts.allow_writes))
             .cloned()
             .collect::<Vec<_>>()
             .leak();
@@ -202,10 +232,18 @@ impl HttpHandler {
             try_404: opts.try_404.clone(),
             log: (opts.loglevel < LogLevel::NoServeStatus, opts.log_time, opts.log_colour),
             webdav: opts.webdav,
+            webdav_infinity: opts.webdav_infinity,
             archives: opts.archives,
-            global_auth_data: global_auth_data,
-            path_auth_data: path_auth_data,
+            config: RwLock::new(Arc::new(HandlerConfig::new(opts))),
+            verified_passwords: Default::default(),
+            digest_nonces: Default::default(),
+            login_failures: Default::default(),
+            client_cert_user: opts.client_ca.as_ref().map(|_| opts.client_cert_user),
             writes_temp_dir: HttpHandler::temp_subdir(&opts.temp_directory, opts.allow_writes, "writes"),
+            quota: opts.quota,
+            quota_usage: Default::default(),
+            quota_measured: Condvar::new(),
+            webdav_locks: Default::default(),
             encoded_temp_dir: HttpHandler::temp_subdir(&opts.temp_directory, opts.encode_fs, "encoded"),
             cache_gen: Default::default(),
             cache_fs: Default::default(),
@@ -214,10 +252,10 @@ impl HttpHandler {
             cache_fs_size: Default::default(),
             encoded_filesystem_limit: opts.encoded_filesystem_limit.unwrap_or(u64::MAX),
             encoded_generated_limit: opts.encoded_generated_limit.unwrap_or(u64::MAX),
-            proxies: opts.proxies.clone(),
-            proxy_redirs: opts.proxy_redirs.clone(),
-            mime_type_overrides: opts.mime_type_overrides.clone(),
-            additional_headers: opts.additional_headers.clone(),
+            uploads_in_progress: Default::default(),
+            concurrency: ConcurrencyLimits::new(opts.max_requests, opts.max_client_requests, opts.max_archives),
+            metrics: Default::default(),
+            metrics_path: opts.metrics_path.clone(),
             allowed_methods: allowed_methods,
         }
     }
@@ -254,18 +292,52 @@ impl HttpHandler {
     }
 }
 
-impl Handler for &'static HttpHandler {
-    fn handle(&self, req: &mut Request) -> IronResult<Response> {
-        if self.global_auth_data.is_some() || !self.path_auth_data.is_empty() {
-            if let Some(resp) = self.verify_auth(req)? {
-                return Ok(resp);
+impl HttpHandler {
+    pub fn handle(&'static self, req: &mut ConfiguredRequest) -> IronResult<Response> {
+        self.client_certificate_user(req);
+        let metrics: &'static Metrics = &self.metrics;
+        self.handle_request(req).map(|resp| metrics.count(&req.method, resp)).map_err(|mut err| {
+            err.response = metrics.count(&req.method, err.response);
+            err
+        })
+    }
+
+    fn handle_request(&'static self, req: &mut ConfiguredRequest) -> IronResult<Response> {
+        let slot = match self.concurrency.enter_request(self.remote_addresses(req).client_ip()) {
+            Ok(slot) => slot,
+            Err(limit) => return self.handle_overloaded(req, limit),
+        };
+
+        let config = req.config.clone();
+        let share = match config.share_secret.as_ref() {
+            Some(secret) => self.verify_share(req, secret)?,
+            None => ShareCheck::Absent,
+        };
+        let share_cookie = match share {
+            ShareCheck::Valid(cookie) => cookie,
+            ShareCheck::Rejected(resp) => return Ok(resp),
+            ShareCheck::Absent => {
+                if config.has_auth() {
+                    if let Some(resp) = self.verify_auth(req)? {
+                        return Ok(resp);
+                    }
+                }
+                if let Some(acl) = config.acl.as_ref() {
+                    if let Some(resp) = self.verify_acl(req, acl)? {
+                        return Ok(resp);
+                    }
+                }
+                None
             }
-        }
+        };
 
         let mut resp = match req.method {
+            method::Get | method::Head if self.metrics_path.as_deref() == Some(url_path(&req.url).trim_matches('/')) => self.handle_metrics(req),
+            method::Get if config.share_secret.is_some() && req.url.as_ref().query_pairs().any(|(key, _)| key == "mkshare") => self.handle_mint_share(req),
             method::Options => self.handle_options(req),
             method::Get => self.handle_get(req),
             method::Put => self.handle_put(req),
+            method::Patch => self.handle_patch(req),
             method::Delete => self.handle_delete(req),
             method::Head => {
                 self.handle_get(req).map(|mut r| {
@@ -280,6 +352,9 @@ impl Handler for &'static HttpHandler {
             method::DavMove if self.webdav >= WebDavLevel::MkColMoveOnly => self.handle_webdav_move(req),
             method::DavPropfind if self.webdav >= WebDavLevel::All => self.handle_webdav_propfind(req),
             method::DavProppatch if self.webdav >= WebDavLevel::All => self.handle_webdav_proppatch(req),
+            method::DavLock if self.webdav >= WebDavLevel::All => self.handle_webdav_lock(req),
+            method::DavUnlock if self.webdav >= WebDavLevel::All => self.handle_webdav_unlock(req),
+            method::DavSearch if self.webdav >= WebDavLevel::All => self.handle_webdav_search(req),
 
             method::Post if self.archives => {
                 if let Some(archive_type) = self.parse_post_archive(req) {
@@ -292,91 +367,31 @@ impl Handler for &'static HttpHandler {
             _ => self.handle_bad_method(req),
         }?;
         if self.webdav >= WebDavLevel::All {
-            resp.headers.set(Dav::LEVEL_1);
+            resp.headers.set(Dav::LEVEL_2);
+            if req.method == method::Options {
+                resp.headers.set(Dasl::BASICSEARCH);
+            }
         }
-        for (h, v) in &self.additional_headers {
-            resp.headers.append_raw(&h[..], v[..].into());
+        if let Some(cookie) = share_cookie {
+            resp.headers.set(cookie);
+        }
+        for (h, v) in &config.additional_headers {
+            resp.headers.append_raw(h.clone(), v.clone().into());
+        }
+        if let Some(slot) = slot {
+            resp.body = resp.body.map(|body| Box::new(ConcurrencyLimitedBody(slot, body)) as Box<dyn WriteBody>);
         }
         Ok(resp)
     }
 }
 
 impl HttpHandler {
-    fn verify_auth(&self, req: &mut Request) -> IronResult<Option<Response>> {
-        let mut auth = self.global_auth_data.as_ref();
-
-        if !self.path_auth_data.is_empty() {
-            let mut path = req.url.as_ref().path();
-            if path.starts_with('/') {
-                path = &path[1..];
-            }
-            if path.ends_with('/') {
-                path = &path[..path.len() - 1];
-            }
-
-            while !path.is_empty() {
-                if let Some(pad) = self.path_auth_data.get(path) {
-                    auth = pad.as_ref();
-                    break;
-                }
-
-                path = &path[..path.rfind('/').unwrap_or(0)];
-            }
-        }
-
-        let auth = if let Some(auth) = auth {
-            auth
-        } else {
-            return Ok(None);
-        };
-
-        match req.headers.get() {
-            Some(headers::Authorization(headers::Basic { username, password })) => {
-                let pwd = if password == &Some(String::new()) {
-                    &None
-                } else {
-                    password
-                };
-
-                if &auth.0 == username && &auth.1 == pwd {
-                    log!(self.log,
-                         "{} correctly authorised to {red}{}{reset} {yellow}{}{reset}",
-                         self.remote_addresses(&req),
-                         req.method,
-                         req.url);
-
-                    Ok(None)
-                } else {
-                    log!(self.log,
-                         "{} requested to {red}{}{reset} {yellow}{}{reset} with invalid credentials \"{}{}{}\"",
-                         self.remote_addresses(&req),
-                         req.method,
-                         req.url,
-                         username,
-                         if password.is_some() { ":" } else { "" },
-                         password.as_ref().map_or("", |s| &s[..]));
-
-                    Ok(Some(Response::with((status::Unauthorized, Header(WwwAuthenticate("basic".into())), "Supplied credentials invalid.\n"))))
-                }
-            }
-            None => {
-                log!(self.log,
-                     "{} requested to {red}{}{reset} {yellow}{}{reset} without authorisation",
-                     self.remote_addresses(&req),
-                     req.method,
-                     req.url);
-
-                Ok(Some(Response::with((status::Unauthorized, Header(WwwAuthenticate("basic".into())), "Credentials required.\n"))))
-            }
-        }
-    }
-
-    fn handle_options(&self, req: &mut Request) -> IronResult<Response> {
+    fn handle_options(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
         log!(self.log, "{} asked for {red}OPTIONS{reset}", self.remote_addresses(&req));
         Ok(Response::with((status::NoContent, Header(headers::Server(USER_AGENT.into())), Header(headers::Allow(self.allowed_methods.into())))))
     }
 
-    fn handle_get(&self, req: &mut Request) -> IronResult<Response> {
+    fn handle_get(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
         if self.archives{
             if let Some(archive_type) = self.parse_get_accept_archive(req) {
                 return self.handle_get_archive(req, archive_type);
@@ -406,7 +421,7 @@ impl HttpHandler {
         if is_file {
             if raw_fs {
                 self.handle_get_raw_fs_file(req, req_p)
-            } else if let Some(range) = range {
+            } else if let Some(range) = range.filter(|_| HttpHandler::if_range_matches(req, &req_p)) {
                 self.handle_get_file_range(req, req_p, range)
             } else {
                 self.handle_get_file(req, &req_p, false)
@@ -420,7 +435,7 @@ impl HttpHandler {
         }
     }
 
-    fn handle_invalid_url(&self, req: &mut Request, cause: &str) -> IronResult<Response> {
+    fn handle_invalid_url(&self, req: &mut ConfiguredRequest, cause: &str) -> IronResult<Response> {
         log!(self.log,
              "{} requested to {red}{}{reset} {yellow}{}{reset} with invalid URL -- {}",
              self.remote_addresses(&req),
@@ -432,19 +447,19 @@ impl HttpHandler {
     }
 
     #[inline(always)]
-    fn handle_nonexistent(&self, req: &mut Request, req_p: PathBuf) -> IronResult<Response> {
+    fn handle_nonexistent(&self, req: &mut ConfiguredRequest, req_p: PathBuf) -> IronResult<Response> {
         self.handle_nonexistent_status(req, req_p, status::NotFound)
     }
 
-    fn handle_nonexistent_status(&self, req: &mut Request, req_p: PathBuf, status: status::Status) -> IronResult<Response> {
+    fn handle_nonexistent_status(&self, req: &mut ConfiguredRequest, req_p: PathBuf, status: status::Status) -> IronResult<Response> {
         self.handle_nonexistent_status_impl(req, req_p, status, &None)
     }
 
-    fn handle_nonexistent_get(&self, req: &mut Request, req_p: PathBuf) -> IronResult<Response> {
+    fn handle_nonexistent_get(&self, req: &mut ConfiguredRequest, req_p: PathBuf) -> IronResult<Response> {
         self.handle_nonexistent_status_impl(req, req_p, status::NotFound, &self.try_404)
     }
 
-    fn handle_nonexistent_status_impl(&self, req: &mut Request, req_p: PathBuf, status: status::Status, try_404: &Option<PathBuf>) -> IronResult<Response> {
+    fn handle_nonexistent_status_impl(&self, req: &mut ConfiguredRequest, req_p: PathBuf, status: status::Status, try_404: &Option<PathBuf>) -> IronResult<Response> {
         log!(self.log,
              "{} requested to {red}{}{reset} nonexistent entity {magenta}{}{reset}",
              self.remote_addresses(&req),
@@ -465,14 +480,14 @@ impl HttpHandler {
                                                            ""))
     }
 
-    fn handle_get_raw_fs_file(&self, req: &mut Request, req_p: PathBuf) -> IronResult<Response> {
+    fn handle_get_raw_fs_file(&self, req: &mut ConfiguredRequest, req_p: PathBuf) -> IronResult<Response> {
         log!(self.log,
              "{} was served metadata for file {magenta}{}{reset}",
              self.remote_addresses(&req),
              req_p.display());
         self.handle_raw_fs_api_response(status::Ok,
                                         &FilesetData {
-                                            writes_supported: self.writes_temp_dir.is_some(),
+                                            writes_supported: self.writes_temp_dir.is_some() && req.config.acl_rights(req.user.as_deref(), &url_path(&req.url)).write,
                                             is_root: false,
                                             is_file: true,
                                             files: vec![get_raw_fs_metadata(&req_p)],
@@ -497,53 +512,86 @@ impl HttpHandler {
         return false;
     }
 
-    fn handle_get_file_range(&self, req: &mut Request, req_p: PathBuf, range: headers::Range) -> IronResult<Response> {
+    /// [If-Range](https://tools.ietf.org/html/rfc7233#section-3.2): whether the Range header is to be honoured
+    ///
+    /// An entity tag matches strongly either the whole-file ETag or the ETag of any range thereof.
+    fn if_range_matches(req: &Request, req_p: &Path) -> bool {
+        match req.headers.get::<headers::IfRange>() {
+            Some(headers::IfRange::EntityTag(tag)) => {
+                let etag = file_etag(&req_p.metadata().expect("Failed to get requested file metadata"));
+                !tag.weak && tag.tag().strip_prefix(&etag[..]).map(|rest| rest.is_empty() || rest.starts_with('+')).unwrap_or(false)
+            }
+            Some(headers::IfRange::Date(date)) => file_time_modified_p(req_p).timestamp() == date.0.timestamp(),
+            None => true,
+        }
+    }
+
+    /// [If-Match](https://tools.ietf.org/html/rfc7232#section-3.1), or, failing that,
+    /// [If-Unmodified-Since](https://tools.ietf.org/html/rfc7232#section-3.4): `false` if the request should fail with 412
+    fn preconditions_hold(req: &Request, req_p: &Path) -> bool {
+        let metadata = req_p.metadata().ok();
+        if let Some(im) = req.headers.get::<headers::IfMatch>() {
+            return match (im, metadata) {
+                (headers::IfMatch::Any, metadata) => metadata.is_some(),
+                (headers::IfMatch::Items(tags), Some(metadata)) => {
+                    let etag = file_etag(&metadata);
+                    tags.iter().any(|tag| !tag.weak && tag.tag() == etag)
+                }
+                (headers::IfMatch::Items(_), None) => false,
+            };
+        }
+
+        if let (Some(headers::IfUnmodifiedSince(since)), Some(metadata)) = (req.headers.get::<headers::IfUnmodifiedSince>(), metadata) {
+            return file_time_modified(&metadata).timestamp() <= since.0.timestamp();
+        }
+
+        true
+    }
+
+    fn handle_get_file_range(&self, req: &mut ConfiguredRequest, req_p: PathBuf, range: headers::Range) -> IronResult<Response> {
         match range {
-            headers::Range::Bytes(ref brs) => {
-                if brs.len() == 1 {
-                    let metadata = req_p.metadata().expect("Failed to get requested file metadata");
-                    let flen = file_length(&metadata, &req_p);
-
-                    let mut etag = file_etag(&metadata).into_bytes(); // normaletag+123-41231
-                    let _ = write!(&mut etag, "+{}", brs[0]);
-                    let etag = unsafe { String::from_utf8_unchecked(etag) };
-                    if HttpHandler::should_304_path(req, &req_p, &etag) {
-                        log!(self.log, "{} Not Modified", self.remote_addresses(req));
-                        return Ok(Response::with((status::NotModified,
-                                                  (Header(headers::Server(USER_AGENT.into())),
-                                                   Header(headers::LastModified(headers::HttpDate(file_time_modified_p(&req_p).into()))),
-                                                   Header(headers::AcceptRanges(headers::RangeUnit::Bytes))),
-                                                  Header(headers::ETag(headers::EntityTag::strong(etag))))));
-                    }
+            headers::Range::Bytes(ref brs) if brs.len() == 1 => {
+                let metadata = req_p.metadata().expect("Failed to get requested file metadata");
+                let flen = file_length(&metadata, &req_p);
+
+                let mut etag = file_etag(&metadata).into_bytes(); // normaletag+123-41231
+                let _ = write!(&mut etag, "+{}", brs[0]);
+                let etag = unsafe { String::from_utf8_unchecked(etag) };
+                if HttpHandler::should_304_path(req, &req_p, &etag) {
+                    log!(self.log, "{} Not Modified", self.remote_addresses(req));
+                    return Ok(Response::with((status::NotModified,
+                                              (Header(headers::Server(USER_AGENT.into())),
+                                               Header(headers::LastModified(headers::HttpDate(file_time_modified_p(&req_p).into()))),
+                                               Header(headers::AcceptRanges(headers::RangeUnit::Bytes))),
+                                              Header(headers::ETag(headers::EntityTag::strong(etag))))));
+                }
 
-                    match brs[0] {
-                        // Cases where from is bigger than to are filtered out by iron so can never happen
-                        headers::ByteRangeSpec::FromTo(from, to) => self.handle_get_file_closed_range(req, req_p, from, to, etag),
-                        headers::ByteRangeSpec::AllFrom(from) => {
-                            if flen < from {
-                                self.handle_get_file_empty_range(req, req_p, from, flen, etag)
-                            } else {
-                                self.handle_get_file_right_opened_range(req, req_p, from, etag)
-                            }
+                match brs[0] {
+                    // Cases where from is bigger than to are filtered out by iron so can never happen
+                    headers::ByteRangeSpec::FromTo(from, to) => self.handle_get_file_closed_range(req, req_p, from, to, etag),
+                    headers::ByteRangeSpec::AllFrom(from) => {
+                        if flen < from {
+                            self.handle_get_file_empty_range(req, req_p, from, flen, etag)
+                        } else {
+                            self.handle_get_file_right_opened_range(req, req_p, from, etag)
                         }
-                        headers::ByteRangeSpec::Last(from) => {
-                            if flen < from {
-                                self.handle_get_file_empty_range(req, req_p, from, flen, etag)
-                            } else {
-                                self.handle_get_file_left_opened_range(req, req_p, from, etag)
-                            }
+                    }
+                    headers::ByteRangeSpec::Last(from) => {
+                        if flen < from {
+                            self.handle_get_file_empty_range(req, req_p, from, flen, etag)
+                        } else {
+                            self.handle_get_file_left_opened_range(req, req_p, from, etag)
                         }
                     }
-                } else {
-                    self.handle_invalid_range(req, req_p, &range, "More than one range is unsupported.")
                 }
             }
+            headers::Range::Bytes(brs) => self.handle_get_file_multi_range(req, req_p, brs),
             headers::Range::Unregistered(..) => self.handle_invalid_range(req, req_p, &range, "Custom ranges are unsupported."),
         }
     }
 
-    fn handle_get_file_closed_range(&self, req: &mut Request, req_p: PathBuf, from: u64, to: u64, etag: String) -> IronResult<Response> {
-        let mime_type = self.guess_mime_type(&req_p);
+    fn handle_get_file_closed_range(&self, req: &mut ConfiguredRequest, req_p: PathBuf, from: u64, to: u64, etag: String) -> IronResult<Response> {
+        let mime_type = self.guess_mime_type(req, &req_p);
         log!(self.log,
              "{} was served byte range {}-{} of file {magenta}{}{reset} as {blue}{}{reset}",
              self.remote_addresses(&req),
@@ -569,8 +617,8 @@ impl HttpHandler {
                            Header(headers::ContentLength(to + 1 - from)))))
     }
 
-    fn handle_get_file_right_opened_range(&self, req: &mut Request, req_p: PathBuf, from: u64, etag: String) -> IronResult<Response> {
-        let mime_type = self.guess_mime_type(&req_p);
+    fn handle_get_file_right_opened_range(&self, req: &mut ConfiguredRequest, req_p: PathBuf, from: u64, etag: String) -> IronResult<Response> {
+        let mime_type = self.guess_mime_type(req, &req_p);
         log!(self.log,
              "{} was served file {magenta}{}{reset} from byte {} as {blue}{}{reset}",
              self.remote_addresses(&req),
@@ -581,8 +629,8 @@ impl HttpHandler {
         self.handle_get_file_opened_range(req_p, |flen| (SeekFrom::Start(from), from, flen - from), mime_type, etag)
     }
 
-    fn handle_get_file_left_opened_range(&self, req: &mut Request, req_p: PathBuf, from: u64, etag: String) -> IronResult<Response> {
-        let mime_type = self.guess_mime_type(&req_p);
+    fn handle_get_file_left_opened_range(&self, req: &mut ConfiguredRequest, req_p: PathBuf, from: u64, etag: String) -> IronResult<Response> {
+        let mime_type = self.guess_mime_type(req, &req_p);
         log!(self.log,
              "{} was served last {} bytes of file {magenta}{}{reset} as {blue}{}{reset}",
              self.remote_addresses(&req),
@@ -614,7 +662,79 @@ impl HttpHandler {
                            mt)))
     }
 
-    fn handle_invalid_range(&self, req: &mut Request, req_p: PathBuf, range: &headers::Range, reason: &str) -> IronResult<Response> {
+    fn handle_get_file_multi_range(&self, req: &mut ConfiguredRequest, req_p: PathBuf, brs: Vec<headers::ByteRangeSpec>) -> IronResult<Response> {
+        let metadata = req_p.metadata().expect("Failed to get requested file metadata");
+        let flen = file_length(&metadata, &req_p);
+
+        let mut etag = file_etag(&metadata).into_bytes(); // normaletag+123-41231,-100
+        for (i, br) in brs.iter().enumerate() {
+            let _ = write!(&mut etag, "{}{}", if i == 0 { '+' } else { ',' }, br);
+        }
+        let etag = unsafe { String::from_utf8_unchecked(etag) };
+        if HttpHandler::should_304_path(req, &req_p, &etag) {
+            log!(self.log, "{} Not Modified", self.remote_addresses(req));
+            return Ok(Response::with((status::NotModified,
+                                      (Header(headers::Server(USER_AGENT.into())),
+                                       Header(headers::LastModified(headers::HttpDate(file_time_modified(&metadata).into()))),
+                                       Header(headers::AcceptRanges(headers::RangeUnit::Bytes))),
+                                      Header(headers::ETag(headers::EntityTag::strong(etag))))));
+        }
+
+        let ranges = coalesce_byte_ranges(&brs, flen);
+        match ranges.len() {
+            0 => {
+                self.handle_invalid_range(req, req_p, &headers::Range::Bytes(brs), "None of the ranges overlap the file.").map(|mut r| {
+                    r.headers.set(headers::ContentRange(headers::ContentRangeSpec::Bytes {
+                        range: None,
+                        instance_length: Some(flen),
+                    }));
+                    r
+                })
+            }
+            1 => self.handle_get_file_closed_range(req, req_p, ranges[0].0, ranges[0].1, etag),
+            _ => self.handle_get_file_multipart_range(req, req_p, ranges, flen, metadata, etag),
+        }
+    }
+
+    fn handle_get_file_multipart_range(&self, req: &mut ConfiguredRequest, req_p: PathBuf, ranges: Vec<(u64, u64)>, flen: u64, metadata: fs::Metadata, etag: String)
+                                       -> IronResult<Response> {
+        let mime_type = self.guess_mime_type(req, &req_p);
+        log!(self.log,
+             "{} was served byte ranges {} of file {magenta}{}{reset} as {blue}{}{reset}",
+             self.remote_addresses(req),
+             CommaList(ranges.iter().map(|&(from, to)| DisplayThree(from, "-", to))),
+             req_p.display(),
+             mime_type);
+
+        let f = match File::open(&req_p) {
+            Ok(f) => f,
+            Err(err) => return self.handle_requested_entity_unopenable(req, err, "file"),
+        };
+
+        let rnd = RandomState::new();
+        let boundary = format!("{:016x}{:016x}", rnd.hash_one(0), rnd.hash_one(1));
+        let parts = ranges.into_iter()
+            .map(|(from, to)| (format!("\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n", boundary, mime_type, from, to, flen), from, to))
+            .collect::<Vec<_>>();
+        let trailer = format!("\r\n--{}--\r\n", boundary);
+        let clen = parts.iter().map(|(head, from, to)| head.len() as u64 + (to + 1 - from)).sum::<u64>() + trailer.len() as u64;
+
+        Ok(Response::with((status::PartialContent,
+                           (Header(headers::Server(USER_AGENT.into())),
+                            Header(headers::LastModified(headers::HttpDate(file_time_modified(&metadata).into()))),
+                            Header(headers::ETag(headers::EntityTag::strong(etag))),
+                            Header(headers::AcceptRanges(headers::RangeUnit::Bytes))),
+                           // multipart/byteranges; boundary=...
+                           Mime(MimeTopLevel::Multipart, MimeSubLevel::Ext("byteranges".to_string()), vec![(MimeAttr::Ext("boundary".to_string()), MimeAttrValue::Ext(boundary))]),
+                           Box::new(MultipartByteRangesBody {
+                               file: f,
+                               parts,
+                               trailer,
+                           }) as Box<dyn WriteBody>,
+                           Header(headers::ContentLength(clen)))))
+    }
+
+    fn handle_invalid_range(&self, req: &mut ConfiguredRequest, req_p: PathBuf, range: &headers::Range, reason: &str) -> IronResult<Response> {
         self.handle_generated_response_encoding(req,
                                                 status::RangeNotSatisfiable,
                                                 error_html("416 Range Not Satisfiable",
@@ -624,8 +744,8 @@ impl HttpHandler {
                                                            reason))
     }
 
-    fn handle_get_file_empty_range(&self, req: &mut Request, req_p: PathBuf, from: u64, to: u64, etag: String) -> IronResult<Response> {
-        let mime_type = self.guess_mime_type(&req_p);
+    fn handle_get_file_empty_range(&self, req: &mut ConfiguredRequest, req_p: PathBuf, from: u64, to: u64, etag: String) -> IronResult<Response> {
+        let mime_type = self.guess_mime_type(req, &req_p);
         log!(self.log,
              "{} was served an empty range from file {magenta}{}{reset} as {blue}{}{reset}",
              self.remote_addresses(&req),
@@ -644,8 +764,8 @@ impl HttpHandler {
                            mime_type)))
     }
 
-    fn handle_get_file(&self, req: &mut Request, req_p: &PathBuf, is_404: bool) -> IronResult<Response> {
-        let mime_type = self.guess_mime_type(&req_p);
+    fn handle_get_file(&self, req: &mut ConfiguredRequest, req_p: &PathBuf, is_404: bool) -> IronResult<Response> {
+        let mime_type = self.guess_mime_type(req, &req_p);
         log!(self.log,
              "{} was served file {magenta}{}{reset} as {blue}{}{reset}",
              self.remote_addresses(&req).maybe_spaces(is_404),
@@ -680,7 +800,7 @@ impl HttpHandler {
         }
     }
 
-    fn handle_get_file_encoded(&self, req: &mut Request, req_p: &PathBuf, mt: Mime,
+    fn handle_get_file_encoded(&self, req: &mut ConfiguredRequest, req_p: &PathBuf, mt: Mime,
                                headers: (Header<headers::Server>, Header<headers::LastModified>, Header<headers::AcceptRanges>), etag: String)
                                -> IronResult<Response> {
         if let Some(encoding) = req.headers.get_mut::<headers::AcceptEncoding>().and_then(|es| response_encoding(&mut **es)) {
@@ -707,6 +827,7 @@ impl HttpHandler {
                         match File::open(resp_p) {
                             Ok(resp) => {
                                 atime.store(precise_time_ns(), AtomicOrdering::Relaxed);
+                                Metrics::add(&self.metrics.cache_fs_hits, 1);
                                 log!(self.log,
                                      "{} encoded as {} for {:.1}% ratio (cached)",
                                      self.remote_addresses(req).as_spaces(),
@@ -730,6 +851,7 @@ impl HttpHandler {
                         }
                     }
                     Some(&((_, false, _), _)) => {
+                        Metrics::add(&self.metrics.cache_fs_hits, 1);
                         let file = match File::open(&req_p) {
                             Ok(file) => file,
                             Err(err) => return self.handle_requested_entity_unopenable(req, err, "file"),
@@ -744,6 +866,7 @@ impl HttpHandler {
                 self.cache_fs.write().expect("Filesystem cache write lock poisoned").remove(&cache_key);
                 return self.handle_get_file_encoded(req, req_p, mt, headers, etag);
             }
+            Metrics::add(&self.metrics.cache_fs_misses, 1);
 
             let mut resp_p = self.encoded_temp_dir.as_ref().unwrap().1.join(cache_key.0.to_hex().as_str());
             match (req_p.extension(), encoding_extension(&encoding)) {
@@ -802,14 +925,16 @@ impl HttpHandler {
                            mt)))
     }
 
-    fn handle_get_raw_fs_dir(&self, req: &mut Request, req_p: PathBuf) -> IronResult<Response> {
+    fn handle_get_raw_fs_dir(&self, req: &mut ConfiguredRequest, req_p: PathBuf) -> IronResult<Response> {
         log!(self.log,
              "{} was served metadata for directory {magenta}{}{reset}",
              self.remote_addresses(&req),
              req_p.display());
+        let relpath = url_path(&req.url);
+        let rights = req.config.acl_rights(req.user.as_deref(), &relpath);
         self.handle_raw_fs_api_response(status::Ok,
                                         &FilesetData {
-                                            writes_supported: self.writes_temp_dir.is_some(),
+                                            writes_supported: self.writes_temp_dir.is_some() && rights.write,
                                             is_root: req.url.as_ref().path_segments().unwrap().count() + !req.url.as_ref().as_str().ends_with('/') as usize ==
                                                      1,
                                             is_file: false,
@@ -823,7 +948,8 @@ impl HttpHandler {
                        {
                         symlink = is_symlink(&fp);
                         symlink
-                    }) || (self.follow_symlinks && self.sandbox_symlinks && symlink && !is_descendant_of(fp, &self.hosted_directory.1)))
+                    }) || (self.follow_symlinks && self.sandbox_symlinks && symlink && !is_descendant_of(fp, &self.hosted_directory.1))) &&
+                    req.config.acl_rights(req.user.as_deref(), &format!("{}/{}", relpath.trim_end_matches('/'), f.file_name().to_string_lossy())).read
                 })
                                                 .map(|f| {
                     let is_file = is_actually_file(&f.file_type().expect("Failed to get file type"), &f.path());
@@ -843,7 +969,7 @@ impl HttpHandler {
                                         })
     }
 
-    fn handle_get_dir(&self, req: &mut Request, req_p: PathBuf) -> IronResult<Response> {
+    fn handle_get_dir(&self, req: &mut ConfiguredRequest, req_p: PathBuf) -> IronResult<Response> {
         if self.check_indices {
             let mut idx = req_p.join("index");
             if let Some(e) = INDEX_EXTENSIONS.iter()
@@ -884,9 +1010,9 @@ impl HttpHandler {
     }
 
     /// Try to resolve any X-Original-URL headers for a redirect, else raw `/loca/tion` from request
-    fn user_facing_request_url(&self, req: &Request) -> String {
-        for (network, header) in &self.proxy_redirs {
-            if network.contains(&req.remote_addr.ip()) {
+    fn user_facing_request_url(&self, req: &ConfiguredRequest) -> String {
+        for (network, header) in &req.config.proxy_redirs {
+            if network.contains(&req.remote_addr.ip()) && req.remote_addr != UNIX_SOCKET_ADDRESS {
                 if let Some(saddrs) = req.headers.get_raw(header) {
                     if saddrs.len() > 0 {
                         if let Ok(s) = str::from_utf8(&saddrs[0]) {
@@ -900,7 +1026,7 @@ impl HttpHandler {
         req.url.to_string()
     }
 
-    fn handle_get_dir_index_no_slash(&self, req: &mut Request, idx_ext: &str) -> IronResult<Response> {
+    fn handle_get_dir_index_no_slash(&self, req: &mut ConfiguredRequest, idx_ext: &str) -> IronResult<Response> {
         let new_url = HttpHandler::slashise(self.user_facing_request_url(req));
         log!(self.log,
              "Redirecting {} to {yellow}{}{reset} - found index file {magenta}index.{}{reset}",
@@ -917,14 +1043,17 @@ impl HttpHandler {
         Ok(Response::with((status::SeeOther, Header(headers::Server(USER_AGENT.into())), Header(headers::Location(new_url)))))
     }
 
-    fn handle_get_mobile_dir_listing(&self, req: &mut Request, req_p: PathBuf) -> IronResult<Response> {
+    fn handle_get_mobile_dir_listing(&self, req: &mut ConfiguredRequest, req_p: PathBuf) -> IronResult<Response> {
         let relpath = url_path(&req.url);
         let is_root = relpath == "/";
         let mut relpath_escaped = escape_specials(&relpath);
         if relpath_escaped.as_bytes().last() != Some(&b'/') {
             relpath_escaped.to_mut().push('/');
         }
-        let show_file_management_controls = self.writes_temp_dir.is_some();
+        let user = req.user.clone();
+        let config = req.config.clone();
+        let rights = config.acl_rights(user.as_deref(), &relpath);
+        let show_file_management_controls = self.writes_temp_dir.is_some() && (rights.write || rights.delete);
         log!(self.log,
              "{} was served mobile directory listing for {magenta}{}{reset}",
              self.remote_addresses(&req),
@@ -956,7 +1085,8 @@ impl HttpHandler {
                        {
                         symlink = is_symlink(&fp);
                         symlink
-                    }) || (self.follow_symlinks && self.sandbox_symlinks && symlink && !is_descendant_of(fp, &self.hosted_directory.1)))
+                    }) || (self.follow_symlinks && self.sandbox_symlinks && symlink && !is_descendant_of(fp, &self.hosted_directory.1))) &&
+                    config.acl_rights(user.as_deref(), &format!("{}/{}", relpath.trim_end_matches('/'), f.file_name().to_string_lossy())).read
                 })
                 .collect::<Vec<_>>();
             list.sort_by(|lhs, rhs| {
@@ -971,6 +1101,7 @@ impl HttpHandler {
                 let fname = f.file_name().into_string().expect("Failed to get file name");
                 let path = f.path();
                 let modified = file_time_modified(&fmeta);
+                let entry_rights = config.acl_rights(user.as_deref(), &format!("{}/{}", relpath.trim_end_matches('/'), fname));
 
                 let _ = writeln!(out,
                                  concat!(r#"<a href="{path}{fname}" id="{}"><div><span class="{}{}_icon">{}{}</span>{}</div>"#,
@@ -980,9 +1111,9 @@ impl HttpHandler {
                                  file_icon_suffix(&path, is_file),
                                  NoHtmlLiteral(&fname),
                                  if is_file { "" } else { "/" },
-                                 if show_file_management_controls {
+                                 if show_file_management_controls && entry_rights.delete {
                                      DisplayThree(r#"<span class="manage"><span class="delete_file_icon" onclick="delete_onclick(arguments[0])">Delete</span>"#,
-                                                  if self.webdav >= WebDavLevel::MkColMoveOnly {
+                                                  if self.webdav >= WebDavLevel::MkColMoveOnly && rights.write {
                                                       r#" <span class="rename_icon" onclick="rename_onclick(arguments[0])">Rename</span>"#
                                                   } else {
                                                       ""
@@ -1024,13 +1155,13 @@ impl HttpHandler {
                                                                               },
                                                                               parent_f,
                                                                               list_f,
-                                                                              if show_file_management_controls {
+                                                                              if show_file_management_controls && rights.write {
                                                                                   concat!(r#"<span class="heading">Upload files: "#,
                                                                                           r#"<input type="file" multiple /></span>"#)
                                                                               } else {
                                                                                   ""
                                                                               },
-                                                                              if show_file_management_controls && self.webdav >= WebDavLevel::MkColMoveOnly {
+                                                                              if show_file_management_controls && rights.write && self.webdav >= WebDavLevel::MkColMoveOnly {
                                                                                   r#"<a id='new"directory' href><span class="new_dir_icon">Create directory</span></a>"#
                                                                               } else {
                                                                                   ""
@@ -1046,14 +1177,17 @@ impl HttpHandler {
                                                                               }))
     }
 
-    fn handle_get_dir_listing(&self, req: &mut Request, req_p: PathBuf) -> IronResult<Response> {
+    fn handle_get_dir_listing(&self, req: &mut ConfiguredRequest, req_p: PathBuf) -> IronResult<Response> {
         let relpath = url_path(&req.url);
         let is_root = relpath == "/";
         let mut relpath_escaped = escape_specials(&relpath);
         if relpath_escaped.as_bytes().last() != Some(&b'/') {
             relpath_escaped.to_mut().push('/');
         }
-        let show_file_management_controls = self.writes_temp_dir.is_some();
+        let user = req.user.clone();
+        let config = req.config.clone();
+        let rights = config.acl_rights(user.as_deref(), &relpath);
+        let show_file_management_controls = self.writes_temp_dir.is_some() && (rights.write || rights.delete);
         log!(self.log,
              "{} was served directory listing for {magenta}{}{reset}",
              self.remote_addresses(&req),
@@ -1090,7 +1224,8 @@ impl HttpHandler {
                        {
                         symlink = is_symlink(&fp);
                         symlink
-                    }) || (self.follow_symlinks && self.sandbox_symlinks && symlink && !is_descendant_of(fp, &self.hosted_directory.1)))
+                    }) || (self.follow_symlinks && self.sandbox_symlinks && symlink && !is_descendant_of(fp, &self.hosted_directory.1))) &&
+                    config.acl_rights(user.as_deref(), &format!("{}/{}", relpath.trim_end_matches('/'), f.file_name().to_string_lossy())).read
                 })
                 .collect::<Vec<_>>();
             list.sort_by(|lhs, rhs| {
@@ -1104,6 +1239,7 @@ impl HttpHandler {
                 let is_file = is_actually_file(&f.file_type().expect("Failed to get file type"), &path);
                 let fmeta = f.metadata().expect("Failed to get requested file metadata");
                 let fname = f.file_name().into_string().expect("Failed to get file name");
+                let entry_rights = config.acl_rights(user.as_deref(), &format!("{}/{}", relpath.trim_end_matches('/'), fname));
                 let len = file_length(&fmeta, &path);
                 let modified = file_time_modified(&fmeta);
                 struct FileSizeDisplay(bool, u64);
@@ -1135,14 +1271,16 @@ impl HttpHandler {
                                    Maybe(None)
                                },
                                if is_file { "</abbr>" } else { "" },
-                               if show_file_management_controls {
+                               if show_file_management_controls && entry_rights.delete {
                                    DisplayThree("<td><a href class=\"delete_file_icon\" onclick=\"delete_onclick(arguments[0])\">Delete</a>",
-                                                if self.webdav >= WebDavLevel::MkColMoveOnly {
+                                                if self.webdav >= WebDavLevel::MkColMoveOnly && rights.write {
                                                     " <a href class=\"rename_icon\" onclick=\"rename_onclick(arguments[0])\">Rename</a>"
                                                 } else {
                                                     ""
                                                 },
                                                 "</td>")
+                               } else if show_file_management_controls {
+                                   DisplayThree("<td>", "", "</td>")
                                } else {
                                    DisplayThree("", "", "")
                                },
@@ -1175,7 +1313,7 @@ impl HttpHandler {
                                                                        },
                                                                        parent_f,
                                                                        list_f,
-                                                                       if show_file_management_controls {
+                                                                       if show_file_management_controls && rights.write {
                                                                            "<hr />\
                                                                             <p>Upload via drag&drop, paste, or <input type=\"file\" multiple />.</p>"
                                                                        } else {
@@ -1186,7 +1324,7 @@ impl HttpHandler {
                                                                        } else {
                                                                            ""
                                                                        },
-                                                                       if show_file_management_controls && self.webdav >= WebDavLevel::MkColMoveOnly {
+                                                                       if show_file_management_controls && rights.write && self.webdav >= WebDavLevel::MkColMoveOnly {
                                                                            "<tr id=\'new\"directory\'><td><a tabindex=\"-1\" href \
                                                                             class=\"new_dir_icon\"></a></td><td colspan=3><a href>Create \
                                                                             directory</a></td><td><a tabindex=\"-1\" href>&nbsp;</a></td></tr>"
@@ -1205,7 +1343,7 @@ impl HttpHandler {
                                                                        }))
     }
 
-    fn handle_put(&self, req: &mut Request) -> IronResult<Response> {
+    fn handle_put(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
         if self.writes_temp_dir.is_none() {
             return self.handle_forbidden_method(req, "-w", "write requests");
         }
@@ -1226,11 +1364,29 @@ impl HttpHandler {
             if illegal {
                 return self.handle_nonexistent(req, req_p);
             }
-            self.handle_put_file(req, req_p)
+            if !HttpHandler::preconditions_hold(req, &req_p) {
+                return self.handle_precondition_failed(req, req_p);
+            }
+            if let Some(resp) = self.check_webdav_locks(req, &req_p, !req_p.exists()) {
+                return resp;
+            }
+            let length = match req.headers.get::<headers::ContentLength>() {
+                Some(cl) => cl.0,
+                None if self.quota.is_some() => return self.handle_put_length_required(req),
+                None => 0,
+            };
+            let allowed = match self.check_quota(req, &req_p, length) {
+                Ok(allowed) => allowed,
+                Err(resp) => return resp,
+            };
+            let replaced = self.quota.map(|_| file_size(&req_p)).unwrap_or(0);
+            let resp = self.handle_put_file(req, req_p.clone());
+            self.quota_written(allowed, self.quota.map(|_| file_size(&req_p) as i64 - replaced as i64).unwrap_or(0));
+            resp
         }
     }
 
-    fn handle_disallowed_method(&self, req: &mut Request, tpe: &str) -> IronResult<Response> {
+    fn handle_disallowed_method(&self, req: &mut ConfiguredRequest, tpe: &str) -> IronResult<Response> {
         log!(self.log,
              "{} tried to {red}{}{reset} on {magenta}{}{reset} ({blue}{}{reset}) but only {red}{}{reset} are allowed",
              self.remote_addresses(&req),
@@ -1249,7 +1405,7 @@ impl HttpHandler {
             })
     }
 
-    fn handle_put_partial_content(&self, req: &mut Request) -> IronResult<Response> {
+    fn handle_put_partial_content(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
         log!(self.log,
              "{} tried to {red}PUT{reset} partial content to {yellow}{}{reset}",
              self.remote_addresses(&req),
@@ -1260,10 +1416,23 @@ impl HttpHandler {
                                                 error_html("400 Bad Request",
                                                            "<a href=\"https://tools.ietf.org/html/rfc7231#section-4.3.3\">RFC7231 forbids partial-content \
                                                             PUT requests.</a>",
-                                                           ""))
+                                                           "<p>Resumable uploads use PATCH with X-Upload-Offset and X-Upload-Length.</p>"))
     }
 
-    fn handle_put_file(&self, req: &mut Request, req_p: PathBuf) -> IronResult<Response> {
+    fn handle_put_length_required(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
+        log!(self.log,
+             "{} tried to {red}PUT{reset} {yellow}{}{reset} without a Content-Length",
+             self.remote_addresses(req),
+             url_path(&req.url));
+
+        self.handle_generated_response_encoding(req,
+                                                status::LengthRequired,
+                                                error_html("411 Length Required",
+                                                           "Uploads must have a Content-Length.",
+                                                           "<p>The upload size is needed to check it against the quota.</p>"))
+    }
+
+    fn handle_put_file(&self, req: &mut ConfiguredRequest, req_p: PathBuf) -> IronResult<Response> {
         let _ = fs::create_dir_all(req_p.parent().expect("Failed to get requested file's parent directory"));
         let direct_output = File::create_new(&req_p);
 
@@ -1278,7 +1447,8 @@ impl HttpHandler {
              mtime.map_or("", |_| ". modified: "),
              Maybe(mtime.map(MsAsSAnd3339)));
 
-        let mut ibuf = BufReader::with_capacity(1024 * 1024, &mut req.body);
+        let upload_buckets = self.upload_buckets(req);
+        let mut ibuf = BufReader::with_capacity(1024 * 1024, LimitBandwidthReader::new(upload_buckets, CountingReader::new(&self.metrics.upload_bytes, &mut req.body)));
         let file = match direct_output {
             Ok(mut file) => {
                 if let Err(err) = io::copy(&mut ibuf, &mut file) {
@@ -1336,14 +1506,215 @@ impl HttpHandler {
                            Header(headers::Server(USER_AGENT.into())))))
     }
 
-    fn handle_put_error(&self, req: &mut Request, res: &str, err: IoError) -> IronResult<Response> {
+    fn handle_put_error(&self, req: &mut ConfiguredRequest, res: &str, err: IoError) -> IronResult<Response> {
         log!(self.log, "{} {} {}", self.remote_addresses(req).as_spaces(), res, err);
         return self.handle_generated_response_encoding(req,
                                                        status::ServiceUnavailable,
                                                        error_html("503 Service Unavailable", res, format_args!("{}", err)));
     }
 
-    fn handle_delete(&self, req: &mut Request) -> IronResult<Response> {
+    fn handle_patch(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
+        if self.writes_temp_dir.is_none() {
+            return self.handle_forbidden_method(req, "-w", "write requests");
+        }
+
+        let (req_p, symlink, url_err) = self.parse_requested_path(req);
+
+        if url_err {
+            self.handle_invalid_url(req, "<p>Percent-encoding decoded to invalid UTF-8.</p>")
+        } else if req_p.is_dir() {
+            self.handle_disallowed_method(req, "directory")
+        } else if detect_file_as_dir(&req_p) {
+            self.handle_invalid_url(req, "<p>Attempted to use file as directory.</p>")
+        } else {
+            let illegal = symlink && (!self.follow_symlinks || (self.sandbox_symlinks && !is_nonexistent_descendant_of(&req_p, &self.hosted_directory.1)));
+            if illegal {
+                return self.handle_nonexistent(req, req_p);
+            }
+            if !HttpHandler::preconditions_hold(req, &req_p) {
+                return self.handle_precondition_failed(req, req_p);
+            }
+            if let Some(resp) = self.check_webdav_locks(req, &req_p, !req_p.exists()) {
+                return resp;
+            }
+            match (req.headers.get::<XUploadOffset>().cloned(), req.headers.get::<XUploadLength>().cloned()) {
+                (Some(XUploadOffset(offset)), Some(XUploadLength(length))) => self.handle_patch_upload(req, req_p, offset, length),
+                _ => self.handle_invalid_upload(req, "<p>Resumable uploads require X-Upload-Offset and X-Upload-Length.</p>"),
+            }
+        }
+    }
+
+    fn handle_patch_upload(&self, req: &mut ConfiguredRequest, req_p: PathBuf, offset: u64, length: u64) -> IronResult<Response> {
+        let mtime = req.headers.get::<XLastModified>().map(|xlm| xlm.0).or_else(|| req.headers.get::<XOcMTime>().map(|xocmt| xocmt.0 * 1000));
+        if offset > length || req.headers.get::<headers::ContentLength>().map(|cl| offset.checked_add(cl.0).map(|end| end > length).unwrap_or(true)).unwrap_or(false) {
+            return self.handle_invalid_upload(req, "<p>Upload data past X-Upload-Length.</p>");
+        }
+
+        // The same file uploaded to the same place resumes, anything else starts over
+        let upload_id = {
+            let mut hasher = blake3::Hasher::new();
+            hasher.update(req_p.as_os_str().as_encoded_bytes());
+            hasher.update(&length.to_le_bytes());
+            hasher.update(&mtime.map(|ms| ms.to_le_bytes()).unwrap_or_default());
+            hasher.finalize()
+        };
+
+        self.create_temp_dir(&self.writes_temp_dir);
+        let (_, temp_dir) = self.writes_temp_dir.as_ref().unwrap();
+        let partial_p = temp_dir.join(format!("{}.partial", upload_id.to_hex()));
+        if offset == 0 {
+            self.prune_partial_uploads(temp_dir);
+        }
+
+        struct UploadInProgress<'a>(&'a Mutex<HashSet<blake3::Hash>>, blake3::Hash);
+        impl<'a> Drop for UploadInProgress<'a> {
+            fn drop(&mut self) {
+                self.0.lock().expect("Failed to unlock uploads in progress").remove(&self.1);
+            }
+        }
+
+        if !self.uploads_in_progress.lock().expect("Failed to lock uploads in progress").insert(upload_id) {
+            return self.handle_upload_offset_conflict(req, req_p, offset, fs::metadata(&partial_p).map(|m| m.len()).unwrap_or(0));
+        }
+        let _upload_in_progress = UploadInProgress(&self.uploads_in_progress, upload_id);
+
+        let mut partial = match File::options().create(true).append(true).open(&partial_p) {
+            Ok(f) => f,
+            Err(err) => return self.handle_put_error(req, "Upload not resumable.", err),
+        };
+        let stored = partial.metadata().map(|m| m.len()).unwrap_or(0);
+        if offset != stored {
+            return self.handle_upload_offset_conflict(req, req_p, offset, stored);
+        }
+
+        // Each request is allowed what's left, and counts what it adds to the partial upload
+        let allowed = match self.check_quota(req, &req_p, length - offset) {
+            Ok(allowed) => allowed,
+            Err(resp) => return resp,
+        };
+
+        let upload_buckets = self.upload_buckets(req);
+        let mut ibuf = BufReader::with_capacity(1024 * 1024, LimitBandwidthReader::new(upload_buckets, CountingReader::new(&self.metrics.upload_bytes, (&mut req.body).take(length - offset))));
+        let received = io::copy(&mut ibuf, &mut partial);
+        let stored = partial.metadata().map(|m| m.len()).unwrap_or(offset);
+        drop(partial);
+        self.quota_written(allowed, stored as i64 - offset as i64);
+        if let Err(err) = received {
+            log!(self.log,
+                 "{} stopped uploading {magenta}{}{reset} at {}B/{}B",
+                 self.remote_addresses(req),
+                 req_p.display(),
+                 stored,
+                 length);
+            return self.handle_put_error(req, "Upload interrupted.", err);
+        }
+
+        if stored < length {
+            log!(self.log,
+                 "{} uploaded {}B/{}B of {magenta}{}{reset}",
+                 self.remote_addresses(req),
+                 stored,
+                 length,
+                 req_p.display());
+            return Ok(Response::with((status::NoContent, Header(headers::Server(USER_AGENT.into())), Header(XUploadOffset(stored)))));
+        }
+
+        let existent = req_p.exists();
+        log!(self.log,
+             "{} {} {magenta}{}{reset}, size: {}B{}{}",
+             self.remote_addresses(req),
+             if existent { "replaced" } else { "created" },
+             req_p.display(),
+             length,
+             mtime.map_or("", |_| ". modified: "),
+             Maybe(mtime.map(MsAsSAnd3339)));
+
+        let replaced = self.quota.map(|_| file_size(&req_p)).unwrap_or(0);
+        if let Err(err) = HttpHandler::move_upload_into_place(&partial_p, &req_p) {
+            return self.handle_put_error(req, "File not created.", err);
+        }
+        self.quota_written(0, -(replaced as i64));
+        if let Some(ms) = mtime {
+            set_mtime(&req_p, ms);
+        }
+
+        Ok(Response::with((if existent {
+                               status::NoContent
+                           } else {
+                               status::Created
+                           },
+                           Header(headers::Server(USER_AGENT.into())),
+                           Header(XUploadOffset(stored)))))
+    }
+
+    /// Delete resumable uploads in `temp_dir` not added to in `MAX_PARTIAL_UPLOAD_IDLE`
+    fn prune_partial_uploads(&self, temp_dir: &Path) {
+        let uploads_in_progress = self.uploads_in_progress.lock().expect("Failed to lock uploads in progress");
+        for entry in temp_dir.read_dir().into_iter().flatten().flatten() {
+            let upload_id = entry.file_name().to_str().and_then(|name| name.strip_suffix(".partial")).and_then(|id| blake3::Hash::from_hex(id).ok());
+            if upload_id.map(|id| uploads_in_progress.contains(&id)).unwrap_or(true) {
+                continue;
+            }
+
+            let idle = entry.metadata().and_then(|meta| meta.modified()).ok().and_then(|mtime| mtime.elapsed().ok());
+            let size = file_size(&entry.path());
+            if idle.map(|idle| idle > MAX_PARTIAL_UPLOAD_IDLE).unwrap_or(false) && fs::remove_file(entry.path()).is_ok() {
+                self.quota_written(0, -(size as i64));
+                log!(self.log, "Deleted abandoned upload {magenta}{}{reset}", entry.path().display());
+            }
+        }
+    }
+
+    /// Rename the finished upload over the requested file.
+    ///
+    /// If the temp directory is on a different filesystem, copy it next to the requested file first,
+    /// so that the file only ever appears complete.
+    fn move_upload_into_place(partial_p: &Path, req_p: &Path) -> io::Result<()> {
+        fs::create_dir_all(req_p.parent().expect("Failed to get requested file's parent directory"))?;
+        if fs::rename(partial_p, req_p).is_ok() {
+            return Ok(());
+        }
+
+        let mut sibling_name = OsString::from(".");
+        sibling_name.push(req_p.file_name().expect("Failed to get requested file's filename"));
+        sibling_name.push(".upload");
+        let sibling_p = req_p.with_file_name(sibling_name);
+        if let Err(err) = fs::copy(partial_p, &sibling_p).and_then(|_| fs::rename(&sibling_p, req_p)) {
+            let _ = fs::remove_file(&sibling_p);
+            return Err(err);
+        }
+        fs::remove_file(partial_p)
+    }
+
+    fn handle_upload_offset_conflict(&self, req: &mut ConfiguredRequest, req_p: PathBuf, offset: u64, stored: u64) -> IronResult<Response> {
+        log!(self.log,
+             "{} tried to resume uploading {magenta}{}{reset} at {}B, but {}B are stored",
+             self.remote_addresses(req),
+             req_p.display(),
+             offset,
+             stored);
+
+        self.handle_generated_response_encoding(req,
+                                                status::Conflict,
+                                                error_html("409 Conflict",
+                                                           format_args!("Upload continues at byte {}.", stored),
+                                                           "<p>Resend from the offset in X-Upload-Offset.</p>"))
+            .map(|mut r| {
+                r.headers.set(XUploadOffset(stored));
+                r
+            })
+    }
+
+    fn handle_invalid_upload(&self, req: &mut ConfiguredRequest, cause: &str) -> IronResult<Response> {
+        log!(self.log,
+             "{} tried to {red}PATCH{reset} {yellow}{}{reset} without a valid resumable upload",
+             self.remote_addresses(req),
+             url_path(&req.url));
+
+        self.handle_generated_response_encoding(req, status::BadRequest, error_html("400 Bad Request", "Invalid resumable upload.", cause))
+    }
+
+    fn handle_delete(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
         if self.writes_temp_dir.is_none() {
             return self.handle_forbidden_method(req, "-w", "write requests");
         }
@@ -1355,12 +1726,18 @@ impl HttpHandler {
         } else if !req_p.exists() || (symlink && !self.follow_symlinks) ||
                   (symlink && self.follow_symlinks && self.sandbox_symlinks && !is_descendant_of(&req_p, &self.hosted_directory.1)) {
             self.handle_nonexistent(req, req_p)
+        } else if !HttpHandler::preconditions_hold(req, &req_p) {
+            self.handle_precondition_failed(req, req_p)
+        } else if let Some(resp) = self.check_webdav_locks(req, &req_p, true) {
+            resp
         } else {
-            self.handle_delete_path(req, req_p, symlink)
+            let resp = self.handle_delete_path(req, req_p.clone(), symlink);
+            self.drop_webdav_locks(&req_p);
+            resp
         }
     }
 
-    fn handle_delete_path(&self, req: &mut Request, req_p: PathBuf, symlink: bool) -> IronResult<Response> {
+    fn handle_delete_path(&self, req: &mut ConfiguredRequest, req_p: PathBuf, symlink: bool) -> IronResult<Response> {
         let ft = req_p.metadata().expect("failed to get file metadata").file_type();
         let is_file = is_actually_file(&ft, &req_p);
         log!(self.log,
@@ -1388,7 +1765,7 @@ impl HttpHandler {
         Ok(Response::with((status::NoContent, Header(headers::Server(USER_AGENT.into())))))
     }
 
-    fn handle_trace(&self, req: &mut Request) -> IronResult<Response> {
+    fn handle_trace(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
         log!(self.log,
              "{} requested {red}TRACE{reset} for {magenta}{}{reset}",
              self.remote_addresses(&req),
@@ -1404,7 +1781,22 @@ impl HttpHandler {
         })
     }
 
-    fn handle_forbidden_method(&self, req: &mut Request, switch: &str, desc: &str) -> IronResult<Response> {
+    fn handle_precondition_failed(&self, req: &mut ConfiguredRequest, req_p: PathBuf) -> IronResult<Response> {
+        log!(self.log,
+             "{} requested to {red}{}{reset} {magenta}{}{reset} but its preconditions failed",
+             self.remote_addresses(req),
+             req.method,
+             req_p.display());
+
+        let url_p = url_path(&req.url);
+        self.handle_generated_response_encoding(req,
+                                                status::PreconditionFailed,
+                                                error_html("412 Precondition Failed",
+                                                           format_args!("The entity \"{}\" doesn't match the request's preconditions.", url_p),
+                                                           "<p>It was most likely modified since it was last retrieved.</p>"))
+    }
+
+    fn handle_forbidden_method(&self, req: &mut ConfiguredRequest, switch: &str, desc: &str) -> IronResult<Response> {
         log!(self.log,
              "{} used disabled request method {red}{}{reset} grouped under {}",
              self.remote_addresses(&req),
@@ -1421,7 +1813,7 @@ impl HttpHandler {
                                                                         desc)))
     }
 
-    fn handle_bad_method(&self, req: &mut Request) -> IronResult<Response> {
+    fn handle_bad_method(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
         log!(self.log,
              "{} used invalid request method {red}{}{reset}",
              self.remote_addresses(&req),
@@ -1436,7 +1828,7 @@ impl HttpHandler {
                                                                         CommaList(self.allowed_methods.iter()))))
     }
 
-    fn handle_generated_response_encoding(&self, req: &mut Request, st: status::Status, resp: String) -> IronResult<Response> {
+    fn handle_generated_response_encoding(&self, req: &mut ConfiguredRequest, st: status::Status, resp: String) -> IronResult<Response> {
         let hash = blake3::hash(resp.as_bytes());
         let etag = hash.to_string();
 
@@ -1458,6 +1850,7 @@ impl HttpHandler {
             {
                 if let Some(enc_resp) = self.cache_gen.read().expect("Generated file cache read lock poisoned").get(&cache_key) {
                     enc_resp.1.store(precise_time_ns(), AtomicOrdering::Relaxed);
+                    Metrics::add(&self.metrics.cache_gen_hits, 1);
                     log!(self.log,
                          "{} encoded as {} for {:.1}% ratio (cached)",
                          self.remote_addresses(req).as_spaces(),
@@ -1473,6 +1866,7 @@ impl HttpHandler {
                 }
             }
 
+            Metrics::add(&self.metrics.cache_gen_misses, 1);
             if let Some(enc_resp) = encode_str(&resp, &encoding) {
                 log!(self.log,
                      "{} encoded as {} for {:.1}% ratio",
@@ -1514,7 +1908,7 @@ impl HttpHandler {
                            resp)))
     }
 
-    fn handle_requested_entity_unopenable(&self, req: &mut Request, e: IoError, entity_type: &str) -> IronResult<Response> {
+    fn handle_requested_entity_unopenable(&self, req: &mut ConfiguredRequest, e: IoError, entity_type: &str) -> IronResult<Response> {
         if e.kind() == IoErrorKind::PermissionDenied {
             self.handle_generated_response_encoding(req,
                                                     status::Forbidden,
@@ -1535,7 +1929,7 @@ impl HttpHandler {
                            serde_json::to_string(&resp).unwrap())))
     }
 
-    fn parse_requested_path(&self, req: &Request) -> (PathBuf, bool, bool) {
+    fn parse_requested_path(&self, req: &ConfiguredRequest) -> (PathBuf, bool, bool) {
         self.parse_requested_path_custom_symlink(req.url.as_ref(), true)
     }
 
@@ -1586,20 +1980,30 @@ impl HttpHandler {
     }
 
     #[inline(always)]
-    fn remote_addresses<'s, 'r, 'ra, 'rb: 'ra>(&'s self, req: &'r Request<'ra, 'rb>) -> AddressWriter<'r, 's, 'ra, 'rb> {
+    fn remote_addresses<'r, 'ra, 'rb: 'ra>(&self, req: &'r ConfiguredRequest<'_, 'ra, 'rb>) -> AddressWriter<'r, 'ra, 'rb> {
         AddressWriter {
             request: req,
-            proxies: &self.proxies,
+            config: req.config.clone(),
             log: self.log,
             just_spaces: false,
         }
     }
 
-    fn guess_mime_type(&self, req_p: &Path) -> Mime {
+    /// Get what uploads from this request need to take from, per `--*-upload-bandwidth`
+    fn upload_buckets(&self, req: &ConfiguredRequest) -> Vec<Arc<TokenBucket>> {
+        req.config.upload_bandwidth.as_ref().map(|limits| limits.buckets(self.remote_addresses(req).client_ip(), req.user.as_deref())).unwrap_or_default()
+    }
+
+    fn guess_mime_type(&self, req: &ConfiguredRequest, req_p: &Path) -> Mime {
+        self.guess_mime_type_config(&req.config, req_p)
+    }
+
+    /// As above, but not borrowing a request
+    fn guess_mime_type_config(&self, config: &HandlerConfig, req_p: &Path) -> Mime {
         // Based on mime_guess::guess_mime_type_opt(); that one does to_str() instead of to_string_lossy()
         let ext = req_p.extension().unwrap_or(OsStr::new(""));
 
-        (self.mime_type_overrides.get(&*ext).cloned())
+        (config.mime_type_overrides.get(ext).cloned())
             .or_else(|| ext.to_str().and_then(get_mime_type_opt))
             .unwrap_or_else(|| if file_binary(req_p) {
                 Mime(MimeTopLevel::Application, MimeSubLevel::OctetStream, Default::default()) // "application/octet-stream"
@@ -1615,15 +2019,35 @@ fn text_html_charset_utf8() -> Mime {
 }
 
 
-pub struct AddressWriter<'r, 'p, 'ra, 'rb: 'ra> {
+/// `multipart/byteranges` body: each part's `(pre-formatted boundary and headers, from, to)`, then the closing boundary
+struct MultipartByteRangesBody {
+    file: File,
+    parts: Vec<(String, u64, u64)>,
+    trailer: String,
+}
+
+impl WriteBody for MultipartByteRangesBody {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        for (head, from, to) in &self.parts {
+            res.write_all(head.as_bytes())?;
+            self.file.seek(SeekFrom::Start(*from))?;
+            io::copy(&mut (&mut self.file).take(to + 1 - from), res)?;
+        }
+        res.write_all(self.trailer.as_bytes())
+    }
+}
+
+
+pub struct AddressWriter<'r, 'ra, 'rb: 'ra> {
     pub request: &'r Request<'ra, 'rb>,
-    pub proxies: &'p BTreeMap<IpCidr, String>,
+    /// For the trusted proxies
+    pub config: Arc<HandlerConfig>,
     /// (at all, log_time, log_colour)
     pub log: (bool, bool, bool),
     pub just_spaces: bool,
 }
 
-impl<'r, 'p, 'ra, 'rb: 'ra> fmt::Display for AddressWriter<'r, 'p, 'ra, 'rb> {
+impl<'r, 'ra, 'rb: 'ra> fmt::Display for AddressWriter<'r, 'ra, 'rb> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use trivial_colours::{Reset as CReset, Colour as C};
 
@@ -1637,15 +2061,13 @@ impl<'r, 'p, 'ra, 'rb: 'ra> fmt::Display for AddressWriter<'r, 'p, 'ra, 'rb> {
             write!(f, "{}", self.request.remote_addr)?;
         }
 
-        for (network, header) in self.proxies {
-            if network.contains(&self.request.remote_addr.ip()) {
-                if let Some(saddrs) = self.request.headers.get_raw(header) {
-                    for saddr in saddrs {
-                        if self.log.1 {
-                            write!(f, " for {green}{}{reset}", String::from_utf8_lossy(saddr), green = C::Green, reset = CReset)?;
-                        } else {
-                            write!(f, " for {}", String::from_utf8_lossy(saddr))?;
-                        }
+        for header in self.forwarded_for_headers() {
+            if let Some(saddrs) = self.request.headers.get_raw(header) {
+                for saddr in saddrs {
+                    if self.log.1 {
+                        write!(f, " for {green}{}{reset}", String::from_utf8_lossy(saddr), green = C::Green, reset = CReset)?;
+                    } else {
+                        write!(f, " for {}", String::from_utf8_lossy(saddr))?;
                     }
                 }
             }
@@ -1655,7 +2077,42 @@ impl<'r, 'p, 'ra, 'rb: 'ra> fmt::Display for AddressWriter<'r, 'p, 'ra, 'rb> {
     }
 }
 
-impl<'r, 'p, 'ra, 'rb: 'ra> AddressWriter<'r, 'p, 'ra, 'rb> {
+impl<'r, 'ra, 'rb: 'ra> AddressWriter<'r, 'ra, 'rb> {
+    /// Get the address of the actual client, as told by the trusted proxies in front of it.
+    ///
+    /// This is the last address in their headers that's not a trusted proxy itself,
+    /// or the first one if they all are; unparseable addresses are ignored.
+    pub fn client_ip(&self) -> IpAddr {
+        let ip = self.request.remote_addr.ip();
+        let forwarded = self.forwarded_for_headers()
+            .flat_map(|header| self.request.headers.get_raw(header).unwrap_or_default())
+            .flat_map(|saddrs| str::from_utf8(saddrs).unwrap_or("").split(','))
+            .flat_map(|saddr| {
+                let saddr = saddr.trim();
+                saddr.parse().ok().or_else(|| saddr.parse::<SocketAddr>().ok().map(|saddr| saddr.ip()))
+            })
+            .collect::<Vec<IpAddr>>();
+
+        forwarded.iter()
+            .rev()
+            .find(|fip| !self.config.proxies.keys().any(|network| network.contains(fip)))
+            .or(forwarded.first())
+            .cloned()
+            .unwrap_or(ip)
+    }
+
+    /// Forwarded-for headers trusted from where the request came from:
+    /// `--unix-socket-proxy` for Unix domain sockets, and `--proxy` networks containing it otherwise
+    fn forwarded_for_headers(&self) -> impl Iterator<Item = &String> {
+        let ip = self.request.remote_addr.ip();
+        let unix_socket = self.request.remote_addr == UNIX_SOCKET_ADDRESS;
+        self.config
+            .unix_socket_proxy
+            .iter()
+            .filter(move |_| unix_socket)
+            .chain(self.config.proxies.iter().filter(move |(network, _)| !unix_socket && network.contains(&ip)).map(|(_, header)| header))
+    }
+
     fn maybe_spaces(mut self, ms: bool) -> Self {
         self.just_spaces = ms;
         self
@@ -1671,13 +2128,11 @@ impl<'r, 'p, 'ra, 'rb: 'ra> AddressWriter<'r, 'p, 'ra, 'rb> {
         let mut widthbuf = ArrayString::<{ LONGEST_IPV6_SOCKET_ADDR.len() }>::new();
         write!(&mut widthbuf, "{}", self.request.remote_addr).unwrap();
         let mut len = widthbuf.len();
-        for (network, header) in self.proxies {
-            if network.contains(&self.request.remote_addr.ip()) {
-                if let Some(saddrs) = self.request.headers.get_raw(header) {
-                    for saddr in saddrs {
-                        len += " for ".len();
-                        len += saddr.len();
-                    }
+        for header in self.forwarded_for_headers() {
+            if let Some(saddrs) = self.request.headers.get_raw(header) {
+                for saddr in saddrs {
+                    len += " for ".len();
+                    len += saddr.len();
                 }
             }
         }
@@ -1701,17 +2156,12 @@ impl<'r, 'p, 'ra, 'rb: 'ra> AddressWriter<'r, 'p, 'ra, 'rb> {
 /// # use iron::{status, Response};
 /// let server = try_ports(|req| Ok(Response::with((status::Ok, "Abolish the burgeoisie!"))), 8000, 8100, None).unwrap();
 /// ```
-pub fn try_ports<H: Handler + Copy>(hndlr: H, addr: IpAddr, from: u16, up_to: u16, tls_data: &Option<((String, PathBuf), String)>) -> Result<Listening, Error> {
+pub fn try_ports<H: Handler + Copy>(hndlr: H, addr: IpAddr, only_v6: bool, from: u16, up_to: u16, tls: &Option<TlsServer>)
+                                    -> Result<Listening, Error> {
     for port in from..=up_to {
-        let ir = Iron::new(hndlr);
-        match if let Some(&((_, ref id), ref pw)) = tls_data.as_ref() {
-            ir.https((addr, port),
-                     NativeTlsServer::new(id, pw).map_err(|err| Error(format!("Opening TLS certificate: {}", err)))?)
-        } else {
-            ir.http((addr, port))
-        } {
-            Ok(server) => return Ok(server),
-            Err(iron::error::HttpError::Io(ioe)) if ioe.kind() == IoErrorKind::AddrInUse => { /* next */ }
+        match bind_tcp(SocketAddr::new(addr, port), only_v6) {
+            Ok(listener) => return listen(hndlr, listener, tls),
+            Err(ioe) if ioe.kind() == IoErrorKind::AddrInUse => { /* next */ }
             Err(error) => return Err(Error(format!("Starting server: {}", error))),
         }
     }
@@ -1719,106 +2169,6 @@ pub fn try_ports<H: Handler + Copy>(hndlr: H, addr: IpAddr, from: u16, up_to: u1
     Err(Error(format!("Starting server: no free ports")))
 }
 
-/// Generate a passwordless self-signed certificate in the `"tls"` subdirectory of the specified directory
-/// with the filenames `"tls.*"`.
-///
-/// # Examples
-///
-/// ```
-/// # use https::ops::generate_tls_data;
-/// let ((ident_name, ident_file), pass) = generate_tls_data(&(".".to_string(), ".".into())).unwrap();
-/// assert_eq!(ident_name, "./tls/tls.p12");
-/// assert!(ident_file.exists());
-/// assert_eq!(pass, "");
-/// ```
-pub fn generate_tls_data(temp_dir: &(String, PathBuf)) -> Result<((String, PathBuf), String), Error> {
-    fn err<M: fmt::Display>(which: bool, op: &'static str, more: M) -> Error {
-        Error(format!("{} {}: {}",
-                      op,
-                      if which {
-                          "TLS key generation process"
-                      } else {
-                          "TLS identity generation process"
-                      },
-                      more))
-    }
-    fn exit_err(which: bool, process: &mut Child, exitc: &ExitStatus) -> Error {
-        let mut stdout = String::new();
-        let mut stderr = String::new();
-        if process.stdout.as_mut().unwrap().read_to_string(&mut stdout).is_err() {
-            stdout = "<error getting process stdout".to_string();
-        }
-        if process.stderr.as_mut().unwrap().read_to_string(&mut stderr).is_err() {
-            stderr = "<error getting process stderr".to_string();
-        }
-
-        err(which,
-            "Exiting",
-            format_args!("{};\nstdout: ```\n{}```;\nstderr: ```\n{}```", exitc, stdout, stderr))
-    }
-
-    let tls_dir = temp_dir.1.join("tls");
-    fs::create_dir_all(&tls_dir).map_err(|err| Error(format!("Creating temporary directory: {}", err)))?;
-
-    let mut child =
-        Command::new("openssl").args(&["req", "-x509", "-newkey", "rsa:4096", "-nodes", "-keyout", "tls.key", "-out", "tls.crt", "-days", "3650", "-utf8"])
-            .current_dir(&tls_dir)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .map_err(|error| err(true, "Spawning", error))?;
-    child.stdin
-        .as_mut()
-        .unwrap()
-        .write_all(concat!("PL\nhttp\n",
-                           env!("CARGO_PKG_VERSION"),
-                           "\nthecoshman&nabijaczleweli\nнаб\nhttp/",
-                           env!("CARGO_PKG_VERSION"),
-                           "\nnabijaczleweli@gmail.com\n")
-            .as_bytes())
-        .map_err(|error| err(true, "Piping", error))?;
-    let es = child.wait().map_err(|error| err(true, "Waiting", error))?;
-    if !es.success() {
-        return Err(exit_err(true, &mut child, &es));
-    }
-
-    let mut child = Command::new("openssl").args(&["pkcs12",
-                "-export",
-                "-out",
-                "tls.p12",
-                "-inkey",
-                "tls.key",
-                "-in",
-                "tls.crt",
-                "-passin",
-                "pass:",
-                "-passout",
-                if cfg!(target_os = "macos") {
-                    "pass:password"
-                } else {
-                    "pass:"
-                }])
-        .current_dir(&tls_dir)
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()
-        .map_err(|error| err(false, "Spawning", error))?;
-    let es = child.wait().map_err(|error| err(false, "Waiting", error))?;
-    if !es.success() {
-        return Err(exit_err(false, &mut child, &es));
-    }
-
-    Ok(((format!("{}/tls/tls.p12", temp_dir.0), tls_dir.join("tls.p12")),
-        if cfg!(target_os = "macos") {
-                "password"
-            } else {
-                ""
-            }
-            .to_string()))
-}
-
 /// Generate random username:password auth credentials.
 pub fn generate_auth_data() -> String {
     const USERNAME_SET_LEN: usize = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789".len();
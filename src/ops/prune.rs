@@ -1,9 +1,9 @@
 use self::super::super::util::{HumanReadableSize, precise_time_ns};
 use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
-use iron::{IronResult, Response, Handler, Request};
+use iron::{IronResult, Response};
 use self::super::super::Options;
 use std::collections::HashSet;
-use self::super::HttpHandler;
+use self::super::{ConfiguredRequest, HttpHandler, Metrics};
 use std::fs;
 
 
@@ -128,6 +128,11 @@ impl PruneChain {
 
         if freed_fs != 0 || freed_gen != 0 {
             let end = precise_time_ns();
+            let metrics = &self.handler.metrics;
+            Metrics::add(&metrics.prunes, 1);
+            Metrics::add(&metrics.pruned_fs_bytes, freed_fs);
+            Metrics::add(&metrics.pruned_gen_bytes, freed_gen);
+            Metrics::add(&metrics.prune_ns, end - start);
             log!(self.handler.log,
                  "Pruned {} + {} in {}ns; used: {} + {}",
                  HumanReadableSize(freed_fs),
@@ -137,11 +142,9 @@ impl PruneChain {
                  HumanReadableSize(self.handler.cache_gen_size.load(AtomicOrdering::Relaxed)));
         }
     }
-}
 
-impl Handler for &'static PruneChain {
-    fn handle(&self, req: &mut Request) -> IronResult<Response> {
-        let resp = (&self.handler).handle(req);
+    pub fn handle(&'static self, req: &mut ConfiguredRequest) -> IronResult<Response> {
+        let resp = self.handler.handle(req);
         self.prune();
         resp
     }
@@ -0,0 +1,153 @@
+//! Disk space reporting, as specified in https://tools.ietf.org/html/rfc4331, and the `--quota` upload limit
+
+
+use self::super::super::util::{HumanReadableSize, error_html, file_length, filesystem_space};
+use std::time::{Duration, Instant};
+use iron::{status, IronResult, Response};
+use self::super::{ConfiguredRequest, HttpHandler};
+use walkdir::WalkDir;
+use std::path::Path;
+
+
+/// How long to trust the hosted directory's size for, since writes through us are counted but nothing else is
+const QUOTA_USAGE_LIFETIME: Duration = Duration::from_secs(60);
+
+
+/// What counts against `--quota`
+#[derive(Debug, Default)]
+pub struct QuotaUsage {
+    /// Size of the hosted directory and partial uploads, adjusted for writes since, and when it was measured
+    used: Option<(Instant, u64)>,
+    /// Bytes allowed to writes still going on
+    reserved: u64,
+    /// While being re-measured, how much writes grew it in the meantime
+    measuring: Option<i64>,
+}
+
+impl QuotaUsage {
+    fn grow(&mut self, delta: i64) {
+        if let Some((_, used)) = self.used.as_mut() {
+            *used = used.saturating_add_signed(delta);
+        }
+        if let Some(grown) = self.measuring.as_mut() {
+            *grown += delta;
+        }
+    }
+}
+
+
+/// Total length of the files under `p`, or of `p` itself
+pub(super) fn tree_size(p: &Path) -> u64 {
+    WalkDir::new(p)
+        .into_iter()
+        .flatten()
+        .flat_map(|entry| entry.metadata().ok().filter(|meta| meta.is_file()).map(|meta| file_length(&meta, &entry.path())))
+        .sum()
+}
+
+/// Length of the file at `p`, if any
+pub(super) fn file_size(p: &Path) -> u64 {
+    p.metadata().ok().filter(|meta| meta.is_file()).map(|meta| file_length(&meta, &p)).unwrap_or(0)
+}
+
+
+impl HttpHandler {
+    /// Bytes still available for and used by uploads: against `--quota` if given,
+    /// limited by the space on the hosted filesystem
+    pub(super) fn quota_space(&self) -> Option<(u64, u64)> {
+        let (fs_available, fs_used) = filesystem_space(&self.hosted_directory.1).ok()?;
+        Some(match self.quota {
+            Some(quota) => {
+                let used = self.with_quota_usage(|usage, used| used + usage.reserved);
+                (quota.saturating_sub(used).min(fs_available), used)
+            }
+            None => (fs_available, fs_used),
+        })
+    }
+
+    /// Run `f` on the usage and the size of the hosted directory, all under one lock.
+    ///
+    /// The size is re-measured without the lock held when out of date;
+    /// other requests keep using the old one meanwhile, or wait for the first.
+    fn with_quota_usage<R, F: FnOnce(&mut QuotaUsage, u64) -> R>(&self, f: F) -> R {
+        let mut usage = self.quota_usage.lock().expect("Failed to get hosted directory size");
+        loop {
+            match (usage.used, usage.measuring) {
+                (Some((when, used)), _) if when.elapsed() < QUOTA_USAGE_LIFETIME => return f(&mut usage, used),
+                (Some((_, used)), Some(_)) => return f(&mut usage, used),
+                (None, Some(_)) => usage = self.quota_measured.wait(usage).expect("Failed to get hosted directory size"),
+                (_, None) => {
+                    usage.measuring = Some(0);
+                    drop(usage);
+
+                    let mut size = tree_size(&self.hosted_directory.1);
+                    if let Some((_, temp_dir)) = self.writes_temp_dir.as_ref() {
+                        size += temp_dir.read_dir()
+                            .into_iter()
+                            .flatten()
+                            .flatten()
+                            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".partial"))
+                            .map(|entry| file_size(&entry.path()))
+                            .sum::<u64>();
+                    }
+
+                    usage = self.quota_usage.lock().expect("Failed to get hosted directory size");
+                    let grown = usage.measuring.take().unwrap_or(0);
+                    usage.used = Some((Instant::now(), size.saturating_add_signed(grown)));
+                    self.quota_measured.notify_all();
+                }
+            }
+        }
+    }
+
+    /// Check that writing `length` bytes to `req_p` keeps the hosted directory within `--quota`, replacing the file there, if any,
+    /// and allow them if so.
+    ///
+    /// Returns the bytes allowed, to be handed to `quota_written()` once the write's done,
+    /// or the response to send if over the quota.
+    pub(super) fn check_quota(&self, req: &mut ConfiguredRequest, req_p: &Path, length: u64) -> Result<u64, IronResult<Response>> {
+        let quota = match self.quota {
+            Some(quota) => quota,
+            None => return Ok(0),
+        };
+
+        let replaced = file_size(req_p);
+        let used = match self.with_quota_usage(|usage, used| {
+            let used = used + usage.reserved;
+            if used.saturating_sub(replaced).saturating_add(length) <= quota {
+                usage.reserved += length;
+                None
+            } else {
+                Some(used)
+            }
+        }) {
+            None => return Ok(length),
+            Some(used) => used,
+        };
+
+        log!(self.log,
+             "{} tried to write {} to {magenta}{}{reset} over the quota ({} of {} used)",
+             self.remote_addresses(req),
+             HumanReadableSize(length),
+             req_p.display(),
+             HumanReadableSize(used),
+             HumanReadableSize(quota));
+
+        Err(self.handle_generated_response_encoding(req,
+                                                     status::InsufficientStorage,
+                                                     error_html("507 Insufficient Storage",
+                                                                format_args!("Writing {} would exceed the quota.", HumanReadableSize(length)),
+                                                                format_args!("<p>{} of {} are available.</p>",
+                                                                             HumanReadableSize(quota.saturating_sub(used)),
+                                                                             HumanReadableSize(quota)))))
+    }
+
+    /// Release the `allowed` bytes from `check_quota()` for a finished write, which grew what counts by `grown`
+    pub(super) fn quota_written(&self, allowed: u64, grown: i64) {
+        if self.quota.is_some() {
+            let mut usage = self.quota_usage.lock().expect("Failed to get hosted directory size");
+            usage.reserved -= allowed;
+            usage.grow(grown);
+        }
+    }
+}
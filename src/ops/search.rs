@@ -0,0 +1,564 @@
+//! Searching, as specified in https://tools.ietf.org/html/rfc5323, with the `DAV:basicsearch` grammar
+
+
+use self::super::super::util::{BorrowXmlName, Depth, error_html, escape_specials, file_length, file_time_created, file_time_modified, client_microsoft,
+                               is_descendant_of, percent_decode, WEBDAV_ALLPROP_PROPERTIES_NON_WINDOWS, WEBDAV_ALLPROP_PROPERTIES_WINDOWS,
+                               WEBDAV_PROPNAME_PROPERTIES, WEBDAV_XML_NAMESPACE_DAV, MAX_PROPFIND_INFINITY_ENTRIES};
+use self::super::webdav::{PropfindMode, PropfindVariant, default_xml_parser_config, intialise_xml_output, namespaces_for_props, text_xml_charset_utf8};
+use xml::reader::{EventReader as XmlReader, XmlEvent as XmlREvent};
+use xml::writer::{XmlEvent as XmlWEvent, Error as XmlWError};
+use xml::name::OwnedName as OwnedXmlName;
+use xml::attribute::OwnedAttribute;
+use iron::{status, IronResult, Response, Request};
+use iron::url::Url as GenericUrl;
+use self::super::{ConfiguredRequest, HandlerConfig, HttpHandler};
+use chrono::{DateTime, Utc};
+use walkdir::{DirEntry, WalkDir};
+use std::path::{PathBuf, Path};
+use std::cmp::Ordering;
+use std::fs::Metadata;
+
+
+/// How deeply query elements may nest, since they're parsed and evaluated recursively
+const MAX_SEARCH_NESTING: usize = 64;
+
+
+impl HttpHandler {
+    pub(super) fn handle_webdav_search(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
+        let (req_p, symlink, url_err) = self.parse_requested_path(req);
+
+        if url_err {
+            return self.handle_invalid_url(req, "<p>Percent-encoding decoded to invalid UTF-8.</p>");
+        }
+
+        if !req_p.exists() || (symlink && (!self.follow_symlinks || (self.sandbox_symlinks && !is_descendant_of(&req_p, &self.hosted_directory.1)))) {
+            return self.handle_nonexistent(req, req_p);
+        }
+
+        let search = match parse_basicsearch(req) {
+            Ok(search) => search,
+            Err(e) => {
+                log!(self.log,
+                     "{} tried to {red}SEARCH{reset} {yellow}{}{reset} with an invalid query",
+                     self.remote_addresses(req),
+                     req_p.display());
+                return self.handle_generated_response_encoding(req,
+                                                               status::BadRequest,
+                                                               error_html("400 Bad Request", format_args!("Invalid query: {}", e), ""));
+            }
+        };
+
+        let scope_url = req.url.as_ref().join(&search.scope).ok();
+        let scope_p = scope_url.as_ref().map(|url| self.parse_requested_path_custom_symlink(url, true)).and_then(|(scope_p, scope_symlink, scope_url_err)| {
+            if scope_url_err || !scope_p.exists() ||
+               (scope_symlink && (!self.follow_symlinks || (self.sandbox_symlinks && !is_descendant_of(&scope_p, &self.hosted_directory.1)))) ||
+               !is_descendant_of(&scope_p, &req_p) {
+                None
+            } else {
+                Some(scope_p)
+            }
+        });
+        let (scope_url, scope_p) = match (scope_url, scope_p) {
+            (Some(scope_url), Some(scope_p)) => (scope_url, scope_p),
+            _ => {
+                log!(self.log,
+                     "{} tried to {red}SEARCH{reset} {yellow}{}{reset} in invalid scope {}",
+                     self.remote_addresses(req),
+                     req_p.display(),
+                     search.scope);
+                return self.handle_generated_response_encoding(req,
+                                                               status::BadRequest,
+                                                               error_html("400 Bad Request",
+                                                                          format_args!("Search scope {} doesn't exist or isn't under the searched collection.",
+                                                                                       search.scope),
+                                                                          ""));
+            }
+        };
+
+        log!(self.log,
+             "{} requested {red}SEARCH{reset} for {} in {yellow}{}{reset} at depth {}",
+             self.remote_addresses(req),
+             search.select,
+             scope_p.display(),
+             search.depth);
+
+        let config = req.config.clone();
+        let (entries, truncated) = self.search_entries(&config, req.user.as_deref(), scope_url.as_str(), &scope_p, &search);
+        let truncated_url = if truncated {
+            Some(req.url.as_ref().as_str())
+        } else {
+            None
+        };
+
+        let out = match &search.select {
+            PropfindVariant::AllProp => {
+                self.handle_webdav_search_write_output(&config,
+                                                       &entries,
+                                                       if client_microsoft(&req.headers) {
+                                                           WEBDAV_ALLPROP_PROPERTIES_WINDOWS
+                                                       } else {
+                                                           WEBDAV_ALLPROP_PROPERTIES_NON_WINDOWS
+                                                       },
+                                                       PropfindMode::All,
+                                                       truncated_url)
+            }
+            PropfindVariant::PropName => {
+                self.handle_webdav_search_write_output(&config, &entries, WEBDAV_PROPNAME_PROPERTIES, PropfindMode::Names, truncated_url)
+            }
+            PropfindVariant::Props(props) => self.handle_webdav_search_write_output(&config, &entries, &[&props[..]], PropfindMode::Named, truncated_url),
+        };
+        Ok(Response::with((status::MultiStatus, out.expect("Couldn't write SEARCH XML"), text_xml_charset_utf8())))
+    }
+
+    /// Resources in the scope that match the query, in order, and whether there were too many to look at all of them
+    fn search_entries(&self, config: &HandlerConfig, user: Option<&str>, scope_url: &str, scope_p: &Path, search: &BasicSearch) -> (Vec<SearchEntry>, bool) {
+        let mut scope_url = scope_url.to_string();
+        if scope_p.is_dir() && !scope_url.ends_with('/') {
+            scope_url.push('/');
+        }
+
+        let walk = WalkDir::new(scope_p)
+            .max_depth(match search.depth {
+                Depth::Zero => 0,
+                Depth::One => 1,
+                Depth::Infinity => usize::MAX,
+            })
+            .follow_links(self.follow_symlinks)
+            .into_iter()
+            .filter_entry(|entry| entry.depth() == 0 || self.search_entry_visible(config, user, &search_entry_url(&scope_url, scope_p, entry), entry));
+
+        let mut entries = vec![];
+        let mut truncated = false;
+        for (i, entry) in walk.flatten().enumerate() {
+            if i >= MAX_PROPFIND_INFINITY_ENTRIES {
+                truncated = true;
+                break;
+            }
+
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            let entry = SearchEntry {
+                url: search_entry_url(&scope_url, scope_p, &entry),
+                path: entry.into_path(),
+                meta,
+            };
+            if search.condition.as_ref().map(|cond| self.search_condition_holds(config, cond, &entry)).unwrap_or(true) {
+                entries.push(entry);
+            }
+        }
+
+        if !search.order.is_empty() {
+            let mut keyed = entries.into_iter()
+                .map(|entry| (search.order.iter().map(|(prop, _)| self.search_value(config, *prop, &entry)).collect::<Vec<_>>(), entry))
+                .collect::<Vec<_>>();
+            keyed.sort_by(|(lhs, _), (rhs, _)| {
+                lhs.iter()
+                    .zip(rhs.iter())
+                    .zip(search.order.iter())
+                    .map(|((lhs, rhs), (_, descending))| if *descending { rhs.cmp(lhs) } else { lhs.cmp(rhs) })
+                    .find(|&ord| ord != Ordering::Equal)
+                    .unwrap_or(Ordering::Equal)
+            });
+            entries = keyed.into_iter().map(|(_, entry)| entry).collect();
+        }
+
+        if let Some(limit) = search.limit {
+            entries.truncate(limit);
+        }
+
+        (entries, truncated)
+    }
+
+    /// Same rules as for PROPFIND: symlinks as configured, and readable by the user under `--acl`
+    fn search_entry_visible(&self, config: &HandlerConfig, user: Option<&str>, url: &str, entry: &DirEntry) -> bool {
+        if entry.path_is_symlink() && (!self.follow_symlinks || (self.sandbox_symlinks && !is_descendant_of(entry.path(), &self.hosted_directory.1))) {
+            return false;
+        }
+
+        config.acl.is_none() ||
+        GenericUrl::parse(url)
+            .ok()
+            .and_then(|url| percent_decode(url.path()).map(|url_p| config.acl_rights(user, &url_p).read))
+            .unwrap_or(false)
+    }
+
+    fn search_condition_holds(&self, config: &HandlerConfig, cond: &SearchCondition, entry: &SearchEntry) -> bool {
+        match cond {
+            SearchCondition::And(conds) => conds.iter().all(|cond| self.search_condition_holds(config, cond, entry)),
+            SearchCondition::Or(conds) => conds.iter().any(|cond| self.search_condition_holds(config, cond, entry)),
+            SearchCondition::Not(cond) => !self.search_condition_holds(config, cond, entry),
+            SearchCondition::Compare(op, prop, literal, caseless) => {
+                let ord = match (self.search_value(config, *prop, entry), literal) {
+                    (SearchValue::Text(value), SearchValue::Text(literal)) if *caseless => value.to_lowercase().cmp(&literal.to_lowercase()),
+                    (value, literal) => value.cmp(literal),
+                };
+                op.holds(ord)
+            }
+            SearchCondition::Like(prop, pattern, caseless) => {
+                match self.search_value(config, *prop, entry) {
+                    SearchValue::Text(value) if *caseless => like_matches(pattern, &value.to_lowercase()),
+                    SearchValue::Text(value) => like_matches(pattern, &value),
+                    _ => false,
+                }
+            }
+            SearchCondition::IsCollection => entry.meta.is_dir(),
+        }
+    }
+
+    /// The value of `prop` for `entry`, as reported by PROPFIND
+    fn search_value(&self, config: &HandlerConfig, prop: SearchProperty, entry: &SearchEntry) -> SearchValue {
+        match prop {
+            SearchProperty::DisplayName => SearchValue::Text(entry.path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()),
+            SearchProperty::ContentType => SearchValue::Text(self.guess_mime_type_config(config, &entry.path).to_string()),
+            SearchProperty::ContentLength => SearchValue::Number(file_length(&entry.meta, &entry.path)),
+            SearchProperty::LastModified => SearchValue::Time(file_time_modified(&entry.meta)),
+            SearchProperty::CreationDate => SearchValue::Time(file_time_created(&entry.meta)),
+        }
+    }
+
+    fn handle_webdav_search_write_output<'n, N: BorrowXmlName<'n>>(&self, config: &HandlerConfig, entries: &[SearchEntry], props: &[&'n [N]],
+                                                                    mode: PropfindMode, truncated_url: Option<&str>)
+                                                                    -> Result<Vec<u8>, XmlWError> {
+        let mut out = intialise_xml_output()?;
+        out.write(namespaces_for_props("D:multistatus", props.iter().flat_map(|pp| pp.iter())))?;
+
+        for entry in entries {
+            self.handle_propfind_path(config, &mut out, &entry.url, &entry.path, &entry.meta, props, mode)?;
+        }
+
+        if let Some(url) = truncated_url {
+            // https://tools.ietf.org/html/rfc5323#section-2.4.3
+            out.write(XmlWEvent::start_element("D:response"))?;
+            out.write(XmlWEvent::start_element("D:href"))?;
+            out.write(XmlWEvent::characters(&escape_specials(url)))?;
+            out.write(XmlWEvent::end_element())?; // href
+            out.write(XmlWEvent::start_element("D:status"))?;
+            out.write(XmlWEvent::characters("HTTP/1.1 507 Insufficient Storage"))?;
+            out.write(XmlWEvent::end_element())?; // status
+            out.write(XmlWEvent::end_element())?; // response
+        }
+
+        out.write(XmlWEvent::end_element())?;
+
+        Ok(out.into_inner())
+    }
+}
+
+
+/// A resource in the search scope
+struct SearchEntry {
+    url: String,
+    path: PathBuf,
+    meta: Metadata,
+}
+
+fn search_entry_url(scope_url: &str, scope_p: &Path, entry: &DirEntry) -> String {
+    let mut url = scope_url.to_string();
+    for (i, segment) in entry.path().strip_prefix(scope_p).unwrap_or(entry.path()).iter().enumerate() {
+        if i != 0 {
+            url.push('/');
+        }
+        url.push_str(&segment.to_string_lossy());
+    }
+    url
+}
+
+
+/// https://tools.ietf.org/html/rfc5323#section-5.2
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BasicSearch {
+    select: PropfindVariant,
+    scope: String,
+    depth: Depth,
+    condition: Option<SearchCondition>,
+    /// Properties to sort by, and whether descending
+    order: Vec<(SearchProperty, bool)>,
+    limit: Option<usize>,
+}
+
+/// Properties that can be searched and sorted on
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+enum SearchProperty {
+    DisplayName,
+    ContentType,
+    ContentLength,
+    LastModified,
+    CreationDate,
+}
+
+impl SearchProperty {
+    fn from_name(name: &OwnedXmlName) -> Option<SearchProperty> {
+        if name.namespace.as_deref() != Some(WEBDAV_XML_NAMESPACE_DAV.1) {
+            return None;
+        }
+        Some(match &name.local_name[..] {
+            "displayname" => SearchProperty::DisplayName,
+            "getcontenttype" => SearchProperty::ContentType,
+            "getcontentlength" => SearchProperty::ContentLength,
+            "getlastmodified" => SearchProperty::LastModified,
+            "creationdate" => SearchProperty::CreationDate,
+            _ => return None,
+        })
+    }
+
+    /// Parse a `DAV:literal` to compare this property against
+    fn parse_literal(self, literal: &str) -> Result<SearchValue, String> {
+        match self {
+            SearchProperty::DisplayName | SearchProperty::ContentType => Ok(SearchValue::Text(literal.to_string())),
+            SearchProperty::ContentLength => literal.trim().parse().map(SearchValue::Number).map_err(|e| format!("{} not a valid length: {}", literal, e)),
+            SearchProperty::LastModified | SearchProperty::CreationDate => {
+                DateTime::parse_from_rfc3339(literal.trim())
+                    .or_else(|_| DateTime::parse_from_rfc2822(literal.trim()))
+                    .map(|dt| SearchValue::Time(dt.with_timezone(&Utc)))
+                    .map_err(|e| format!("{} not a valid date: {}", literal, e))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+enum SearchValue {
+    Text(String),
+    Number(u64),
+    Time(DateTime<Utc>),
+}
+
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+enum SearchOperator {
+    Eq,
+    Lt,
+    Gt,
+    Lte,
+    Gte,
+}
+
+impl SearchOperator {
+    fn holds(self, ord: Ordering) -> bool {
+        match self {
+            SearchOperator::Eq => ord == Ordering::Equal,
+            SearchOperator::Lt => ord == Ordering::Less,
+            SearchOperator::Gt => ord == Ordering::Greater,
+            SearchOperator::Lte => ord != Ordering::Greater,
+            SearchOperator::Gte => ord != Ordering::Less,
+        }
+    }
+}
+
+/// https://tools.ietf.org/html/rfc5323#section-5.5
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SearchCondition {
+    And(Vec<SearchCondition>),
+    Or(Vec<SearchCondition>),
+    Not(Box<SearchCondition>),
+    /// Operator, property, literal, caseless
+    Compare(SearchOperator, SearchProperty, SearchValue, bool),
+    /// Property, pattern (lowercased if caseless), caseless
+    Like(SearchProperty, Vec<LikeToken>, bool),
+    IsCollection,
+}
+
+/// Part of a [`DAV:like`](https://tools.ietf.org/html/rfc5323#section-5.15) pattern
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+enum LikeToken {
+    /// `%`
+    Any,
+    /// `_`
+    One,
+    Char(char),
+}
+
+fn like_pattern(pattern: &str) -> Vec<LikeToken> {
+    let mut chars = pattern.chars();
+    let mut ret = vec![];
+    while let Some(c) = chars.next() {
+        ret.push(match c {
+            '%' => LikeToken::Any,
+            '_' => LikeToken::One,
+            '\\' => LikeToken::Char(chars.next().unwrap_or('\\')),
+            c => LikeToken::Char(c),
+        });
+    }
+    ret
+}
+
+/// Walk all the positions in `text` the pattern can have matched up to at once, so `%`s don't backtrack
+fn like_matches(pattern: &[LikeToken], text: &str) -> bool {
+    let text = text.chars().collect::<Vec<_>>();
+    let mut reachable = vec![false; text.len() + 1];
+    reachable[0] = true;
+    for token in pattern {
+        let mut next = vec![false; text.len() + 1];
+        for i in 0..=text.len() {
+            if !reachable[i] {
+                continue;
+            }
+            match token {
+                LikeToken::Any => {
+                    next[i..].iter_mut().for_each(|r| *r = true);
+                    break;
+                }
+                LikeToken::One => {
+                    if i < text.len() {
+                        next[i + 1] = true;
+                    }
+                }
+                LikeToken::Char(c) => {
+                    if text.get(i) == Some(c) {
+                        next[i + 1] = true;
+                    }
+                }
+            }
+        }
+        reachable = next;
+    }
+    reachable[text.len()]
+}
+
+
+/// Just enough of a document tree to walk the recursive `DAV:where` grammar
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct XmlElement {
+    name: OwnedXmlName,
+    attributes: Vec<OwnedAttribute>,
+    children: Vec<XmlElement>,
+    text: String,
+}
+
+impl XmlElement {
+    fn is_dav(&self, local_name: &str) -> bool {
+        self.name.namespace.as_deref() == Some(WEBDAV_XML_NAMESPACE_DAV.1) && self.name.local_name == local_name
+    }
+
+    fn child(&self, local_name: &str) -> Option<&XmlElement> {
+        self.children.iter().find(|child| child.is_dav(local_name))
+    }
+
+    fn only_child(&self) -> Result<&XmlElement, String> {
+        match &self.children[..] {
+            [child] => Ok(child),
+            _ => Err(format!("{} needs exactly one element", self.name.local_name)),
+        }
+    }
+
+    fn caseless(&self) -> bool {
+        self.attributes.iter().any(|attr| attr.name.local_name == "caseless" && attr.value == "yes")
+    }
+}
+
+fn parse_xml_element(req: &mut Request) -> Result<XmlElement, String> {
+    let mut stack: Vec<XmlElement> = vec![];
+    for event in XmlReader::new_with_config(&mut req.body, default_xml_parser_config()) {
+        match event.map_err(|e| e.to_string())? {
+            XmlREvent::StartElement { name, attributes, .. } => {
+                if stack.len() >= MAX_SEARCH_NESTING {
+                    return Err(format!("Elements nested deeper than {}", MAX_SEARCH_NESTING));
+                }
+                stack.push(XmlElement {
+                    name,
+                    attributes,
+                    children: vec![],
+                    text: String::new(),
+                })
+            }
+            XmlREvent::EndElement { .. } => {
+                let elem = stack.pop().expect("EndElement without StartElement");
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(elem),
+                    None => return Ok(elem),
+                }
+            }
+            XmlREvent::Characters(text) |
+            XmlREvent::CData(text) => {
+                if let Some(elem) = stack.last_mut() {
+                    elem.text.push_str(&text);
+                }
+            }
+            _ => (),
+        }
+    }
+    Err("No root element".to_string())
+}
+
+fn parse_basicsearch(req: &mut Request) -> Result<BasicSearch, String> {
+    let root = parse_xml_element(req)?;
+    if !root.is_dav("searchrequest") {
+        return Err(format!("Expected searchrequest, got {}", root.name.local_name));
+    }
+    let search = root.child("basicsearch").ok_or("Only the basicsearch grammar is supported")?;
+
+    let select = search.child("select").ok_or("Missing select")?;
+    let select = if select.child("allprop").is_some() {
+        PropfindVariant::AllProp
+    } else {
+        PropfindVariant::Props(select.child("prop").ok_or("select needs allprop or prop")?.children.iter().map(|prop| prop.name.clone()).collect())
+    };
+
+    let scope = search.child("from").and_then(|from| from.child("scope")).ok_or("Missing from scope")?;
+    let depth = match scope.child("depth").map(|depth| depth.text.trim()) {
+        None | Some("infinity") => Depth::Infinity,
+        Some("1") => Depth::One,
+        Some("0") => Depth::Zero,
+        Some(depth) => return Err(format!("Invalid scope depth {}", depth)),
+    };
+
+    let mut order = vec![];
+    for by in search.child("orderby").map(|orderby| &orderby.children[..]).unwrap_or_default() {
+        let prop = by.child("prop").ok_or("order needs prop")?.only_child()?;
+        order.push((SearchProperty::from_name(&prop.name).ok_or_else(|| format!("Can't order by {}", prop.name))?, by.child("descending").is_some()));
+    }
+
+    Ok(BasicSearch {
+        select,
+        scope: scope.child("href").ok_or("Missing scope href")?.text.trim().to_string(),
+        depth,
+        condition: search.child("where").map(|wh| wh.only_child().and_then(parse_condition)).transpose()?,
+        order,
+        limit: search.child("limit")
+            .and_then(|limit| limit.child("nresults"))
+            .map(|nresults| nresults.text.trim().parse().map_err(|e| format!("{} not a valid nresults: {}", nresults.text, e)))
+            .transpose()?,
+    })
+}
+
+fn parse_condition(elem: &XmlElement) -> Result<SearchCondition, String> {
+    if elem.name.namespace.as_deref() != Some(WEBDAV_XML_NAMESPACE_DAV.1) {
+        return Err(format!("Unsupported operator {}", elem.name));
+    }
+
+    let op = match &elem.name.local_name[..] {
+        "and" => return elem.children.iter().map(parse_condition).collect::<Result<_, _>>().map(SearchCondition::And),
+        "or" => return elem.children.iter().map(parse_condition).collect::<Result<_, _>>().map(SearchCondition::Or),
+        "not" => return parse_condition(elem.only_child()?).map(|cond| SearchCondition::Not(Box::new(cond))),
+        "is-collection" => return Ok(SearchCondition::IsCollection),
+        "like" => None,
+        "eq" => Some(SearchOperator::Eq),
+        "lt" => Some(SearchOperator::Lt),
+        "gt" => Some(SearchOperator::Gt),
+        "lte" => Some(SearchOperator::Lte),
+        "gte" => Some(SearchOperator::Gte),
+        _ => return Err(format!("Unsupported operator {}", elem.name.local_name)),
+    };
+
+    let prop = elem.child("prop").ok_or_else(|| format!("{} needs prop", elem.name.local_name))?.only_child()?;
+    let prop = SearchProperty::from_name(&prop.name).ok_or_else(|| format!("Can't search by {}", prop.name))?;
+    let literal = &elem.child("literal").ok_or_else(|| format!("{} needs literal", elem.name.local_name))?.text;
+    let caseless = elem.caseless();
+
+    match op {
+        Some(op) => Ok(SearchCondition::Compare(op, prop, prop.parse_literal(literal)?, caseless)),
+        None => {
+            if !matches!(prop, SearchProperty::DisplayName | SearchProperty::ContentType) {
+                return Err("like needs a text property".to_string());
+            }
+            Ok(SearchCondition::Like(prop,
+                                     like_pattern(&if caseless {
+                                         literal.to_lowercase()
+                                     } else {
+                                         literal.to_string()
+                                     }),
+                                     caseless))
+        }
+    }
+}
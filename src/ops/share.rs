@@ -0,0 +1,203 @@
+use self::super::super::util::{percent_decode, error_html, url_path, MAX_SHARE_AGE};
+use iron::{headers, status, method, IronResult, Response};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::convert::TryFrom;
+use chrono::{Local, TimeZone};
+use iron::url::form_urlencoded;
+use self::super::super::Options;
+use self::super::{ConfiguredRequest, HttpHandler};
+use hmac::{Hmac, Mac};
+use iron::mime::Mime;
+use sha2::Sha256;
+use blake3;
+
+
+/// Left as-is in scopes in share links and their cookies' paths
+const SHARE_SCOPE_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'/').remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+
+/// What a request's share link, if any, amounts to
+pub(super) enum ShareCheck {
+    /// Neither in the URL nor in a cookie
+    Absent,
+    /// Good for this request, with a cookie keeping it for the rest of the scope if it came from the URL
+    Valid(Option<headers::SetCookie>),
+    /// Forged, expired, out of scope, or not for reading
+    Rejected(Response),
+}
+
+/// A share link with a valid signature
+struct ShareLink {
+    /// Normalised path, without leading slash
+    scope: String,
+    /// UNIX time
+    expires: u64,
+}
+
+impl ShareLink {
+    fn parse(secret: &[u8], link: &str) -> Option<ShareLink> {
+        let mut itr = link.splitn(3, '.');
+        let expires = itr.next()?.parse().ok()?;
+        let signature = itr.next()?;
+        let scope = percent_decode(itr.next()?)?.into_owned();
+
+        // blake3::Hash compares in constant time
+        if blake3::hash(share_signature(secret, &scope, expires).as_bytes()) == blake3::hash(signature.as_bytes()) {
+            Some(ShareLink { scope, expires })
+        } else {
+            None
+        }
+    }
+
+    fn covers(&self, path: &str) -> bool {
+        self.scope.is_empty() || path == self.scope || (path.starts_with(&self.scope) && path.as_bytes()[self.scope.len()] == b'/')
+    }
+}
+
+
+/// Sign a share link for `scope` (a normalised path, without leading slash) until `expires` (UNIX time).
+///
+/// Returns the value for its `share` query parameter.
+fn sign_share_link(secret: &[u8], scope: &str, expires: u64) -> String {
+    format!("{}.{}.{}", expires, share_signature(secret, scope, expires), utf8_percent_encode(scope, SHARE_SCOPE_ENCODE_SET))
+}
+
+/// Get the path and query to access `scope` with a share link until `expires` (UNIX time).
+pub fn share_link_url(secret: &[u8], scope: &str, expires: u64, directory: bool) -> String {
+    format!("/{}{}?{}",
+            utf8_percent_encode(scope, SHARE_SCOPE_ENCODE_SET),
+            if directory && !scope.is_empty() { "/" } else { "" },
+            form_urlencoded::Serializer::new(String::new()).append_pair("share", &sign_share_link(secret, scope, expires)).finish())
+}
+
+fn share_signature(secret: &[u8], scope: &str, expires: u64) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(scope.as_bytes());
+    mac.update(b"\n");
+    mac.update(expires.to_string().as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// When a share link valid until `expires` (UNIX time) expires, in local time, or the raw timestamp if that's out of range.
+pub fn share_expiry(expires: u64) -> String {
+    match i64::try_from(expires).ok().and_then(|expires| Local.timestamp_opt(expires, 0).single()) {
+        Some(expires) => expires.format("%F %T").to_string(),
+        None => expires.to_string(),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+
+impl HttpHandler {
+    /// Check the share link in the `share` query parameter or cookies, which stand in for credentials and the ACL when reading.
+    ///
+    /// Bad links in the URL are rejected, but bad cookies are ignored, so they don't get in the way of logging in.
+    pub(super) fn verify_share(&self, req: &mut ConfiguredRequest, secret: &[u8]) -> IronResult<ShareCheck> {
+        let path = url_path(&req.url).trim_matches('/').to_string();
+        let now = unix_now();
+        let reading = matches!(req.method,
+                               method::Options | method::Get | method::Head | method::DavPropfind) ||
+                      (req.method == method::Post && self.archives);
+
+        let link = req.url.as_ref().query_pairs().find(|(key, _)| key == "share").map(|(_, link)| link.into_owned());
+        if let Some(link) = link {
+            let (title, desc) = match ShareLink::parse(secret, &link) {
+                None => ("Invalid share link", "This share link is invalid.".to_string()),
+                Some(share) if share.expires <= now => {
+                    ("Share link expired", format!("This share link expired on {}.", share_expiry(share.expires)))
+                }
+                Some(share) if !share.covers(&path) => ("Share link out of scope", format!("This share link is only for /{}.", share.scope)),
+                Some(_) if !reading => ("Share link read-only", format!("Share links don't allow {} requests.", req.method)),
+                Some(share) => {
+                    log!(self.log,
+                         "{} used a share link for {magenta}/{}{reset} to {red}{}{reset} {yellow}{}{reset}",
+                         self.remote_addresses(req),
+                         share.scope,
+                         req.method,
+                         req.url);
+
+                    let cookie = format!("share={}; Path=/{}; Max-Age={}; HttpOnly; SameSite=Lax",
+                                         link,
+                                         utf8_percent_encode(&share.scope, SHARE_SCOPE_ENCODE_SET),
+                                         share.expires - now);
+                    return Ok(ShareCheck::Valid(Some(headers::SetCookie(vec![cookie]))));
+                }
+            };
+
+            log!(self.log,
+                 "{} was denied {red}{}{reset} {yellow}{}{reset}: {}",
+                 self.remote_addresses(req),
+                 req.method,
+                 req.url,
+                 desc);
+            let resp_text = error_html(format_args!("403 {}", title), &desc[..], "<p>Ask whoever gave it to you for a new one.</p>");
+            return self.handle_generated_response_encoding(req, status::Forbidden, resp_text).map(ShareCheck::Rejected);
+        }
+
+        if reading {
+            if let Some(headers::Cookie(cookies)) = req.headers.get::<headers::Cookie>() {
+                let valid = cookies.iter()
+                    .filter_map(|cookie| cookie.strip_prefix("share="))
+                    .filter_map(|link| ShareLink::parse(secret, link))
+                    .any(|share| share.expires > now && share.covers(&path));
+                if valid {
+                    return Ok(ShareCheck::Valid(None));
+                }
+            }
+        }
+
+        Ok(ShareCheck::Absent)
+    }
+
+    /// Mint a share link for the requested path for `?mkshare=AGE`, to authenticated users that can read all of it
+    pub(super) fn handle_mint_share(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
+        let config = req.config.clone();
+        let secret = config.share_secret.as_ref().expect("Minting share link without secret");
+        let path = url_path(&req.url).trim_matches('/').to_string();
+        let user = req.user.clone();
+        let age = req.url.as_ref().query_pairs().find(|(key, _)| key == "mkshare").map(|(_, age)| age.into_owned()).unwrap_or_default();
+
+        let expires = match Options::age_parse(age.as_str().into()).ok().filter(|&age| age <= MAX_SHARE_AGE).and_then(|age| unix_now().checked_add(age)) {
+            Some(expires) => expires,
+            None => {
+                return self.handle_generated_response_encoding(req,
+                                                               status::BadRequest,
+                                                               error_html("400 Bad Request",
+                                                                          format_args!("\"{}\" isn't a valid share link age.", age),
+                                                                          format_args!("<p>Use a number of seconds, optionally suffixed with s, m, h, or d, \
+                                                                                        up to {}d.</p>",
+                                                                                       MAX_SHARE_AGE / (24 * 60 * 60))))
+            }
+        };
+        let readable = config.acl.as_ref().map(|acl| acl.rights_recursive(user.as_deref(), &path).read).unwrap_or(true);
+        if user.is_none() || !readable {
+            log!(self.log,
+                 "{} was denied a share link for {magenta}/{}{reset}",
+                 self.remote_addresses(req),
+                 path);
+            return self.handle_generated_response_encoding(req,
+                                                           status::Forbidden,
+                                                           error_html("403 Forbidden",
+                                                                      format_args!("You may not share /{}.", path),
+                                                                      "<p>Share links can only be made by logged-in users that can read everything \
+                                                                       they'd share.</p>"));
+        }
+
+        let mut url = req.url.as_ref().clone();
+        url.set_query(None);
+        url.query_pairs_mut().append_pair("share", &sign_share_link(secret, &path, expires));
+
+        log!(self.log,
+             "{} as \"{}\" made a share link for {magenta}/{}{reset} until {}",
+             self.remote_addresses(req),
+             user.as_deref().unwrap_or(""),
+             path,
+             share_expiry(expires));
+        Ok(Response::with((status::Ok, "text/plain; charset=utf-8".parse::<Mime>().unwrap(), format!("{}\n", url))))
+    }
+}
+
@@ -0,0 +1,433 @@
+use rcgen::{PKCS_ECDSA_P256_SHA256, PKCS_ECDSA_P384_SHA384, CertificateParams, SignatureAlgorithm, DnType, KeyPair, date_time_ymd};
+use rustls::server::{WebPkiClientVerifier, ResolvesServerCert, ServerConnection, ServerConfig, ClientHello};
+use rustls::pki_types::{PrivateKeyDer, CertificateDer, ServerName};
+use hyper_native_tls::{TlsStream as NativeTlsStream, NativeTlsServer};
+use std::io::{Result as IoResult, Write, Read};
+use hyper::net::{NetworkStream, HttpStream, SslServer};
+use hyper::{Result as HyperResult, Error as HyperError};
+use rustls::crypto::{CryptoProvider, ring};
+use std::time::{Duration as StdDuration, SystemTime};
+use std::net::{SocketAddr, IpAddr, Shutdown};
+use std::sync::{RwLock, Mutex, Arc};
+use rustls::pki_types::pem::PemObject;
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, StreamOwned};
+use x509_parser::prelude::{GeneralName, parse_x509_certificate};
+use webpki::EndEntityCert;
+use chrono::{Duration, Datelike, Utc};
+use std::fs::{self, OpenOptions};
+#[cfg(not(target_os = "windows"))]
+use std::os::unix::fs::OpenOptionsExt;
+use self::super::super::util::{hostname, hangups, MAX_TLS_VALIDITY_DAYS};
+use self::super::{ConfiguredRequest, HttpHandler};
+use self::super::super::Error;
+use std::fmt::Write as FmtWrite;
+use sha2::{Sha256, Digest};
+use std::path::PathBuf;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::{fmt, thread};
+
+
+/// How often to check whether the certificate files were changed or SIGHUP received
+const CERTIFICATE_CHECK_INTERVAL: StdDuration = StdDuration::from_secs(1);
+
+
+/// Where a TLS certificate and its key come from
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsIdentity {
+    /// PKCS#12 identity file and its password, from `--ssl`
+    Pkcs12((String, PathBuf), String),
+    /// PEM certificate chain and private key files, which can be the same one, from `--ssl` and `--ssl-key` or `--gen-ssl`
+    Pem((String, PathBuf), (String, PathBuf)),
+}
+
+impl TlsIdentity {
+    /// The certificate file, as specified
+    pub fn name(&self) -> &str {
+        match self {
+            TlsIdentity::Pkcs12((name, _), _) => name,
+            TlsIdentity::Pem((name, _), _) => name,
+        }
+    }
+
+    fn load_pem(cert: &(String, PathBuf), key: &(String, PathBuf), provider: &CryptoProvider) -> Result<Arc<CertifiedKey>, Error> {
+        let chain = CertificateDer::pem_file_iter(&cert.1)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| Error(format!("Loading TLS certificate {}: {}", cert.0, err)))?;
+        if chain.is_empty() {
+            return Err(Error(format!("Loading TLS certificate {}: no certificates", cert.0)));
+        }
+        let key_der = PrivateKeyDer::from_pem_file(&key.1).map_err(|err| Error(format!("Loading TLS key {}: {}", key.0, err)))?;
+        CertifiedKey::from_der(chain, key_der, provider).map(Arc::new).map_err(|err| Error(format!("Loading TLS key {}: {}", key.0, err)))
+    }
+}
+
+
+/// The PEM certificates, chosen by SNI, falling back to the first one, and reloaded when their files change
+#[derive(Debug)]
+pub struct TlsCertificates {
+    identities: Vec<((String, PathBuf), (String, PathBuf))>,
+    provider: Arc<CryptoProvider>,
+    keys: RwLock<Vec<Arc<CertifiedKey>>>,
+}
+
+impl TlsCertificates {
+    fn load(&self) -> Result<Vec<Arc<CertifiedKey>>, Error> {
+        self.identities.iter().map(|(cert, key)| TlsIdentity::load_pem(cert, key, &self.provider)).collect()
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        let modified = |path: &PathBuf| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        self.identities.iter().flat_map(|(cert, key)| [modified(&cert.1), modified(&key.1)]).collect()
+    }
+}
+
+impl ResolvesServerCert for TlsCertificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let keys = self.keys.read().expect("TLS certificates poisoned");
+        client_hello.server_name()
+            .and_then(|name| ServerName::try_from(name).ok())
+            .and_then(|name| {
+                keys.iter().find(|key| {
+                    key.end_entity_cert()
+                        .ok()
+                        .and_then(|cert| EndEntityCert::try_from(cert).ok())
+                        .map(|cert| cert.verify_is_valid_for_subject_name(&name).is_ok())
+                        .unwrap_or(false)
+                })
+            })
+            .or_else(|| keys.first())
+            .cloned()
+    }
+}
+
+
+/// Serves `--ssl` and `--gen-ssl` identities: PKCS#12 ones with the platform's TLS implementation, PEM ones with rustls
+#[derive(Clone)]
+pub enum TlsServer {
+    NativeTls(NativeTlsServer),
+    Rustls(Arc<ServerConfig>),
+}
+
+impl TlsServer {
+    /// Load `identities`, which are either one PKCS#12 identity or any number of PEM ones,
+    /// and require, or just request if `client_cert_optional`, client certificates signed by `client_ca`, if any
+    ///
+    /// Also returns the PEM certificates, for `HttpHandler::watch_tls()`.
+    pub fn new(identities: &[TlsIdentity], client_ca: &Option<(String, PathBuf)>, client_cert_optional: bool)
+               -> Result<(TlsServer, Option<Arc<TlsCertificates>>), Error> {
+        if let [TlsIdentity::Pkcs12((name, id), pw)] = identities {
+            if client_ca.is_some() {
+                return Err(Error(format!("Client certificates need PEM TLS certificates, not PKCS#12 identity {}", name)));
+            }
+            return NativeTlsServer::new(id, pw)
+                .map(|server| (TlsServer::NativeTls(server), None))
+                .map_err(|err| Error(format!("Opening TLS certificate {}: {}", name, err)));
+        }
+
+        let provider = Arc::new(ring::default_provider());
+        let mut certs = TlsCertificates {
+            identities: identities.iter()
+                .map(|identity| match identity {
+                    TlsIdentity::Pem(cert, key) => Ok((cert.clone(), key.clone())),
+                    TlsIdentity::Pkcs12((name, _), _) => Err(Error(format!("PKCS#12 TLS identity {} can't be used with other certificates", name))),
+                })
+                .collect::<Result<_, _>>()?,
+            provider: provider.clone(),
+            keys: RwLock::new(vec![]),
+        };
+        *certs.keys.get_mut().expect("TLS certificates poisoned") = certs.load()?;
+        let certs = Arc::new(certs);
+
+        let config = ServerConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions().map_err(|err| Error(format!("Setting up TLS: {}", err)))?;
+        let mut config = match client_ca {
+                Some((name, path)) => {
+                    let err = |err: &dyn fmt::Display| Error(format!("Loading client CA certificates {}: {}", name, err));
+                    let mut roots = RootCertStore::empty();
+                    for cert in CertificateDer::pem_file_iter(path).map_err(|e| err(&e))? {
+                        roots.add(cert.map_err(|e| err(&e))?).map_err(|e| err(&e))?;
+                    }
+                    let mut verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                    if client_cert_optional {
+                        verifier = verifier.allow_unauthenticated();
+                    }
+                    config.with_client_cert_verifier(verifier.build().map_err(|e| err(&e))?)
+                }
+                None => config.with_no_client_auth(),
+            }
+            .with_cert_resolver(certs.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok((TlsServer::Rustls(Arc::new(config)), Some(certs)))
+    }
+}
+
+impl SslServer<HttpStream> for TlsServer {
+    type Stream = TlsStream;
+
+    /// The rustls handshake happens on the first read, on the thread handling the connection
+    fn wrap_server(&self, stream: HttpStream) -> HyperResult<TlsStream> {
+        match self {
+            TlsServer::NativeTls(server) => server.wrap_server(stream).map(TlsStream::NativeTls),
+            TlsServer::Rustls(config) => {
+                let conn = ServerConnection::new(config.clone()).map_err(|err| HyperError::Ssl(Box::new(err)))?;
+                Ok(TlsStream::Rustls(Arc::new(Mutex::new(StreamOwned::new(conn, stream)))))
+            }
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub enum TlsStream {
+    NativeTls(NativeTlsStream<HttpStream>),
+    Rustls(Arc<Mutex<StreamOwned<ServerConnection, HttpStream>>>),
+}
+
+impl TlsStream {
+    /// The certificate the client sent, which was verified against `--client-ca`
+    pub fn peer_certificate(&self) -> Option<CertificateDer<'static>> {
+        match self {
+            TlsStream::NativeTls(_) => None,
+            TlsStream::Rustls(stream) => stream.lock().expect("TLS stream poisoned").conn.peer_certificates().and_then(|certs| certs.first()).cloned(),
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        match self {
+            TlsStream::NativeTls(stream) => stream.read(buf),
+            TlsStream::Rustls(stream) => stream.lock().expect("TLS stream poisoned").read(buf),
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        match self {
+            TlsStream::NativeTls(stream) => stream.write(buf),
+            TlsStream::Rustls(stream) => stream.lock().expect("TLS stream poisoned").write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        match self {
+            TlsStream::NativeTls(stream) => stream.flush(),
+            TlsStream::Rustls(stream) => stream.lock().expect("TLS stream poisoned").flush(),
+        }
+    }
+}
+
+impl NetworkStream for TlsStream {
+    fn peer_addr(&mut self) -> IoResult<SocketAddr> {
+        match self {
+            TlsStream::NativeTls(stream) => stream.peer_addr(),
+            TlsStream::Rustls(stream) => stream.lock().expect("TLS stream poisoned").sock.peer_addr(),
+        }
+    }
+
+    fn set_read_timeout(&self, dur: Option<StdDuration>) -> IoResult<()> {
+        match self {
+            TlsStream::NativeTls(stream) => stream.set_read_timeout(dur),
+            TlsStream::Rustls(stream) => stream.lock().expect("TLS stream poisoned").sock.set_read_timeout(dur),
+        }
+    }
+
+    fn set_write_timeout(&self, dur: Option<StdDuration>) -> IoResult<()> {
+        match self {
+            TlsStream::NativeTls(stream) => stream.set_write_timeout(dur),
+            TlsStream::Rustls(stream) => stream.lock().expect("TLS stream poisoned").sock.set_write_timeout(dur),
+        }
+    }
+
+    fn close(&mut self, how: Shutdown) -> IoResult<()> {
+        match self {
+            TlsStream::NativeTls(stream) => stream.close(how),
+            TlsStream::Rustls(stream) => {
+                let mut stream = stream.lock().expect("TLS stream poisoned");
+                stream.conn.send_close_notify();
+                let _ = stream.flush();
+                stream.sock.close(how)
+            }
+        }
+    }
+}
+
+
+/// Which part of a client certificate is the username, for `--client-cert-user`
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ClientCertName {
+    /// The subject's common name
+    CommonName,
+    /// The first DNS name, email address, or URI subject alternative name
+    AltName,
+}
+
+impl ClientCertName {
+    pub fn from_name(name: &str) -> Option<ClientCertName> {
+        match name {
+            "cn" => Some(ClientCertName::CommonName),
+            "san" => Some(ClientCertName::AltName),
+            _ => None,
+        }
+    }
+
+    fn of(&self, cert: &[u8]) -> Option<String> {
+        let (_, cert) = parse_x509_certificate(cert).ok()?;
+        match self {
+            ClientCertName::CommonName => cert.subject().iter_common_name().next().and_then(|cn| cn.as_str().ok()).map(str::to_string),
+            ClientCertName::AltName => {
+                cert.subject_alternative_name().ok().flatten().and_then(|san| {
+                    san.value.general_names.iter().find_map(|name| match name {
+                        GeneralName::DNSName(name) | GeneralName::RFC822Name(name) | GeneralName::URI(name) => Some(name.to_string()),
+                        _ => None,
+                    })
+                })
+            }
+        }
+    }
+}
+
+
+impl HttpHandler {
+    /// Authenticate `req` as the user named in the client certificate of its connection, if any
+    pub(super) fn client_certificate_user(&self, req: &mut ConfiguredRequest) {
+        if let Some(name) = self.client_cert_user {
+            req.user = req.body
+                .stream()
+                .downcast_ref::<TlsStream>()
+                .and_then(TlsStream::peer_certificate)
+                .and_then(|cert| name.of(&cert));
+        }
+    }
+
+    /// Reload `certs` when their files are modified or on SIGHUP, forever, keeping the old ones if that fails
+    pub fn watch_tls(&self, certs: Arc<TlsCertificates>) -> ! {
+        let names = certs.identities.iter().map(|(cert, _)| &cert.0[..]).collect::<Vec<_>>().join(", ");
+        let mut last_modified = certs.modified();
+        let mut last_hangups = hangups();
+        loop {
+            thread::sleep(CERTIFICATE_CHECK_INTERVAL);
+
+            let (now_modified, now_hangups) = (certs.modified(), hangups());
+            if now_modified == last_modified && now_hangups == last_hangups {
+                continue;
+            }
+            last_modified = now_modified;
+            last_hangups = now_hangups;
+
+            match certs.load() {
+                Ok(keys) => {
+                    *certs.keys.write().expect("TLS certificates poisoned") = keys;
+                    log!(self.log, "Reloaded TLS certificates {magenta}{}{reset}", names);
+                }
+                Err(err) => log!(self.log, "Reloading TLS certificates failed: {red}{}{reset}", err.0),
+            }
+        }
+    }
+}
+
+
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsKeyType {
+    EcdsaP256,
+    EcdsaP384,
+}
+
+impl TlsKeyType {
+    pub fn from_name(name: &str) -> Option<TlsKeyType> {
+        match name {
+            "ecdsa-p256" => Some(TlsKeyType::EcdsaP256),
+            "ecdsa-p384" => Some(TlsKeyType::EcdsaP384),
+            _ => None,
+        }
+    }
+
+    fn algorithm(&self) -> &'static SignatureAlgorithm {
+        match self {
+            TlsKeyType::EcdsaP256 => &PKCS_ECDSA_P256_SHA256,
+            TlsKeyType::EcdsaP384 => &PKCS_ECDSA_P384_SHA384,
+        }
+    }
+}
+
+impl fmt::Display for TlsKeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TlsKeyType::EcdsaP256 => "ECDSA P-256",
+            TlsKeyType::EcdsaP384 => "ECDSA P-384",
+        })
+    }
+}
+
+
+/// The names to generate a certificate for: the hostname, `addresses`, or localhost for unspecified ones, then `extra`
+pub fn tls_names<I: IntoIterator<Item = IpAddr>>(addresses: I, extra: &[String]) -> Vec<String> {
+    let mut names: Vec<String> = hostname().filter(|name| name.is_ascii()).into_iter().collect();
+    for addr in addresses {
+        if addr.is_unspecified() {
+            names.extend(["localhost", "127.0.0.1", "::1"].iter().map(|name| name.to_string()));
+        } else {
+            names.push(addr.to_string());
+        }
+    }
+    names.extend(extra.iter().cloned());
+
+    let mut seen = BTreeSet::new();
+    names.retain(|name| seen.insert(name.clone()));
+    names
+}
+
+/// Generate a passwordless self-signed certificate for `names` (DNS names or IP addresses), valid from today for `days`,
+/// in the `"tls"` subdirectory of the specified directory with the filenames `"tls.*"`.
+///
+/// Returns the identity and the certificate's SHA-256 fingerprint.
+///
+/// # Examples
+///
+/// ```
+/// # use https::ops::{generate_tls_data, TlsKeyType};
+/// let (identity, fingerprint) = generate_tls_data(&(".".to_string(), ".".into()), &["localhost".to_string()], TlsKeyType::EcdsaP256, 30).unwrap();
+/// assert_eq!(identity.name(), "./tls/tls.crt");
+/// assert_eq!(fingerprint.len(), 32 * 3 - 1);
+/// ```
+pub fn generate_tls_data(temp_dir: &(String, PathBuf), names: &[String], key_type: TlsKeyType, days: u64) -> Result<(TlsIdentity, String), Error> {
+    let err = |err: rcgen::Error| Error(format!("Generating TLS certificate: {}", err));
+
+    let key = KeyPair::generate_for(key_type.algorithm()).map_err(err)?;
+    let mut params = CertificateParams::new(names).map_err(err)?;
+    params.distinguished_name.push(DnType::CommonName, names.first().map(String::as_str).unwrap_or(concat!("http/", env!("CARGO_PKG_VERSION"))));
+    params.distinguished_name.push(DnType::OrganizationName, concat!("http/", env!("CARGO_PKG_VERSION")));
+    let from = Utc::now();
+    let until = from.checked_add_signed(Duration::days(days.min(MAX_TLS_VALIDITY_DAYS) as i64))
+        .ok_or_else(|| Error(format!("Generating TLS certificate: {} days from now is out of range", days)))?;
+    params.not_before = date_time_ymd(from.year(), from.month() as u8, from.day() as u8);
+    params.not_after = date_time_ymd(until.year(), until.month() as u8, until.day() as u8);
+    let cert = params.self_signed(&key).map_err(err)?;
+
+    let tls_dir = temp_dir.1.join("tls");
+    fs::create_dir_all(&tls_dir).map_err(|err| Error(format!("Creating temporary directory: {}", err)))?;
+    let write = |name: &str, data: &str, mode: u32| {
+        let mut opts = OpenOptions::new();
+        opts.write(true).create(true).truncate(true);
+        #[cfg(not(target_os = "windows"))]
+        opts.mode(mode);
+        #[cfg(target_os = "windows")]
+        let _ = mode;
+        opts.open(tls_dir.join(name))
+            .and_then(|mut f| f.write_all(data.as_bytes()))
+            .map_err(|err| Error(format!("Writing {}/tls/{}: {}", temp_dir.0, name, err)))
+    };
+    write("tls.crt", &cert.pem(), 0o644)?;
+    write("tls.key", &key.serialize_pem(), 0o600)?;
+
+    let mut fingerprint = String::with_capacity(32 * 3);
+    for (i, b) in Sha256::digest(cert.der()).iter().enumerate() {
+        let _ = write!(fingerprint, "{}{:02X}", if i == 0 { "" } else { ":" }, b);
+    }
+
+    Ok((TlsIdentity::Pem((format!("{}/tls/tls.crt", temp_dir.0), tls_dir.join("tls.crt")),
+                         (format!("{}/tls/tls.key", temp_dir.0), tls_dir.join("tls.key"))),
+        fingerprint))
+}
@@ -8,9 +8,10 @@
 
 use self::super::super::util::{BorrowXmlName, Destination, DisplayThree, CommaList, Overwrite, Depth, win32_file_attributes, file_time_accessed,
                                file_time_modified, file_time_created, client_microsoft, is_actually_file, is_descendant_of, escape_specials, file_executable,
-                               set_executable, error_html, file_length, set_times, copy_dir, WEBDAV_ALLPROP_PROPERTIES_NON_WINDOWS,
+                               set_executable, error_html, file_length, set_times, copy_dir, percent_decode, dead_properties, set_dead_properties,
+                               copy_dead_properties, WEBDAV_ALLPROP_PROPERTIES_NON_WINDOWS,
                                WEBDAV_ALLPROP_PROPERTIES_WINDOWS, WEBDAV_XML_NAMESPACE_MICROSOFT, WEBDAV_XML_NAMESPACE_APACHE, WEBDAV_PROPNAME_PROPERTIES,
-                               WEBDAV_XML_NAMESPACE_DAV, WEBDAV_XML_NAMESPACES, MAX_SYMLINKS};
+                               WEBDAV_XML_NAMESPACE_DAV, WEBDAV_XML_NAMESPACES, MAX_PROPFIND_INFINITY_ENTRIES, MAX_SYMLINKS};
 use iron::mime::{Mime, Attr as MimeAttr, Value as MimeAttrValue, SubLevel as MimeSubLevel, TopLevel as MimeTopLevel};
 use std::io::{ErrorKind as IoErrorKind, Result as IoResult, Error as IoError, Write, Read};
 use xml::reader::{EventReader as XmlReader, XmlEvent as XmlREvent, Error as XmlRError};
@@ -24,13 +25,17 @@ use iron::url::Url as GenericUrl;
 use std::collections::BTreeSet;
 use std::path::{PathBuf, Path};
 use std::fs::{self, Metadata};
-use self::super::HttpHandler;
+use iron::response::WriteBody;
+use std::sync::Arc;
+use self::super::lock::{webdav_error_xml, write_supportedlock};
+use self::super::quota::{tree_size, file_size};
+use self::super::{ConfiguredRequest, HandlerConfig, HttpHandler};
 use std::{fmt, mem};
 use chrono;
 
 
 /// This should be a pub const but the default/new function isn't const
-fn default_xml_parser_config() -> XmlParserConfig {
+pub(super) fn default_xml_parser_config() -> XmlParserConfig {
     XmlParserConfig {
         trim_whitespace: true,
         whitespace_to_characters: true,
@@ -44,7 +49,7 @@ fn default_xml_emitter_config() -> XmlEmitterConfig {
 
 
 impl HttpHandler {
-    pub(super) fn handle_webdav_propfind(&self, req: &mut Request) -> IronResult<Response> {
+    pub(super) fn handle_webdav_propfind(&'static self, req: &mut ConfiguredRequest) -> IronResult<Response> {
         let (req_p, symlink, url_err) = self.parse_requested_path(req);
 
         if url_err {
@@ -57,7 +62,26 @@ impl HttpHandler {
         }
 
 
+        let url = req.url.as_ref().as_str().to_string();
         let depth = req.headers.get::<Depth>().copied().unwrap_or(Depth::Zero);
+        let entries = if depth == Depth::Infinity && req_p.is_dir() && !self.webdav_infinity {
+            None
+        } else {
+            self.propfind_entries(&req.config, req.user.as_deref(), &url, &req_p, depth)
+        };
+        let entries = match entries {
+            Some(entries) => entries,
+            None => {
+                log!(self.log,
+                     "{} tried to {red}PROPFIND{reset} {yellow}{}{reset} at depth infinity{}",
+                     self.remote_addresses(req),
+                     req_p.display(),
+                     if self.webdav_infinity { ", but it has too many entries" } else { "" });
+                return Ok(Response::with((status::Forbidden,
+                                          webdav_error_xml("propfind-finite-depth", None).expect("Couldn't write WebDAV error XML"),
+                                          text_xml_charset_utf8())));
+            }
+        };
 
         let props = match parse_propfind(req) {
             Ok(props) => props,
@@ -93,55 +117,93 @@ impl HttpHandler {
              req_p.display(),
              depth);
 
-        let url = req.url.as_ref().as_str().to_string();
-        let resp = match props {
-            PropfindVariant::AllProp => {
-                self.handle_webdav_propfind_write_output(req,
-                                                         url,
-                                                         &req_p,
-                                                         if client_microsoft(&req.headers) {
-                                                             WEBDAV_ALLPROP_PROPERTIES_WINDOWS
-                                                         } else {
-                                                             WEBDAV_ALLPROP_PROPERTIES_NON_WINDOWS
-                                                         },
-                                                         false,
-                                                         depth)
-            }
-            PropfindVariant::PropName => self.handle_webdav_propfind_write_output(req, url, &req_p, WEBDAV_PROPNAME_PROPERTIES, true, depth),
-            PropfindVariant::Props(props) => self.handle_webdav_propfind_write_output(req, url, &req_p, &[&props[..]], false, depth),
-        };
-
-        match resp.expect("Couldn't write PROPFIND XML") {
-            Ok(xml_resp) => Ok(Response::with((status::MultiStatus, xml_resp, text_xml_charset_utf8()))),
-            Err(resp) => resp,
+        if depth == Depth::Infinity && req_p.is_dir() {
+            return Ok(Response::with((status::MultiStatus,
+                                      text_xml_charset_utf8(),
+                                      Box::new(PropfindInfinityBody {
+                                          handler: self,
+                                          config: req.config.clone(),
+                                          microsoft: client_microsoft(&req.headers),
+                                          entries,
+                                          props,
+                                      }) as Box<dyn WriteBody>)));
         }
+
+        let out = self.handle_webdav_propfind_write_output(&req.config,
+                                                           intialise_xml_output(),
+                                                           &entries,
+                                                           &props,
+                                                           client_microsoft(&req.headers))
+            .expect("Couldn't write PROPFIND XML");
+        Ok(Response::with((status::MultiStatus, out, text_xml_charset_utf8())))
     }
 
     /// Adapted from
     /// https://github.com/tylerwhall/hyperdav-server/blob/415f512ac030478593ad389a3267aeed7441d826/src/lib.rs#L459
-    fn handle_webdav_propfind_write_output<'n, N: BorrowXmlName<'n>>(&self, req: &mut Request, mut url: String, path: &Path, props: &[&'n [N]],
-                                                                     just_names: bool, depth: Depth)
-                                                                     -> Result<Result<Vec<u8>, IronResult<Response>>, XmlWError> {
-        let mut out = intialise_xml_output()?;
-        out.write(namespaces_for_props("D:multistatus", props.iter().flat_map(|pp| pp.iter())))?;
+    fn handle_webdav_propfind_write_output<W: Write>(&self, config: &HandlerConfig, out: Result<XmlWriter<W>, XmlWError>, entries: &[PropfindEntry],
+                                                     props: &PropfindVariant, microsoft: bool)
+                                                     -> Result<W, XmlWError> {
+        let out = out?;
+        match props {
+            PropfindVariant::AllProp => {
+                self.handle_webdav_propfind_write_props(config,
+                                                        out,
+                                                        entries,
+                                                        if microsoft {
+                                                            WEBDAV_ALLPROP_PROPERTIES_WINDOWS
+                                                        } else {
+                                                            WEBDAV_ALLPROP_PROPERTIES_NON_WINDOWS
+                                                        },
+                                                        PropfindMode::All)
+            }
+            PropfindVariant::PropName => {
+                self.handle_webdav_propfind_write_props(config, out, entries, WEBDAV_PROPNAME_PROPERTIES, PropfindMode::Names)
+            }
+            PropfindVariant::Props(props) => {
+                self.handle_webdav_propfind_write_props(config, out, entries, &[&props[..]], PropfindMode::Named)
+            }
+        }
+    }
 
-        let meta = path.metadata().expect("Failed to get requested file metadata");
-        self.handle_propfind_path(&mut out, &url, &path, &meta, props, just_names)?;
+    fn handle_webdav_propfind_write_props<'n, W: Write, N: BorrowXmlName<'n>>(&self, config: &HandlerConfig, mut out: XmlWriter<W>, entries: &[PropfindEntry],
+                                                                              props: &[&'n [N]], mode: PropfindMode)
+                                                                              -> Result<W, XmlWError> {
+        out.write(namespaces_for_props("D:multistatus", props.iter().flat_map(|pp| pp.iter())))?;
 
-        if meta.is_dir() {
-            if let Some(ir) = self.handle_webdav_propfind_path_recursive(req, &mut out, &mut url, &path, props, just_names, depth)? {
-                return Ok(Err(ir));
-            }
+        for entry in entries {
+            self.handle_propfind_path(config, &mut out, &entry.url, &entry.path, &entry.meta, props, mode)?;
         }
 
         out.write(XmlWEvent::end_element())?;
 
-        Ok(Ok(out.into_inner()))
+        Ok(out.into_inner())
+    }
+
+    /// Find what a PROPFIND of `path` at `depth` lists, starting with `path` itself, and then what's under it that `user` can see,
+    /// or `None` if that's more than `MAX_PROPFIND_INFINITY_ENTRIES` at depth infinity
+    fn propfind_entries(&self, config: &HandlerConfig, user: Option<&str>, url: &str, path: &Path, depth: Depth) -> Option<Vec<PropfindEntry>> {
+        let mut walk = PropfindWalk {
+            entries_left: if depth == Depth::Infinity {
+                MAX_PROPFIND_INFINITY_ENTRIES
+            } else {
+                usize::MAX
+            },
+            visited: path.canonicalize().into_iter().collect(),
+            entries: vec![PropfindEntry {
+                              url: url.to_string(),
+                              path: path.to_path_buf(),
+                              meta: path.metadata().expect("Failed to get requested file metadata"),
+                          }],
+        };
+        if walk.entries[0].meta.is_dir() {
+            self.propfind_entries_recursive(config, user, &mut url.to_string(), path, depth, &mut walk)?;
+        }
+        Some(walk.entries)
     }
 
-    fn handle_webdav_propfind_path_recursive<'n, W: Write, N: BorrowXmlName<'n>>(&self, req: &mut Request, out: &mut XmlWriter<W>, root_url: &mut String,
-                                                                                 root_path: &Path, props: &[&'n [N]], just_names: bool, depth: Depth)
-                                                                                 -> Result<Option<IronResult<Response>>, XmlWError> {
+    fn propfind_entries_recursive(&self, config: &HandlerConfig, user: Option<&str>, root_url: &mut String, root_path: &Path, depth: Depth,
+                                  walk: &mut PropfindWalk)
+                                  -> Option<()> {
         if !root_url.ends_with('/') {
             root_url.push('/');
         }
@@ -152,6 +214,13 @@ impl HttpHandler {
             for f in root_path.read_dir().expect("Failed to read requested directory").map(|p| p.expect("Failed to iterate over requested directory")) {
                 root_url.truncate(root_url_orig_len);
                 root_url.push_str(&f.file_name().to_string_lossy()[..]);
+                if config.acl.is_some() &&
+                   !GenericUrl::parse(root_url)
+                    .ok()
+                    .and_then(|url| percent_decode(url.path()).map(|url_p| config.acl_rights(user, &url_p).read))
+                    .unwrap_or(false) {
+                    continue;
+                }
 
                 let mut path = f.path();
                 let mut symlink = false;
@@ -172,20 +241,32 @@ impl HttpHandler {
 
                 if !(!path.exists() || (symlink && !self.follow_symlinks) ||
                      (symlink && self.follow_symlinks && self.sandbox_symlinks && !is_descendant_of(&path, &self.hosted_directory.1))) {
-                    let metadata = path.metadata().expect("Failed to get requested file metadata");
-                    self.handle_propfind_path(out, &root_url, &path, &metadata, props, just_names)?;
-                    if metadata.is_dir() {
-                        self.handle_webdav_propfind_path_recursive(req, out, root_url, &path, props, just_names, next_depth)?;
+                    if walk.entries_left == 0 {
+                        return None;
+                    }
+                    walk.entries_left -= 1;
+
+                    let meta = path.metadata().expect("Failed to get requested file metadata");
+                    // Symlinks can lead back up the tree
+                    let recurse = meta.is_dir() && path.canonicalize().map(|cp| walk.visited.insert(cp)).unwrap_or(false);
+                    walk.entries.push(PropfindEntry {
+                        url: root_url.clone(),
+                        path: path.clone(),
+                        meta,
+                    });
+                    if recurse {
+                        self.propfind_entries_recursive(config, user, root_url, &path, next_depth, walk)?;
                     }
                 }
             }
         }
 
-        Ok(None)
+        Some(())
     }
 
-    /// NB: we don't allow modifying any properties, so we 409 Conflict all of them
-    pub(super) fn handle_webdav_proppatch(&self, req: &mut Request) -> IronResult<Response> {
+    /// NB: we don't allow modifying live properties, so we 409 Conflict all of them (but act on some),
+    /// and store dead properties with `set_dead_properties()`
+    pub(super) fn handle_webdav_proppatch(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
         let (req_p, symlink, url_err) = self.parse_requested_path(req);
 
         if url_err {
@@ -201,7 +282,11 @@ impl HttpHandler {
             return self.handle_nonexistent(req, req_p);
         }
 
-        let (props, actionables) = match parse_proppatch(req) {
+        if let Some(resp) = self.check_webdav_locks(req, &req_p, false) {
+            return resp;
+        }
+
+        let (props, actionables, dead) = match parse_proppatch(req) {
             Ok(pp) => pp,
             Err(e) => {
                 log!(self.log,
@@ -229,14 +314,22 @@ impl HttpHandler {
         if let Some(ex) = actionables.executable {
             set_executable(&req_p, ex);
         }
+        // PROPPATCH is atomic (https://tools.ietf.org/html/rfc4918#section-9.2), so live properties failing means storing no dead ones
+        let dead_status = if props.iter().any(|(name, _)| is_live_property(name.borrow())) {
+            "HTTP/1.1 424 Failed Dependency"
+        } else if dead.is_empty() || update_dead_properties(&req_p, dead).is_ok() {
+            "HTTP/1.1 200 OK"
+        } else {
+            "HTTP/1.1 507 Insufficient Storage"
+        };
 
-        match write_proppatch_output(&props, req.url.as_ref()).expect("Couldn't write PROPPATCH XML") {
+        match write_proppatch_output(&props, dead_status, req.url.as_ref()).expect("Couldn't write PROPPATCH XML") {
             Ok(xml_resp) => Ok(Response::with((status::MultiStatus, xml_resp, text_xml_charset_utf8()))),
             Err(resp) => resp,
         }
     }
 
-    pub(super) fn handle_webdav_mkcol(&self, req: &mut Request) -> IronResult<Response> {
+    pub(super) fn handle_webdav_mkcol(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
         let (req_p, symlink, url_err) = self.parse_requested_path(req);
 
         log!(self.log,
@@ -257,6 +350,10 @@ impl HttpHandler {
             return self.handle_nonexistent_status(req, req_p, status::Conflict);
         }
 
+        if let Some(resp) = self.check_webdav_locks(req, &req_p, true) {
+            return resp;
+        }
+
         if req.body.read_exact(&mut [0]).is_ok() {
             return Ok(Response::with(status::UnsupportedMediaType));
         }
@@ -274,12 +371,12 @@ impl HttpHandler {
     }
 
     #[inline(always)]
-    pub(crate) fn handle_webdav_copy(&self, req: &mut Request) -> IronResult<Response> {
+    pub(crate) fn handle_webdav_copy(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
         self.handle_webdav_copy_move(req, false, None)
     }
 
     #[inline(always)]
-    pub(crate) fn handle_webdav_move(&self, req: &mut Request) -> IronResult<Response> {
+    pub(crate) fn handle_webdav_move(&self, req: &mut ConfiguredRequest) -> IronResult<Response> {
         let mut sp = (PathBuf::new(), false);
         let resp = self.handle_webdav_copy_move(req, true, Some(&mut sp))?;
 
@@ -287,19 +384,20 @@ impl HttpHandler {
             let (req_p, is_file) = sp;
 
             let removal = if is_file {
-                fs::remove_file(req_p)
+                fs::remove_file(&req_p)
             } else {
-                fs::remove_dir_all(req_p)
+                fs::remove_dir_all(&req_p)
             };
             if removal.is_err() {
                 return Ok(Response::with(status::Locked));
             }
+            self.drop_webdav_locks(&req_p);
         }
 
         Ok(resp)
     }
 
-    fn handle_webdav_copy_move(&self, req: &mut Request, is_move: bool, source_path: Option<&mut (PathBuf, bool)>) -> IronResult<Response> {
+    fn handle_webdav_copy_move(&self, req: &mut ConfiguredRequest, is_move: bool, source_path: Option<&mut (PathBuf, bool)>) -> IronResult<Response> {
         let (req_p, symlink, url_err) = self.parse_requested_path(req);
 
         if url_err {
@@ -343,20 +441,52 @@ impl HttpHandler {
             return self.handle_nonexistent(req, req_p);
         }
 
+        if !HttpHandler::preconditions_hold(req, &req_p) {
+            return self.handle_precondition_failed(req, req_p);
+        }
+
+        if is_move {
+            if let Some(resp) = self.check_webdav_locks(req, &req_p, true) {
+                return resp;
+            }
+        }
+        if let Some(resp) = self.check_webdav_locks(req, &dest_p, true) {
+            return resp;
+        }
+
         if !dest_p.parent().map(|pp| pp.exists()).unwrap_or(true) || (dest_symlink && !self.follow_symlinks) ||
            (dest_symlink && self.follow_symlinks && self.sandbox_symlinks && !is_descendant_of(&dest_p, &self.hosted_directory.1)) {
             return Ok(Response::with(status::Conflict));
         }
 
+        // Moves stay within the hosted directory
+        let counted = self.quota.is_some() && !is_move;
+        let allowed = if counted {
+            let copied = if req_p.is_dir() && depth != Depth::Infinity {
+                0
+            } else {
+                tree_size(&req_p)
+            };
+            match self.check_quota(req, &dest_p, copied) {
+                Ok(allowed) => allowed,
+                Err(resp) => return resp,
+            }
+        } else {
+            0
+        };
+        let replaced = if counted { file_size(&dest_p) } else { 0 };
+
         let mut overwritten = false;
         if dest_p.exists() {
             if !overwrite {
+                self.quota_written(allowed, 0);
                 return Ok(Response::with(status::PreconditionFailed));
             }
 
             if !is_actually_file(&dest_p.metadata().expect("Failed to get destination file metadata").file_type(), &dest_p) {
                 // NB: this disallows overwriting non-empty directories
                 if fs::remove_dir(&dest_p).is_err() {
+                    self.quota_written(allowed, 0);
                     return Ok(Response::with(status::Locked));
                 }
             }
@@ -368,11 +498,11 @@ impl HttpHandler {
         if let Some(sp) = source_path {
             *sp = (req_p.clone(), source_file);
         }
-        if source_file {
-            copy_response(fs::copy(req_p, dest_p).map(|_| ()), overwritten)
+        let resp = if source_file {
+            copy_response(fs::copy(&req_p, &dest_p).and_then(|_| copy_dead_properties(&req_p, &dest_p)), overwritten)
         } else {
             match depth {
-                Depth::Zero if !is_move => copy_response(fs::create_dir(dest_p), overwritten),
+                Depth::Zero if !is_move => copy_response(fs::create_dir(&dest_p).and_then(|_| copy_dead_properties(&req_p, &dest_p)), overwritten),
                 Depth::Infinity => {
                     match copy_dir(&req_p, &dest_p) {
                         Ok(errors) => {
@@ -392,13 +522,18 @@ impl HttpHandler {
                                                             error_html("400 Bad Request", format_args!("Invalid depth: {}", depth), ""))
                 }
             }
+        };
+
+        if counted {
+            self.quota_written(allowed, tree_size(&dest_p) as i64 - replaced as i64);
         }
+        resp
     }
 
     /// Adapted from
     /// https://github.com/tylerwhall/hyperdav-server/blob/415f512ac030478593ad389a3267aeed7441d826/src/lib.rs#L306
-    fn handle_propfind_path<'n, W: Write, N: BorrowXmlName<'n>>(&self, out: &mut XmlWriter<W>, url: &str, path: &Path, meta: &Metadata, props: &[&'n [N]],
-                                                                just_names: bool)
+    pub(super) fn handle_propfind_path<'n, W: Write, N: BorrowXmlName<'n>>(&self, config: &HandlerConfig, out: &mut XmlWriter<W>, url: &str, path: &Path, meta: &Metadata,
+                                                                props: &[&'n [N]], mode: PropfindMode)
                                                                 -> Result<(), XmlWError> {
         out.write(XmlWEvent::start_element("D:response"))?;
 
@@ -414,16 +549,26 @@ impl HttpHandler {
             let prop = prop.borrow_xml_name();
 
             let mut write_name = false;
-            if !just_names && !self.handle_prop_path(out, path, meta, prop)? {
+            if mode != PropfindMode::Names && !self.handle_prop_path(config, out, path, meta, prop)? {
                 failed_props.push(prop);
                 write_name = true;
             }
 
-            if just_names || write_name {
+            if mode == PropfindMode::Names || write_name {
                 start_client_prop_element(out, prop)?;
                 out.write(XmlWEvent::end_element())?;
             }
         }
+        if mode != PropfindMode::Named {
+            for (name, element) in read_dead_properties(path) {
+                if mode == PropfindMode::Names {
+                    start_client_prop_element(out, name.borrow())?;
+                    out.write(XmlWEvent::end_element())?;
+                } else {
+                    write_dead_property(out, &element)?;
+                }
+            }
+        }
         out.write(XmlWEvent::end_element())?; // prop
         out.write(XmlWEvent::start_element("D:status"))?;
         if failed_props.len() >= prop_count {
@@ -463,7 +608,7 @@ impl HttpHandler {
     /// https://github.com/tylerwhall/hyperdav-server/blob/415f512ac030478593ad389a3267aeed7441d826/src/lib.rs#L245
     /// extended properties adapted from
     /// https://github.com/miquels/webdav-handler-rs/blob/02433c1acfccd848a7de26889f6857cbad559076/src/handle_props.rs#L655
-    fn handle_prop_path<W: Write>(&self, out: &mut XmlWriter<W>, path: &Path, meta: &Metadata, prop: XmlName) -> Result<bool, XmlWError> {
+    fn handle_prop_path<W: Write>(&self, config: &HandlerConfig, out: &mut XmlWriter<W>, path: &Path, meta: &Metadata, prop: XmlName) -> Result<bool, XmlWError> {
         if prop.namespace == Some(WEBDAV_XML_NAMESPACE_DAV.1) {
             match prop.local_name {
                 "creationdate" => {
@@ -478,7 +623,7 @@ impl HttpHandler {
 
                 "getcontenttype" => {
                     out.write(XmlWEvent::start_element((WEBDAV_XML_NAMESPACE_DAV.0, "getcontenttype")))?;
-                    out.write(XmlWEvent::characters(&self.guess_mime_type(&path).to_string()))?;
+                    out.write(XmlWEvent::characters(&self.guess_mime_type_config(config, path).to_string()))?;
                 }
 
                 "getlastmodified" => {
@@ -494,6 +639,18 @@ impl HttpHandler {
                     }
                 }
 
+                "quota-available-bytes" | "quota-used-bytes" => {
+                    let (available, used) = match self.quota_space() {
+                        Some(space) => space,
+                        None => return Ok(false),
+                    };
+                    out.write(XmlWEvent::start_element((WEBDAV_XML_NAMESPACE_DAV.0, prop.local_name)))?;
+                    out.write(XmlWEvent::characters(&if prop.local_name == "quota-used-bytes" { used } else { available }.to_string()))?;
+                }
+
+                "supportedlock" => return write_supportedlock(out).map(|_| true),
+                "lockdiscovery" => return self.write_lockdiscovery(out, path).map(|_| true),
+
                 _ => return Ok(false),
             }
         } else if prop.namespace == Some(WEBDAV_XML_NAMESPACE_MICROSOFT.1) {
@@ -520,7 +677,7 @@ impl HttpHandler {
                     out.write(XmlWEvent::characters(&file_time_modified(meta).to_rfc3339()))?;
                 }
 
-                _ => return Ok(false),
+                _ => return handle_dead_prop_path(out, path, prop),
             }
         } else if prop.namespace == Some(WEBDAV_XML_NAMESPACE_APACHE.1) {
             match prop.local_name {
@@ -529,10 +686,10 @@ impl HttpHandler {
                     out.write(XmlWEvent::characters(if file_executable(&meta) { "T" } else { "F" }))?;
                 }
 
-                _ => return Ok(false),
+                _ => return handle_dead_prop_path(out, path, prop),
             }
         } else {
-            return Ok(false);
+            return handle_dead_prop_path(out, path, prop);
         }
 
         out.write(XmlWEvent::end_element())?;
@@ -541,9 +698,52 @@ impl HttpHandler {
 }
 
 
+/// A `Depth: infinity` PROPFIND response, written as the tree is walked instead of all at once
+struct PropfindInfinityBody {
+    handler: &'static HttpHandler,
+    config: Arc<HandlerConfig>,
+    microsoft: bool,
+    entries: Vec<PropfindEntry>,
+    props: PropfindVariant,
+}
+
+impl WriteBody for PropfindInfinityBody {
+    fn write_body(&mut self, res: &mut dyn Write) -> IoResult<()> {
+        self.handler
+            .handle_webdav_propfind_write_output(&self.config,
+                                                 intialise_xml_writer(res),
+                                                 &self.entries,
+                                                 &self.props,
+                                                 self.microsoft)
+            .map(|_| ())
+            .map_err(|err| match err {
+                XmlWError::Io(err) => err,
+                err => IoError::other(err.to_string()),
+            })
+    }
+}
+
+
+/// Progress through a PROPFIND's directory tree
+struct PropfindWalk {
+    /// Entries that can still be returned, up to `MAX_PROPFIND_INFINITY_ENTRIES` at depth infinity
+    entries_left: usize,
+    /// Canonical paths of the directories listed so far
+    visited: BTreeSet<PathBuf>,
+    entries: Vec<PropfindEntry>,
+}
+
+/// A resource a PROPFIND lists
+struct PropfindEntry {
+    url: String,
+    path: PathBuf,
+    meta: Metadata,
+}
+
+
 /// https://tools.ietf.org/html/rfc2518#section-12.14
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
-enum PropfindVariant {
+pub(super) enum PropfindVariant {
     AllProp,
     PropName,
     Props(Vec<OwnedXmlName>),
@@ -571,6 +771,27 @@ impl fmt::Display for PropfindVariant {
     }
 }
 
+/// A property we don't interpret, with its whole element
+type DeadProperty = (OwnedXmlName, Vec<XmlREvent>);
+
+/// A dead property to set to the element, or to remove
+type DeadPropertyChange = (OwnedXmlName, Option<Vec<XmlREvent>>);
+
+/// The properties in a PROPPATCH (with their value, if we act on it), what to do with them, and the dead properties to change
+type ProppatchRequest = (Vec<(OwnedXmlName, String)>, ProppatchActionables, Vec<DeadPropertyChange>);
+
+
+/// What to write for each resource in a PROPFIND response
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub(super) enum PropfindMode {
+    /// Values of the requested properties
+    Named,
+    /// Values of the requested properties and all dead properties
+    All,
+    /// Names of the requested properties and all dead properties
+    Names,
+}
+
 
 /// https://tools.ietf.org/html/rfc2518#section-12.14
 ///
@@ -622,8 +843,11 @@ fn start_client_prop_element<W: Write>(out: &mut XmlWriter<W>, prop: XmlName) ->
             return out.write(XmlWEvent::start_element(XmlName { prefix: Some(prefix), ..prop }));
         }
 
-        if prop.prefix.map(|prop_prefix| WEBDAV_XML_NAMESPACES.iter().any(|(pf, _)| *pf == prop_prefix)).unwrap_or(true) {
-            return out.write(XmlWEvent::start_element(XmlName { prefix: Some("U"), ..prop }).ns("U", prop_namespace));
+        match prop.prefix {
+            Some(prop_prefix) if !WEBDAV_XML_NAMESPACES.iter().any(|(pf, _)| *pf == prop_prefix) => {
+                return out.write(XmlWEvent::start_element(prop).ns(prop_prefix, prop_namespace))
+            }
+            _ => return out.write(XmlWEvent::start_element(XmlName { prefix: Some("U"), ..prop }).ns("U", prop_namespace)),
         }
     }
 
@@ -674,23 +898,25 @@ fn win32time(t: &str) -> Option<u64> {
 }
 
 /// https://tools.ietf.org/html/rfc2518#section-12.13
-fn parse_proppatch(req: &mut Request) -> Result<(Vec<(OwnedXmlName, String)>, ProppatchActionables), String> {
+fn parse_proppatch(req: &mut Request) -> Result<ProppatchRequest, String> {
     #[derive(Debug, Copy, Clone, Hash, PartialOrd, Ord, PartialEq, Eq)]
     enum State {
         Start,
         PropertyUpdate,
         Action,
         Prop,
-        InProp,
+        InProp(usize),
     }
 
     let mut xml = XmlReader::new_with_config(&mut req.body, default_xml_parser_config());
     let mut state = State::Start;
     let mut props = vec![];
+    let mut dead = vec![];
     let mut propname = None;
     let mut is_remove = false;
     let mut actionables = ProppatchActionables::new();
     let mut propdata = String::new();
+    let mut propelement = vec![];
 
     loop {
         let event = xml.next().map_err(|e| e.to_string())?;
@@ -707,68 +933,185 @@ fn parse_proppatch(req: &mut Request) -> Result<(Vec<(OwnedXmlName, String)>, Pr
                 state = State::Action;
                 is_remove = true;
             }
-            (State::PropertyUpdate, XmlREvent::EndElement { .. }) => return Ok((props, actionables)),
+            (State::PropertyUpdate, XmlREvent::EndElement { .. }) => return Ok((props, actionables, dead)),
 
             (State::Action, XmlREvent::StartElement { ref name, .. }) if name.local_name == "prop" => state = State::Prop,
             (State::Action, XmlREvent::EndElement { .. }) => state = State::PropertyUpdate,
 
-            (State::Prop, XmlREvent::StartElement { name, .. }) => {
-                state = State::InProp;
-                propname = Some(name);
+            (State::Prop, ev @ XmlREvent::StartElement { .. }) => {
+                state = State::InProp(0);
+                if let XmlREvent::StartElement { ref name, .. } = ev {
+                    propname = Some(name.clone());
+                }
+                propelement.push(ev);
             }
             (State::Prop, XmlREvent::EndElement { .. }) => state = State::Action,
 
-            (State::InProp, XmlREvent::EndElement { name, .. }) => {
-                if Some(&name) == propname.as_ref() {
-                    props.push((name, mem::take(&mut propdata)));
-                    state = State::Prop;
+            (State::InProp(0), ev @ XmlREvent::EndElement { .. }) => {
+                let name = propname.take().unwrap();
+                propelement.push(ev);
+                if !is_live_property(name.borrow()) {
+                    dead.push((name.clone(), if is_remove { None } else { Some(mem::take(&mut propelement)) }));
                 }
+                propelement.clear();
+                props.push((name, mem::take(&mut propdata)));
+                state = State::Prop;
             }
-            (State::InProp, XmlREvent::Characters(data)) if !is_remove => {
-                propdata = data;
-                match &propname.as_ref().unwrap().local_name[..] {
-                    "Win32CreationTime" => actionables.Win32CreationTime = win32time(&propdata),
-                    "Win32LastAccessTime" => actionables.Win32LastAccessTime = win32time(&propdata),
-                    "Win32LastModifiedTime" => actionables.Win32LastModifiedTime = win32time(&propdata),
-                    "executable" => actionables.executable = Some(propdata == "T"),
-                    _ => propdata = String::new(),
+            (State::InProp(depth), ev) => {
+                match ev {
+                    XmlREvent::Characters(ref data) if depth == 0 && !is_remove => {
+                        propdata = data.clone();
+                        match &propname.as_ref().unwrap().local_name[..] {
+                            "Win32CreationTime" => actionables.Win32CreationTime = win32time(&propdata),
+                            "Win32LastAccessTime" => actionables.Win32LastAccessTime = win32time(&propdata),
+                            "Win32LastModifiedTime" => actionables.Win32LastModifiedTime = win32time(&propdata),
+                            "executable" => actionables.executable = Some(propdata == "T"),
+                            _ => propdata = String::new(),
+                        }
+                    }
+                    XmlREvent::StartElement { .. } => state = State::InProp(depth + 1),
+                    XmlREvent::EndElement { .. } => state = State::InProp(depth - 1),
+                    _ => {}
                 }
+                propelement.push(ev);
             }
-            (State::InProp, _) => {}
 
             (st, ev) => return Err(format!("Unexpected event {:?} during state {:?}", ev, st)),
         }
     }
 }
 
-fn write_proppatch_output(props: &[(OwnedXmlName, String)], req_url: &GenericUrl) -> Result<Result<Vec<u8>, IronResult<Response>>, XmlWError> {
+/// Properties we report from the filesystem, and so can't store
+fn is_live_property(name: XmlName) -> bool {
+    match name.namespace {
+        Some(ns) if ns == WEBDAV_XML_NAMESPACE_DAV.1 => true,
+        Some(ns) if ns == WEBDAV_XML_NAMESPACE_MICROSOFT.1 => {
+            matches!(name.local_name,
+                     "Win32CreationTime" | "Win32FileAttributes" | "Win32LastAccessTime" | "Win32LastModifiedTime")
+        }
+        Some(ns) if ns == WEBDAV_XML_NAMESPACE_APACHE.1 => name.local_name == "executable",
+        _ => false,
+    }
+}
+
+fn write_proppatch_output(props: &[(OwnedXmlName, String)], dead_status: &str, req_url: &GenericUrl)
+                          -> Result<Result<Vec<u8>, IronResult<Response>>, XmlWError> {
     let mut out = intialise_xml_output()?;
     out.write(namespaces_for_props("D:multistatus", props.iter().map(|p| &p.0)))?;
+    out.write(XmlWEvent::start_element("D:response"))?;
 
     out.write(XmlWEvent::start_element("D:href"))?;
     out.write(XmlWEvent::characters(req_url.as_str()))?;
     out.write(XmlWEvent::end_element())?;
 
-    out.write(XmlWEvent::start_element("D:propstat"))?;
-
-    for (name, _) in props {
+    let status = |name: &OwnedXmlName| if is_live_property(name.borrow()) {
+        "HTTP/1.1 409 Conflict"
+    } else {
+        dead_status
+    };
+    for st in props.iter().map(|(name, _)| status(name)).collect::<BTreeSet<_>>() {
+        out.write(XmlWEvent::start_element("D:propstat"))?;
         out.write(XmlWEvent::start_element("D:prop"))?;
+        for (name, _) in props.iter().filter(|(name, _)| status(name) == st) {
+            start_client_prop_element(&mut out, name.borrow())?;
+            out.write(XmlWEvent::end_element())?;
+        }
+        out.write(XmlWEvent::end_element())?; // prop
 
-        start_client_prop_element(&mut out, name.borrow())?;
+        out.write(XmlWEvent::start_element("D:status"))?;
+        out.write(XmlWEvent::characters(st))?;
         out.write(XmlWEvent::end_element())?;
+        out.write(XmlWEvent::end_element())?; // propstat
+    }
 
-        out.write(XmlWEvent::end_element())?;
+    out.write(XmlWEvent::end_element())?; // response
+    out.write(XmlWEvent::end_element())?; // multistatus
+
+    Ok(Ok(out.into_inner()))
+}
+
+/// The dead properties of `path`, stored as the children of a `<D:prop>`
+fn read_dead_properties(path: &Path) -> Vec<DeadProperty> {
+    let raw = match dead_properties(path) {
+        Ok(Some(raw)) => raw,
+        _ => return vec![],
+    };
+
+    let mut props: Vec<DeadProperty> = vec![];
+    let mut depth = 0usize;
+    for event in XmlReader::new_with_config(&raw[..], default_xml_parser_config()) {
+        let event = match event {
+            Ok(event) => event,
+            Err(_) => break,
+        };
+
+        if let XmlREvent::StartElement { ref name, .. } = event {
+            depth += 1;
+            if depth == 2 {
+                props.push((name.clone(), vec![]));
+            }
+        }
+        let end = matches!(event, XmlREvent::EndElement { .. });
+        if depth >= 2 {
+            props.last_mut().unwrap().1.push(event);
+        }
+        if end {
+            depth -= 1;
+        }
     }
+    props
+}
 
-    out.write(XmlWEvent::start_element("D:status"))?;
-    out.write(XmlWEvent::characters("HTTP/1.1 409 Conflict"))?;
-    out.write(XmlWEvent::end_element())?;
+/// Apply a PROPPATCH's changes to the dead properties of `path`
+fn update_dead_properties(path: &Path, changes: Vec<DeadPropertyChange>) -> IoResult<()> {
+    let mut props = read_dead_properties(path);
+    for (name, element) in changes {
+        let existing = props.iter().position(|(pname, _)| same_property(pname.borrow(), name.borrow()));
+        match (existing, element) {
+            (Some(idx), Some(element)) => props[idx].1 = element,
+            (None, Some(element)) => props.push((name, element)),
+            (Some(idx), None) => {
+                props.remove(idx);
+            }
+            (None, None) => {}
+        }
+    }
 
-    out.write(XmlWEvent::end_element())?;
+    if props.is_empty() {
+        return set_dead_properties(path, None);
+    }
+
+    let raw = serialise_dead_properties(&props).map_err(|e| IoError::other(e.to_string()))?;
+    set_dead_properties(path, Some(&raw))
+}
 
+fn serialise_dead_properties(props: &[DeadProperty]) -> Result<Vec<u8>, XmlWError> {
+    let mut out = XmlWriter::new_with_config(vec![], XmlEmitterConfig::new().write_document_declaration(false));
+    out.write(XmlWEvent::start_element("D:prop").ns(WEBDAV_XML_NAMESPACE_DAV.0, WEBDAV_XML_NAMESPACE_DAV.1))?;
+    for (_, element) in props {
+        write_dead_property(&mut out, element)?;
+    }
     out.write(XmlWEvent::end_element())?;
+    Ok(out.into_inner())
+}
 
-    Ok(Ok(out.into_inner()))
+fn write_dead_property<W: Write>(out: &mut XmlWriter<W>, element: &[XmlREvent]) -> Result<(), XmlWError> {
+    for event in element.iter().flat_map(|ev| ev.as_writer_event()) {
+        out.write(event)?;
+    }
+    Ok(())
+}
+
+fn handle_dead_prop_path<W: Write>(out: &mut XmlWriter<W>, path: &Path, prop: XmlName) -> Result<bool, XmlWError> {
+    match read_dead_properties(path).into_iter().find(|(name, _)| same_property(name.borrow(), prop)) {
+        Some((_, element)) => write_dead_property(out, &element).map(|_| true),
+        None => Ok(false),
+    }
+}
+
+/// Names are the same if their namespaces are, regardless of prefix
+fn same_property(lhs: XmlName, rhs: XmlName) -> bool {
+    lhs.namespace == rhs.namespace && lhs.local_name == rhs.local_name
 }
 
 fn copy_response(op_result: IoResult<()>, overwritten: bool) -> IronResult<Response> {
@@ -806,8 +1149,12 @@ fn copy_response_multierror(errors: &[(IoError, String)], req_url: &GenericUrl)
     Ok(out.into_inner())
 }
 
-fn intialise_xml_output() -> Result<XmlWriter<Vec<u8>>, XmlWError> {
-    let mut out = XmlWriter::new_with_config(vec![], default_xml_emitter_config());
+pub(super) fn intialise_xml_output() -> Result<XmlWriter<Vec<u8>>, XmlWError> {
+    intialise_xml_writer(vec![])
+}
+
+fn intialise_xml_writer<W: Write>(into: W) -> Result<XmlWriter<W>, XmlWError> {
+    let mut out = XmlWriter::new_with_config(into, default_xml_emitter_config());
 
     out.write(XmlWEvent::StartDocument {
             version: XmlVersion::Version10,
@@ -818,7 +1165,7 @@ fn intialise_xml_output() -> Result<XmlWriter<Vec<u8>>, XmlWError> {
     Ok(out)
 }
 
-fn namespaces_for_props<'n, N: 'n + BorrowXmlName<'n>, Ni: Iterator<Item = &'n N>>(elem_name: &str, props: Ni) -> XmlWEventStartElementBuilder {
+pub(super) fn namespaces_for_props<'n, N: 'n + BorrowXmlName<'n>, Ni: Iterator<Item = &'n N>>(elem_name: &str, props: Ni) -> XmlWEventStartElementBuilder {
     props.map(|p| p.borrow_xml_name())
         .flat_map(|p| p.namespace)
         .collect::<BTreeSet<_>>()
@@ -829,6 +1176,6 @@ fn namespaces_for_props<'n, N: 'n + BorrowXmlName<'n>, Ni: Iterator<Item = &'n N
 }
 
 /// text/xml; charset=utf-8
-fn text_xml_charset_utf8() -> Mime {
+pub(super) fn text_xml_charset_utf8() -> Mime {
     Mime(MimeTopLevel::Text, MimeSubLevel::Xml, vec![(MimeAttr::Charset, MimeAttrValue::Utf8)])
 }
@@ -11,9 +11,10 @@
 //! ```
 
 
-use clap::{AppSettings, ErrorKind as ClapErrorKind, Error as ClapError, Arg, App};
+use clap::{AppSettings, ErrorKind as ClapErrorKind, Error as ClapError, ArgMatches, Arg, App};
 use std::collections::btree_map::{BTreeMap, Entry as BTreeMapEntry};
-use self::super::ops::WebDavLevel;
+use self::super::util::{password_hash_supported, activation_socket_count, MAX_TLS_VALIDITY_DAYS, MAX_SHARE_AGE};
+use self::super::ops::{ClientCertName, AccessLogFormat, TlsIdentity, WebDavLevel, TlsKeyType, AclSubject, AclRights, Acl};
 use std::ffi::{OsString, OsStr};
 use std::collections::BTreeSet;
 use std::env::{self, temp_dir};
@@ -23,8 +24,9 @@ use std::path::PathBuf;
 use std::str::FromStr;
 use std::borrow::Cow;
 use iron::mime::Mime;
-use std::net::IpAddr;
+use std::net::{SocketAddr, IpAddr};
 use cidr::IpCidr;
+use toml;
 use blake3;
 
 
@@ -52,6 +54,10 @@ impl From<u64> for LogLevel {
 }
 
 
+/// An htpasswd or token file, as specified and canonicalised, and its `username` -> password hash or token mapping
+pub type UserFile = ((String, PathBuf), BTreeMap<String, String>);
+
+
 /// Representation of the application's all configurable values.
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 pub struct Options {
@@ -59,8 +65,16 @@ pub struct Options {
     pub hosted_directory: (String, PathBuf),
     /// The port to host on. Default: first free port from 8000 up
     pub port: Option<u16>,
-    /// The address to bind to. Default: 0.0.0.0
-    pub bind_address: IpAddr,
+    /// The addresses to bind to, all on `port`. Default: 0.0.0.0, or none with only `listen`
+    pub bind_addresses: Vec<IpAddr>,
+    /// More addresses to listen on, and whether with TLS. Default: none
+    pub listen: Vec<(SocketAddr, bool)>,
+    /// Whether to redirect plain HTTP `listen` addresses to the first HTTPS one. Default: false
+    pub https_redirect: bool,
+    /// Unix domain sockets to listen on, as specified and absolute. Default: none
+    pub unix_sockets: Vec<(String, PathBuf)>,
+    /// Permissions to give `unix_sockets`. Default: from umask
+    pub unix_socket_mode: Option<u32>,
     /// Whether to allow symlinks to be requested. Default: true
     pub follow_symlinks: bool,
     /// Whether to disallow going out of the descendants of the hosted directory (via symlinks)
@@ -82,6 +96,8 @@ pub struct Options {
     pub try_404: Option<PathBuf>,
     /// Whether to allow write operations. Default: false
     pub allow_writes: bool,
+    /// Refuse uploads that would make the hosted directory larger than this. Default: `None`
+    pub quota: Option<u64>,
     /// Whether to encode filesystem files. Default: true
     pub encode_fs: bool,
     /// Consume at most this much space for encoded filesystem files.
@@ -102,40 +118,123 @@ pub struct Options {
     pub log_colour: bool,
     /// Whether to handle WebDAV requests. Default: false
     pub webdav: WebDavLevel,
+    /// Whether to allow `Depth: infinity` WebDAV PROPFINDs. Default: false
+    pub webdav_infinity: bool,
     /// Whether to allow requesting tar and ZIP archives. Default: false
     pub archives: bool,
-    /// Data for HTTPS, identity file and password. Default: `None`
-    pub tls_data: Option<((String, PathBuf), String)>,
+    /// Data for HTTPS, one PKCS#12 identity or PEM certificates and keys. Default: none
+    pub tls_data: Vec<TlsIdentity>,
     /// Whether to generate a one-off certificate. Default: false
     pub generate_tls: bool,
+    /// DNS names and IP addresses to make the generated certificate valid for, besides the bound addresses and hostname
+    pub tls_names: Vec<String>,
+    /// Default: `TlsKeyType::EcdsaP256`
+    pub tls_key_type: TlsKeyType,
+    /// How many days the generated certificate is valid for. Default: 3650
+    pub tls_validity: u64,
+    /// CA certificates to verify client certificates against, as specified and canonicalised. Default: `None`
+    pub client_ca: Option<(String, PathBuf)>,
+    /// Whether clients may connect without a certificate. Default: false
+    pub client_cert_optional: bool,
+    /// Default: `ClientCertName::CommonName`
+    pub client_cert_user: ClientCertName,
     /// Data for per-path authentication, in the form `username[:password]`, or `None` to explicitly disable
     pub path_auth_data: BTreeMap<String, Option<String>>,
     /// Paths for which to generate auth data
     pub generate_path_auth: BTreeSet<String>,
+    /// Users for per-path authentication from htpasswd files
+    pub path_htpasswd: BTreeMap<String, UserFile>,
+    /// Bearer tokens for per-path authentication from token files
+    pub path_tokens: BTreeMap<String, UserFile>,
+    /// Whether to use Digest instead of Basic authentication for passwords. Default: false
+    pub digest_auth: bool,
+    /// How many failed logins a client gets before it's locked out. Default: 5, `None` to never lock out
+    pub login_attempts: Option<NonZeroU64>,
+    /// Key for signing share links, and the file it's from. Default: `None`, no share links
+    pub share_secret: Option<((String, PathBuf), Vec<u8>)>,
+    /// Paths to print share links for, and for how many seconds they're valid
+    pub shares: Vec<(String, u64)>,
+    /// Per-user rights, and the file they're from. Default: `None`, everyone authenticated can do everything
+    pub acl: Option<((String, PathBuf), Acl)>,
     /// Header names and who we trust them from in `HEADER-NAME:CIDR` format
     pub proxies: BTreeMap<IpCidr, String>,
     /// Header names and who we trust them from in `HEADER-NAME:CIDR` format
     pub proxy_redirs: BTreeMap<IpCidr, String>,
+    /// Forwarded-for header name trusted from `--unix-socket` clients
+    pub unix_socket_proxy: Option<String>,
     /// Extension -> MIME type mapping overrides; empty string for no extension
     pub mime_type_overrides: BTreeMap<OsString, Mime>,
     /// Max amount of data per second each request is allowed to return. Default: `None`
     pub request_bandwidth: Option<NonZeroU64>,
+    /// Max amount of data per second each client (by address) is allowed to be returned, in total. Default: `None`
+    pub client_bandwidth: Option<NonZeroU64>,
+    /// Max amount of data per second each authenticated user is allowed to be returned, in total. Default: `None`
+    pub user_bandwidth: Option<NonZeroU64>,
+    /// Max amount of data per second returned to everyone. Default: `None`
+    pub total_bandwidth: Option<NonZeroU64>,
+    /// Like `request_bandwidth`, but for uploads. Default: `None`
+    pub request_upload_bandwidth: Option<NonZeroU64>,
+    /// Like `client_bandwidth`, but for uploads. Default: `None`
+    pub client_upload_bandwidth: Option<NonZeroU64>,
+    /// Like `user_bandwidth`, but for uploads. Default: `None`
+    pub user_upload_bandwidth: Option<NonZeroU64>,
+    /// Like `total_bandwidth`, but for uploads. Default: `None`
+    pub total_upload_bandwidth: Option<NonZeroU64>,
+    /// Max requests being handled at once. Default: `None`
+    pub max_requests: Option<NonZeroU64>,
+    /// Max requests being handled at once for each client (by address). Default: `None`
+    pub max_client_requests: Option<NonZeroU64>,
+    /// Max archives being generated at once. Default: `None`
+    pub max_archives: Option<NonZeroU64>,
+    /// Where to write a record of each request, as specified and canonicalised, or `None` for stdout. Default: nowhere
+    pub access_log: Option<Option<(String, PathBuf)>>,
+    /// Default: `AccessLogFormat::Combined`
+    pub access_log_format: AccessLogFormat,
+    /// Path to serve metrics at, normalised, without leading slash. Default: `None`
+    pub metrics_path: Option<String>,
+    /// Separate port to serve metrics on. Default: `None`
+    pub metrics_port: Option<u16>,
+    /// Where to write messages instead of stdout, as specified and canonicalised. Default: `None`
+    pub log_file: Option<(String, PathBuf)>,
+    /// Rotate the log file once it's at least this big. Default: `None`
+    pub log_rotate_size: Option<u64>,
+    /// Rotate the log file once it's been written to for this many seconds. Default: `None`
+    pub log_rotate_age: Option<u64>,
+    /// How many rotated log files to keep. Default: 5
+    pub log_keep: u64,
     /// Additional headers to add to every response
     pub additional_headers: Vec<(String, Vec<u8>)>,
+    pub config_file: Option<(String, PathBuf)>,
 }
 
 impl Options {
-    /// Parse `env`-wide command-line arguments into an `Options` instance
-    pub fn parse() -> Options {
-        let matches = App::new("http")
+    fn app(authors: &str) -> App<'_, '_> {
+        App::new("http")
             .version(crate_version!())
-            .author(&*env!("CARGO_PKG_AUTHORS").replace(":", "\n"))
+            .author(authors)
             .about(crate_description!())
             .setting(AppSettings::ColoredHelp)
+            .arg(Arg::from_usage("--config [CONFIG_FILE] 'Read options from a TOML file, overridden by ones given here'")
+                .validator(|s| Options::config_args(&s, None).map(|_| ())))
             .arg(Arg::from_usage("[DIR] 'Directory to host. Default: current working directory'")
                 .validator(|s| Options::filesystem_dir_validator(s, "Directory to host")))
             .arg(Arg::from_usage("-p --port [port] 'Port to use. Default: first free port from 8000 up'").validator(Options::u16_validator))
-            .arg(Arg::from_usage("-a --address [address] 'Address to bind to. Default: 0.0.0.0'").validator(Options::ipaddr_validator))
+            .arg(Arg::from_usage("-a --address [address]... 'Addresses to bind to, all on the same port. Default: 0.0.0.0'")
+                .number_of_values(1)
+                .validator(Options::ipaddr_validator))
+            .arg(Arg::from_usage("--listen [[SCHEME://]ADDRESS:PORT]... 'Also listen on ADDRESS:PORT, over http or https. Default: https with --ssl or --gen-ssl'")
+                .number_of_values(1)
+                .validator(|s| Options::listen_parse(&s).map(|_| ())))
+            .arg(Arg::from_usage("--redirect-https 'Redirect plain HTTP --listen addresses to the first HTTPS one'"))
+            .arg(Arg::from_usage("--unix-socket [PATH]... 'Also listen on a Unix domain socket at PATH, over http'")
+                .number_of_values(1)
+                .validator(Options::unix_socket_validator))
+            .arg(Arg::from_usage("--unix-socket-mode [MODE] 'Octal permissions for --unix-socket. Default: from umask'")
+                .requires("unix-socket")
+                .validator(|s| u32::from_str_radix(&s, 8).map(|_| ()).map_err(|_| format!("{} is not a valid octal mode", s))))
+            .arg(Arg::from_usage("--unix-socket-proxy [HEADER-NAME] 'Treat HEADER-NAME as proxy forwarded-for header for --unix-socket requests'")
+                .requires("unix-socket")
+                .empty_values(false))
             .arg(Arg::from_usage("-t --temp-dir [temp] 'Temporary directory. Default: $TEMP'")
                 .validator(|s| Options::filesystem_dir_validator(s, "Temporary directory")))
             .arg(Arg::from_usage("--404 [fallback-file] 'Return this file instead of a 404 for a GET. Default: generated response'"))
@@ -143,6 +242,8 @@ impl Options {
             .arg(Arg::from_usage("-r --sandbox-symlinks 'Restrict/sandbox where symlinks lead to only the direct descendants of the hosted directory. \
                                   Default: false'"))
             .arg(Arg::from_usage("-w --allow-write 'Allow for write operations. Default: false'"))
+            .arg(Arg::from_usage("--quota [SIZE] 'Refuse uploads that would make the hosted directory larger than SIZE'")
+                .validator(|s| Options::size_parse(s.into()).map(|_| ())))
             .arg(Arg::from_usage("-l --no-listings 'Never generate dir listings. Default: false'"))
             .arg(Arg::from_usage("-i --no-indices 'Do not automatically use index files. Default: false'"))
             .arg(Arg::from_usage("-e --no-encode 'Do not encode filesystem files. Default: false'"))
@@ -156,12 +257,41 @@ impl Options {
             .arg(Arg::from_usage("-q --quiet... 'Suppress increasing amounts of output'"))
             .arg(Arg::from_usage("-Q --quiet-time 'Don't prefix logs with the timestamp'"))
             .arg(Arg::from_usage("-c --no-colour 'Don't colourise the log output'"))
-            .arg(Arg::from_usage("-d --webdav 'Handle WebDAV requests (MKCOL, MOVE, PROPFIND, COPY, PROPPATCH). Default: false'"))
+            .arg(Arg::from_usage("-d --webdav 'Handle WebDAV requests (MKCOL, MOVE, PROPFIND, COPY, PROPPATCH, LOCK, UNLOCK, SEARCH). Default: false'"))
             .arg(Arg::from_usage("-D --convenient-webdav 'Allow WebDAV MKCOL and MOVE only. Default: false'"))
+            .arg(Arg::from_usage("--webdav-infinity 'Allow WebDAV PROPFINDs at Depth: infinity, streamed, of up to 100000 entries. Default: false'")
+                .requires("webdav"))
             .arg(Arg::from_usage("-A --archives 'Allow requesting tar and ZIP archives. Default: false'"))
-            .arg(Arg::from_usage("--ssl [TLS_IDENTITY] 'Data for HTTPS, identity file. Password in HTTP_SSL_PASS env var, otherwise empty'")
-                .validator(Options::identity_validator))
+            .arg(Arg::from_usage("--ssl [TLS_IDENTITY]... 'Data for HTTPS, PKCS#12 identity file or PEM certificate chain, chosen by SNI if multiple. \
+                                  PKCS#12 password in HTTP_SSL_PASS env var, otherwise empty'")
+                .number_of_values(1)
+                .validator(|s| Options::tls_file_validator(s, "identity")))
+            .arg(Arg::from_usage("--ssl-key [TLS_KEY]... 'PEM private key for the PEM --ssl certificate in the same position. Default: in the certificate file'")
+                .number_of_values(1)
+                .requires("ssl")
+                .validator(|s| Options::tls_file_validator(s, "key")))
             .arg(Arg::from_usage("--gen-ssl 'Generate a one-off TLS certificate'").conflicts_with("ssl"))
+            .arg(Arg::from_usage("--san [NAME]... 'Also make the generated TLS certificate valid for NAME, a DNS name or IP address'")
+                .number_of_values(1)
+                .requires("gen-ssl")
+                .validator(Options::san_validator))
+            .arg(Arg::from_usage("--gen-ssl-key [TYPE] 'Key type of the generated TLS certificate. Default: ecdsa-p256'")
+                .possible_values(&["ecdsa-p256", "ecdsa-p384"])
+                .requires("gen-ssl"))
+            .arg(Arg::from_usage("--gen-ssl-days [DAYS] 'Make the generated TLS certificate valid for DAYS. Default: 3650'")
+                .requires("gen-ssl")
+                .validator(|s| match u64::from_str(&s) {
+                    Ok(0) | Err(_) => Err(format!("{} is not a valid number of days", s)),
+                    Ok(days) if days > MAX_TLS_VALIDITY_DAYS => Err(format!("{} days is more than the maximum of {}", days, MAX_TLS_VALIDITY_DAYS)),
+                    Ok(_) => Ok(()),
+                }))
+            .arg(Arg::from_usage("--client-ca [CA_FILE] 'Require TLS client certificates signed by a PEM CA certificate in CA_FILE, \
+                                  authenticating them as the user they name'")
+                .validator(|s| Options::tls_file_validator(s, "client CA")))
+            .arg(Arg::from_usage("--client-cert-optional 'Allow clients without a certificate'").requires("client-ca"))
+            .arg(Arg::from_usage("--client-cert-user [FIELD] 'Take the username from the subject common name or the first subject alternative name. Default: cn'")
+                .possible_values(&["cn", "san"])
+                .requires("client-ca"))
             .arg(Arg::from_usage("--auth [USERNAME[:PASSWORD]] 'Data for global authentication'").validator(Options::credentials_validator))
             .arg(Arg::from_usage("--gen-auth 'Generate a one-off username:password set for global authentication'").conflicts_with("auth"))
             .arg(Arg::from_usage("--path-auth [PATH=[USERNAME[:PASSWORD]]]... 'Data for authentication under PATH'")
@@ -171,6 +301,30 @@ impl Options {
             .arg(Arg::from_usage("--gen-path-auth [PATH]... 'Generate a one-off username:password set for authentication under PATH'")
                 .number_of_values(1)
                 .use_delimiter(false))
+            .arg(Arg::from_usage("--htpasswd [HTPASSWD_FILE] 'Users for global authentication, with bcrypt, SHA-crypt, or Argon2 password hashes'")
+                .conflicts_with_all(&["auth", "gen-auth"])
+                .validator(|s| Options::htpasswd_parse(s).map(|_| ())))
+            .arg(Arg::from_usage("--path-htpasswd [PATH=HTPASSWD_FILE]... 'Users for authentication under PATH, like --htpasswd'")
+                .number_of_values(1)
+                .use_delimiter(false)
+                .validator(|s| Options::path_htpasswd_parse(s).map(|_| ())))
+            .arg(Arg::from_usage("--tokens [TOKEN_FILE] 'Bearer tokens for global authentication'").validator(|s| Options::tokens_parse(s).map(|_| ())))
+            .arg(Arg::from_usage("--path-tokens [PATH=TOKEN_FILE]... 'Bearer tokens for authentication under PATH, like --tokens'")
+                .number_of_values(1)
+                .use_delimiter(false)
+                .validator(|s| Options::path_tokens_parse(s).map(|_| ())))
+            .arg(Arg::from_usage("--digest 'Use Digest instead of Basic authentication for passwords'").conflicts_with_all(&["htpasswd", "path-htpasswd"]))
+            .arg(Arg::from_usage("--login-attempts [ATTEMPTS] 'Lock clients out after ATTEMPTS failed logins, for increasingly longer. 0 to never lock out. \
+                                  Default: 5'")
+                .validator(|s| u64::from_str(&s).map(|_| ()).map_err(|_| format!("{} is not a valid number of attempts", s))))
+            .arg(Arg::from_usage("--share-secret [SECRET_FILE] 'Key for signing share links, from a file'")
+                .validator(|s| Options::share_secret_parse(s).map(|_| ())))
+            .arg(Arg::from_usage("--share [PATH[=AGE]]... 'Print a share link for PATH, valid for AGE. Default: 1d'")
+                .number_of_values(1)
+                .use_delimiter(false)
+                .requires("share-secret")
+                .validator(|s| Options::share_parse(s).map(|_| ())))
+            .arg(Arg::from_usage("--acl [ACL_FILE] 'Per-user read, write, and delete rights under paths'").validator(|s| Options::acl_parse(s).map(|_| ())))
             .arg(Arg::from_usage("--proxy [HEADER-NAME:CIDR]... 'Treat HEADER-NAME as proxy forwarded-for header when request comes from CIDR'")
                 .number_of_values(1)
                 .use_delimiter(false)
@@ -186,14 +340,118 @@ impl Options {
                 .validator_os(|s| Options::mime_type_override_parse(s.into()).map(|_| ())))
             .arg(Arg::from_usage("--request-bandwidth [BYTES] 'Limit each request to returning BYTES per second, or 0 for unlimited. Default: 0'")
                 .validator(|s| Options::bandwidth_parse(s.into()).map(|_| ())))
+            .arg(Arg::from_usage("--client-bandwidth [BYTES] 'Limit each client to being returned BYTES per second, or 0 for unlimited. Default: 0'")
+                .validator(|s| Options::bandwidth_parse(s.into()).map(|_| ())))
+            .arg(Arg::from_usage("--user-bandwidth [BYTES] 'Limit each authenticated user to being returned BYTES per second, or 0 for unlimited. Default: 0'")
+                .validator(|s| Options::bandwidth_parse(s.into()).map(|_| ())))
+            .arg(Arg::from_usage("--total-bandwidth [BYTES] 'Limit the server to returning BYTES per second, or 0 for unlimited. Default: 0'")
+                .validator(|s| Options::bandwidth_parse(s.into()).map(|_| ())))
+            .arg(Arg::from_usage("--request-upload-bandwidth [BYTES] 'Like --request-bandwidth, but for uploads. Default: 0'")
+                .validator(|s| Options::bandwidth_parse(s.into()).map(|_| ())))
+            .arg(Arg::from_usage("--client-upload-bandwidth [BYTES] 'Like --client-bandwidth, but for uploads. Default: 0'")
+                .validator(|s| Options::bandwidth_parse(s.into()).map(|_| ())))
+            .arg(Arg::from_usage("--user-upload-bandwidth [BYTES] 'Like --user-bandwidth, but for uploads. Default: 0'")
+                .validator(|s| Options::bandwidth_parse(s.into()).map(|_| ())))
+            .arg(Arg::from_usage("--total-upload-bandwidth [BYTES] 'Like --total-bandwidth, but for uploads. Default: 0'")
+                .validator(|s| Options::bandwidth_parse(s.into()).map(|_| ())))
+            .arg(Arg::from_usage("--max-requests [REQUESTS] 'Turn requests away with 503 while handling REQUESTS at once, or 0 for unlimited. Default: 0'")
+                .validator(Options::concurrency_validator))
+            .arg(Arg::from_usage("--max-client-requests [REQUESTS] 'Turn requests away with 503 while handling REQUESTS at once for the same client, \
+                                  or 0 for unlimited. Default: 0'")
+                .validator(Options::concurrency_validator))
+            .arg(Arg::from_usage("--max-archives [ARCHIVES] 'Turn archive requests away with 503 while generating ARCHIVES at once, or 0 for unlimited. \
+                                  Default: 0'")
+                .validator(Options::concurrency_validator))
+            .arg(Arg::from_usage("--access-log [FILE] 'Append a record of each request to FILE, or - for stdout'").validator(Options::access_log_validator))
+            .arg(Arg::from_usage("--access-log-format [FORMAT] 'Write access log records in Apache Combined Log Format or as JSON lines. Default: combined'")
+                .possible_values(&["combined", "json"])
+                .requires("access-log"))
+            .arg(Arg::from_usage("--metrics [PATH] 'Serve Prometheus metrics at PATH'"))
+            .arg(Arg::from_usage("--metrics-port [PORT] 'Serve Prometheus metrics on a separate PORT'").validator(Options::u16_validator))
+            .arg(Arg::from_usage("--log-file [FILE] 'Write messages to FILE instead of stdout, reopening it on SIGHUP'")
+                .validator(|s| Options::log_path_validator(&s, "Log file")))
+            .arg(Arg::from_usage("--log-rotate-size [SIZE] 'Rotate the log file once it reaches SIZE'")
+                .requires("log-file")
+                .validator(|s| Options::size_parse(s.into()).map(|_| ())))
+            .arg(Arg::from_usage("--log-rotate-age [AGE] 'Rotate the log file once it's been written to for AGE'")
+                .requires("log-file")
+                .validator(|s| Options::age_parse(s.into()).map(|_| ())))
+            .arg(Arg::from_usage("--log-keep [COUNT] 'Keep COUNT rotated log files. Default: 5'")
+                .requires("log-file")
+                .validator(|s| u64::from_str(&s).map(|_| ()).map_err(|_| format!("{} is not a valid number of log files", s))))
             .arg(Arg::from_usage("-H --header [NAME: VALUE]... 'Headers to add to every response'")
                 .number_of_values(1)
                 .use_delimiter(false)
                 .validator(|s| Options::header_parse(&s).map(|_| ())))
-            .get_matches();
+    }
+
+    /// Parse the command-line arguments, with ones from the `--config` file, if any, before them
+    fn matches(authors: &str) -> Result<ArgMatches<'_>, ClapError> {
+        let args: Vec<OsString> = env::args_os().collect();
+        let cli = Options::app(authors).get_matches_from_safe(&args)?;
+        let config = match cli.value_of("config") {
+            Some(config) => config,
+            None => return Ok(cli),
+        };
+
+        let config_args = Options::config_args(config, Some(&cli)).map_err(|err| ClapError::with_description(&err, ClapErrorKind::InvalidValue))?;
+        if let Err(mut err) = Options::app(authors).get_matches_from_safe(args.iter().take(1).cloned().chain(config_args.iter().map(OsString::from))) {
+            err.message = format!("In config file {}:\n{}", config, err.message);
+            return Err(err);
+        }
+        Options::app(authors).get_matches_from_safe(args.iter().take(1).cloned().chain(config_args.into_iter().map(OsString::from)).chain(args.iter().skip(1).cloned()))
+    }
+
+    /// Turn a TOML config file into arguments, like `allow-write = true` into `--allow-write`,
+    /// `header = ["A: B", "C: D"]` into `--header=A: B --header=C: D`, and `dir = "/srv"` into `/srv`, first.
+    ///
+    /// Options given in `cli` are skipped.
+    fn config_args(path: &str, cli: Option<&ArgMatches>) -> Result<Vec<String>, String> {
+        let config = fs::read_to_string(path).map_err(|err| format!("Config file {} unreadable: {}", path, err))?;
+        let config: toml::value::Table = toml::from_str(&config).map_err(|err| format!("Config file {} invalid: {}", path, err))?;
+
+        let mut args = vec![];
+        for (key, value) in config {
+            let name = key.replace('_', "-");
+            let name = if name == "dir" { "DIR" } else { &name[..] };
+            if name == "config" {
+                return Err(format!("Config file {} can't include another config file", path));
+            }
+            if cli.map(|cli| cli.is_present(name)).unwrap_or(false) {
+                continue;
+            }
+
+            let values = match value {
+                toml::Value::Array(values) => values,
+                value => vec![value],
+            };
+            for value in values {
+                match (name, value) {
+                    ("DIR", toml::Value::String(dir)) => args.insert(0, dir),
+                    ("quiet", toml::Value::Integer(level)) => args.extend((0..level).map(|_| "--quiet".to_string())),
+                    (_, toml::Value::Boolean(true)) => args.push(format!("--{}", name)),
+                    (_, toml::Value::Boolean(false)) => {}
+                    (_, toml::Value::String(value)) => args.push(format!("--{}={}", name, value)),
+                    (_, toml::Value::Integer(value)) => args.push(format!("--{}={}", name, value)),
+                    (_, value) => return Err(format!("Config file {}: {} can't be {} {}", path, key, value.type_str(), value)),
+                }
+            }
+        }
+        Ok(args)
+    }
+
+    /// Parse `env`-wide command-line arguments into an `Options` instance
+    pub fn parse() -> Options {
+        Options::load().unwrap_or_else(|err| err.exit())
+    }
+
+    /// Like `parse()`, but returning errors instead of exiting, to reload the `--config` file
+    pub fn load() -> Result<Options, ClapError> {
+        let authors = env!("CARGO_PKG_AUTHORS").replace(":", "\n");
+        let matches = Options::matches(&authors)?;
 
         let dir = matches.value_of("DIR").unwrap_or(".");
-        let dir_pb = fs::canonicalize(dir).unwrap();
+        let dir_pb = Options::canonicalize_loaded(dir)?;
         let follow_symlinks = !matches.is_present("no-follow-symlinks");
 
         let mut path_auth_data = BTreeMap::new();
@@ -204,12 +462,131 @@ impl Options {
         if let Some(path_auth) = matches.values_of("path-auth") {
             for (path, auth) in path_auth.map(Options::decode_path_credentials) {
                 match path_auth_data.entry(path) {
-                    BTreeMapEntry::Occupied(oe) => Options::path_credentials_dupe(oe.key()),
+                    BTreeMapEntry::Occupied(oe) => return Err(Options::path_credentials_dupe(oe.key())),
                     BTreeMapEntry::Vacant(ve) => ve.insert(auth.map(Options::normalise_credentials)),
                 };
             }
         }
 
+        let mut path_htpasswd = BTreeMap::new();
+        if let Some(root_htpasswd) = matches.value_of("htpasswd") {
+            path_htpasswd.insert("".to_string(), Options::htpasswd_parse(root_htpasswd.to_string()).map_err(Options::load_error)?);
+        }
+
+        if let Some(path_htpasswds) = matches.values_of("path-htpasswd") {
+            for (path, htpasswd) in path_htpasswds.map(|s| Options::path_htpasswd_parse(s.to_string()).map_err(Options::load_error))
+                .collect::<Result<Vec<_>, _>>()? {
+                if path_auth_data.contains_key(&path) {
+                    return Err(Options::path_credentials_dupe(&path));
+                }
+
+                match path_htpasswd.entry(path) {
+                    BTreeMapEntry::Occupied(oe) => return Err(Options::path_credentials_dupe(oe.key())),
+                    BTreeMapEntry::Vacant(ve) => ve.insert(htpasswd),
+                };
+            }
+        }
+
+        let mut path_tokens = BTreeMap::new();
+        if let Some(root_tokens) = matches.value_of("tokens") {
+            path_tokens.insert("".to_string(), Options::tokens_parse(root_tokens.to_string()).map_err(Options::load_error)?);
+        }
+
+        if let Some(path_token_files) = matches.values_of("path-tokens") {
+            for (path, tokens) in path_token_files.map(|s| Options::path_tokens_parse(s.to_string()).map_err(Options::load_error))
+                .collect::<Result<Vec<_>, _>>()? {
+                match path_tokens.entry(path) {
+                    BTreeMapEntry::Occupied(oe) => return Err(Options::path_credentials_dupe(oe.key())),
+                    BTreeMapEntry::Vacant(ve) => ve.insert(tokens),
+                };
+            }
+        }
+        // Tokens are in addition to passwords, but not to explicitly disabled authentication, and need distinct names
+        for (path, (_, tokens)) in &path_tokens {
+            let passwords = match path_auth_data.get(path) {
+                Some(None) => return Err(Options::path_credentials_dupe(path)),
+                Some(Some(creds)) => vec![creds.split(':').next().unwrap()],
+                None => path_htpasswd.get(path).map(|(_, users)| users.keys().map(String::as_str).collect()).unwrap_or_default(),
+            };
+            if let Some(username) = passwords.into_iter().find(|username| tokens.contains_key(*username)) {
+                return Err(ClapError {
+                    message: format!("User \"{}\" has both a password and a token for path \"/{}\"", username, path),
+                    kind: ClapErrorKind::ArgumentConflict,
+                    info: None,
+                });
+            }
+        }
+
+        let mut tls_keys = matches.values_of("ssl-key")
+            .unwrap_or_default()
+            .map(|key| Options::canonicalize_loaded(key).map(|key_pb| (key.to_string(), key_pb)))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter();
+        let tls_data: Vec<_> = matches.values_of("ssl")
+            .unwrap_or_default()
+            .map(|id| {
+                let id = (id.to_string(), Options::canonicalize_loaded(id)?);
+                Ok(if fs::read(&id.1).map(|data| data.windows(11).any(|w| w == b"-----BEGIN ")).unwrap_or(false) {
+                    let key = tls_keys.next().unwrap_or_else(|| id.clone());
+                    TlsIdentity::Pem(id, key)
+                } else {
+                    TlsIdentity::Pkcs12(id, env::var("HTTP_SSL_PASS").unwrap_or_default())
+                })
+            })
+            .collect::<Result<_, ClapError>>()?;
+        if let Some((key, _)) = tls_keys.next() {
+            return Err(ClapError {
+                message: format!("No PEM --ssl certificate for TLS key {}", key),
+                kind: ClapErrorKind::ArgumentConflict,
+                info: None,
+            });
+        }
+        if let (Some(TlsIdentity::Pkcs12((id, _), _)), true) = (tls_data.iter().find(|id| matches!(id, TlsIdentity::Pkcs12(..))), tls_data.len() > 1) {
+            return Err(ClapError {
+                message: format!("PKCS#12 TLS identity {} can't be used with other certificates", id),
+                kind: ClapErrorKind::ArgumentConflict,
+                info: None,
+            });
+        }
+
+        let tls = matches.is_present("ssl") || matches.is_present("gen-ssl");
+        if matches.is_present("client-ca") && !tls {
+            return Err(ClapError {
+                message: "--client-ca needs --ssl or --gen-ssl".to_string(),
+                kind: ClapErrorKind::MissingRequiredArgument,
+                info: None,
+            });
+        }
+        let listen: Vec<_> = matches.values_of("listen")
+            .unwrap_or_default()
+            .map(|s| Options::listen_parse(s).map_err(Options::load_error))
+            .map(|listen| listen.map(|(addr, https)| (addr, https.unwrap_or(tls))))
+            .collect::<Result<_, _>>()?;
+        if let Some((addr, _)) = listen.iter().find(|(_, https)| *https && !tls) {
+            return Err(ClapError {
+                message: format!("Listening with HTTPS on {} needs --ssl or --gen-ssl", addr),
+                kind: ClapErrorKind::MissingRequiredArgument,
+                info: None,
+            });
+        }
+        let unix_sockets: Vec<_> = matches.values_of("unix-socket").unwrap_or_default().map(Options::log_path).collect();
+        let bind_addresses = match matches.values_of("address") {
+            Some(addrs) => addrs.map(IpAddr::from_str).map(Result::unwrap).collect(),
+            None if (listen.is_empty() && unix_sockets.is_empty() && activation_socket_count() == 0) || matches.is_present("port") => {
+                vec![IpAddr::from([0, 0, 0, 0])]
+            }
+            None => vec![],
+        };
+        let https_redirect = matches.is_present("redirect-https");
+        let https_anywhere = (tls && !bind_addresses.is_empty()) || listen.iter().any(|(_, https)| *https);
+        if https_redirect && !https_anywhere {
+            return Err(ClapError {
+                message: "--redirect-https needs something listening with HTTPS".to_string(),
+                kind: ClapErrorKind::MissingRequiredArgument,
+                info: None,
+            });
+        }
+
         let mut generate_path_auth = BTreeSet::new();
         if matches.is_present("gen-auth") {
             generate_path_auth.insert("".to_string());
@@ -217,25 +594,29 @@ impl Options {
 
         if let Some(gen_path_auth) = matches.values_of("gen-path-auth") {
             for path in gen_path_auth.map(Options::normalise_path) {
-                if path_auth_data.contains_key(&path) {
-                    Options::path_credentials_dupe(&path);
+                if path_auth_data.contains_key(&path) || path_htpasswd.contains_key(&path) {
+                    return Err(Options::path_credentials_dupe(&path));
                 }
 
                 if let Some(path) = generate_path_auth.replace(path) {
-                    Options::path_credentials_dupe(&path);
+                    return Err(Options::path_credentials_dupe(&path));
                 }
             }
         }
 
-        Options {
+        Ok(Options {
             hosted_directory: (dir.to_string(), dir_pb.clone()),
             port: matches.value_of("port").map(u16::from_str).map(Result::unwrap),
-            bind_address: matches.value_of("address").map(IpAddr::from_str).map(Result::unwrap).unwrap_or_else(|| "0.0.0.0".parse().unwrap()),
+            bind_addresses,
+            listen,
+            https_redirect,
+            unix_sockets,
+            unix_socket_mode: matches.value_of("unix-socket-mode").map(|s| u32::from_str_radix(s, 8).unwrap()),
             follow_symlinks: follow_symlinks,
             sandbox_symlinks: follow_symlinks && matches.is_present("sandbox-symlinks"),
             temp_directory: {
                 let (temp_s, temp_pb) = if let Some(tmpdir) = matches.value_of("temp-dir") {
-                    (tmpdir.to_string(), fs::canonicalize(tmpdir).unwrap())
+                    (tmpdir.to_string(), Options::canonicalize_loaded(tmpdir)?)
                 } else {
                     ("$TEMP".to_string(), temp_dir())
                 };
@@ -261,13 +642,14 @@ impl Options {
             strip_extensions: matches.is_present("strip-extensions"),
             try_404: matches.value_of("404").map(PathBuf::from),
             allow_writes: matches.is_present("allow-write"),
+            quota: matches.value_of("quota").and_then(|s| Options::size_parse(s.into()).ok()),
             encode_fs: !matches.is_present("no-encode"),
             encoded_filesystem_limit: matches.value_of("encoded-filesystem").and_then(|s| Options::size_parse(s.into()).ok()),
             encoded_generated_limit: matches.value_of("encoded-generated").and_then(|s| Options::size_parse(s.into()).ok()),
             encoded_prune: matches.value_of("encoded-prune").and_then(|s| Options::age_parse(s.into()).ok()),
             loglevel: matches.occurrences_of("quiet").into(),
             log_time: !matches.is_present("quiet-time"),
-            log_colour: !matches.is_present("no-colour"),
+            log_colour: !matches.is_present("no-colour") && !matches.is_present("log-file"),
             webdav: cmp::max(if matches.is_present("webdav") {
                             WebDavLevel::All
                         } else {
@@ -278,13 +660,28 @@ impl Options {
                         } else {
                             WebDavLevel::No
                         }),
+            webdav_infinity: matches.is_present("webdav-infinity"),
             archives: matches.is_present("archives"),
-            tls_data: matches.value_of("ssl").map(|id| ((id.to_string(), fs::canonicalize(id).unwrap()), env::var("HTTP_SSL_PASS").unwrap_or_default())),
+            tls_data,
             generate_tls: matches.is_present("gen-ssl"),
+            tls_names: matches.values_of("san").unwrap_or_default().map(str::to_string).collect(),
+            tls_key_type: matches.value_of("gen-ssl-key").and_then(TlsKeyType::from_name).unwrap_or(TlsKeyType::EcdsaP256),
+            tls_validity: matches.value_of("gen-ssl-days").map(u64::from_str).map(Result::unwrap).unwrap_or(3650),
+            client_ca: matches.value_of("client-ca").map(|ca| Options::canonicalize_loaded(ca).map(|ca_pb| (ca.to_string(), ca_pb))).transpose()?,
+            client_cert_optional: matches.is_present("client-cert-optional"),
+            client_cert_user: matches.value_of("client-cert-user").and_then(ClientCertName::from_name).unwrap_or(ClientCertName::CommonName),
             path_auth_data: path_auth_data,
             generate_path_auth: generate_path_auth,
+            path_htpasswd,
+            path_tokens,
+            digest_auth: matches.is_present("digest"),
+            login_attempts: matches.value_of("login-attempts").map(u64::from_str).map(Result::unwrap).map(NonZeroU64::new).unwrap_or(NonZeroU64::new(5)),
+            share_secret: matches.value_of("share-secret").map(|s| Options::share_secret_parse(s.to_string())).transpose().map_err(Options::load_error)?,
+            shares: matches.values_of("share").unwrap_or_default().map(|s| Options::share_parse(s.to_string()).unwrap()).collect(),
+            acl: matches.value_of("acl").map(|s| Options::acl_parse(s.to_string())).transpose().map_err(Options::load_error)?,
             proxies: matches.values_of("proxy").unwrap_or_default().map(Cow::from).map(Options::proxy_parse).map(Result::unwrap).collect(),
             proxy_redirs: matches.values_of("proxy-redir").unwrap_or_default().map(Cow::from).map(Options::proxy_parse).map(Result::unwrap).collect(),
+            unix_socket_proxy: matches.value_of("unix-socket-proxy").map(str::to_string),
             mime_type_overrides: matches.values_of_os("mime-type")
                 .unwrap_or_default()
                 .map(Cow::from)
@@ -292,14 +689,51 @@ impl Options {
                 .map(Result::unwrap)
                 .collect(),
             request_bandwidth: matches.value_of("request-bandwidth").map(Cow::from).map(Options::bandwidth_parse).map(Result::unwrap).unwrap_or_default(),
+            client_bandwidth: matches.value_of("client-bandwidth").map(Cow::from).map(Options::bandwidth_parse).map(Result::unwrap).unwrap_or_default(),
+            user_bandwidth: matches.value_of("user-bandwidth").map(Cow::from).map(Options::bandwidth_parse).map(Result::unwrap).unwrap_or_default(),
+            total_bandwidth: matches.value_of("total-bandwidth").map(Cow::from).map(Options::bandwidth_parse).map(Result::unwrap).unwrap_or_default(),
+            request_upload_bandwidth: matches.value_of("request-upload-bandwidth").map(Cow::from).map(Options::bandwidth_parse).map(Result::unwrap).unwrap_or_default(),
+            client_upload_bandwidth: matches.value_of("client-upload-bandwidth").map(Cow::from).map(Options::bandwidth_parse).map(Result::unwrap).unwrap_or_default(),
+            user_upload_bandwidth: matches.value_of("user-upload-bandwidth").map(Cow::from).map(Options::bandwidth_parse).map(Result::unwrap).unwrap_or_default(),
+            total_upload_bandwidth: matches.value_of("total-upload-bandwidth").map(Cow::from).map(Options::bandwidth_parse).map(Result::unwrap).unwrap_or_default(),
+            max_requests: matches.value_of("max-requests").map(u64::from_str).map(Result::unwrap).and_then(NonZeroU64::new),
+            max_client_requests: matches.value_of("max-client-requests").map(u64::from_str).map(Result::unwrap).and_then(NonZeroU64::new),
+            max_archives: matches.value_of("max-archives").map(u64::from_str).map(Result::unwrap).and_then(NonZeroU64::new),
+            access_log: matches.value_of("access-log").map(|s| if s == "-" {
+                None
+            } else {
+                Some(Options::log_path(s))
+            }),
+            metrics_path: matches.value_of("metrics").map(Options::normalise_path),
+            metrics_port: matches.value_of("metrics-port").map(u16::from_str).map(Result::unwrap),
+            log_file: matches.value_of("log-file").map(Options::log_path),
+            log_rotate_size: matches.value_of("log-rotate-size").map(|s| Options::size_parse(s.into()).unwrap()).filter(|&size| size != 0),
+            log_rotate_age: matches.value_of("log-rotate-age").map(|s| Options::age_parse(s.into()).unwrap()).filter(|&age| age != 0),
+            log_keep: matches.value_of("log-keep").map(u64::from_str).map(Result::unwrap).unwrap_or(5),
+            access_log_format: matches.value_of("access-log-format").and_then(AccessLogFormat::from_name).unwrap_or(AccessLogFormat::Combined),
             additional_headers: matches.values_of("header")
                 .unwrap_or_default()
                 .map(Options::header_parse)
                 .map(Result::unwrap)
                 .collect(),
+            config_file: matches.value_of("config").map(Options::log_path),
+        })
+    }
+
+    /// Files checked by the validators can be gone by the time they're used, especially when reloading
+    fn load_error(message: String) -> ClapError {
+        ClapError {
+            message,
+            kind: ClapErrorKind::ValueValidation,
+            info: None,
         }
     }
 
+    /// `fs::canonicalize()`, for paths checked by the validators
+    fn canonicalize_loaded(s: &str) -> Result<PathBuf, ClapError> {
+        fs::canonicalize(s).map_err(|err| Options::load_error(format!("\"{}\": {}", s, err)))
+    }
+
     fn filesystem_dir_validator(s: String, prefix: &str) -> Result<(), String> {
         fs::canonicalize(&s).map_err(|_| format!("{} \"{}\" not found", prefix, s)).and_then(|f| if f.is_dir() {
             Ok(())
@@ -308,11 +742,11 @@ impl Options {
         })
     }
 
-    fn identity_validator(s: String) -> Result<(), String> {
-        fs::canonicalize(&s).map_err(|_| format!("TLS identity file \"{}\" not found", s)).and_then(|f| if f.is_file() {
+    fn tls_file_validator(s: String, what: &str) -> Result<(), String> {
+        fs::canonicalize(&s).map_err(|_| format!("TLS {} file \"{}\" not found", what, s)).and_then(|f| if f.is_file() {
             Ok(())
         } else {
-            Err(format!("TLS identity file \"{}\" not actually a file", s))
+            Err(format!("TLS {} file \"{}\" not actually a file", what, s))
         })
     }
 
@@ -356,13 +790,141 @@ impl Options {
               }))
     }
 
-    fn path_credentials_dupe(path: &str) -> ! {
-        ClapError {
-                message: format!("Credentials for path \"/{}\" already present", path),
-                kind: ClapErrorKind::ArgumentConflict,
-                info: None,
+    fn htpasswd_parse(s: String) -> Result<UserFile, String> {
+        let file = fs::canonicalize(&s).map_err(|_| format!("htpasswd file \"{}\" not found", s))?;
+        let content = fs::read_to_string(&file).map_err(|e| format!("htpasswd file \"{}\" unreadable: {}", s, e))?;
+
+        let mut users = BTreeMap::new();
+        for (i, line) in content.lines().enumerate().map(|(i, l)| (i + 1, l.trim())).filter(|(_, l)| !l.is_empty() && !l.starts_with('#')) {
+            let (username, hash) = line.split_once(':').ok_or_else(|| format!("htpasswd file \"{}\" line {} not in format \"username:hash\"", s, i))?;
+            if username.is_empty() {
+                return Err(format!("htpasswd file \"{}\" line {} has no username", s, i));
+            }
+            if !password_hash_supported(hash) {
+                return Err(format!("htpasswd file \"{}\" line {}: password hash for \"{}\" not bcrypt, SHA-crypt, or Argon2", s, i, username));
+            }
+            if users.insert(username.to_string(), hash.to_string()).is_some() {
+                return Err(format!("htpasswd file \"{}\" line {}: user \"{}\" already present", s, i, username));
+            }
+        }
+
+        Ok(((s, file), users))
+    }
+
+    fn path_htpasswd_parse(s: String) -> Result<(String, UserFile), String> {
+        match s.split_once('=') {
+            Some((path, file)) => Ok((Options::normalise_path(path), Options::htpasswd_parse(file.to_string())?)),
+            None => Err(format!("Per-path htpasswd file \"{}\" needs be in format \"path=htpasswd_file\"", s)),
+        }
+    }
+
+    fn tokens_parse(s: String) -> Result<UserFile, String> {
+        let file = fs::canonicalize(&s).map_err(|_| format!("Token file \"{}\" not found", s))?;
+        let content = fs::read_to_string(&file).map_err(|e| format!("Token file \"{}\" unreadable: {}", s, e))?;
+
+        let mut tokens = BTreeMap::new();
+        for (i, line) in content.lines().enumerate().map(|(i, l)| (i + 1, l.trim())).filter(|(_, l)| !l.is_empty() && !l.starts_with('#')) {
+            let (name, token) = line.split_once(':').ok_or_else(|| format!("Token file \"{}\" line {} not in format \"name:token\"", s, i))?;
+            if name.is_empty() || token.is_empty() {
+                return Err(format!("Token file \"{}\" line {} has no name or no token", s, i));
+            }
+            if tokens.values().any(|t| t == token) {
+                return Err(format!("Token file \"{}\" line {}: token for \"{}\" already used", s, i, name));
+            }
+            if tokens.insert(name.to_string(), token.to_string()).is_some() {
+                return Err(format!("Token file \"{}\" line {}: \"{}\" already present", s, i, name));
+            }
+        }
+
+        Ok(((s, file), tokens))
+    }
+
+    fn path_tokens_parse(s: String) -> Result<(String, UserFile), String> {
+        match s.split_once('=') {
+            Some((path, file)) => Ok((Options::normalise_path(path), Options::tokens_parse(file.to_string())?)),
+            None => Err(format!("Per-path token file \"{}\" needs be in format \"path=token_file\"", s)),
+        }
+    }
+
+    fn share_secret_parse(s: String) -> Result<((String, PathBuf), Vec<u8>), String> {
+        let file = fs::canonicalize(&s).map_err(|_| format!("Share secret file \"{}\" not found", s))?;
+        let mut secret = fs::read(&file).map_err(|e| format!("Share secret file \"{}\" unreadable: {}", s, e))?;
+        while secret.last().map(u8::is_ascii_whitespace).unwrap_or(false) {
+            secret.pop();
+        }
+        if secret.is_empty() {
+            return Err(format!("Share secret file \"{}\" empty", s));
+        }
+
+        Ok(((s, file), secret))
+    }
+
+    fn share_parse(s: String) -> Result<(String, u64), String> {
+        match s.split_once('=') {
+            Some((path, age)) => {
+                let age = Options::age_parse(age.into())?;
+                if age > MAX_SHARE_AGE {
+                    return Err(format!("{} longer than the maximum share link age of {}s", age, MAX_SHARE_AGE));
+                }
+                Ok((Options::normalise_path(path), age))
             }
-            .exit()
+            None => Ok((Options::normalise_path(&s), 60 * 60 * 24)),
+        }
+    }
+
+    fn acl_parse(s: String) -> Result<((String, PathBuf), Acl), String> {
+        let file = fs::canonicalize(&s).map_err(|_| format!("ACL file \"{}\" not found", s))?;
+        let content = fs::read_to_string(&file).map_err(|e| format!("ACL file \"{}\" unreadable: {}", s, e))?;
+
+        let mut acl = Acl::default();
+        for (i, line) in content.lines().enumerate().map(|(i, l)| (i + 1, l.trim())).filter(|(_, l)| !l.is_empty() && !l.starts_with('#')) {
+            if let Some(group) = line.strip_prefix('@') {
+                let (name, members) = group.split_once('=').ok_or_else(|| format!("ACL file \"{}\" line {} not in format \"@group = user...\"", s, i))?;
+                acl.groups.entry(name.trim().to_string()).or_default().extend(members.split_whitespace().map(str::to_string));
+                continue;
+            }
+
+            let (path, subject, rights) = match line.rsplit_once(char::is_whitespace)
+                .and_then(|(rest, rights)| rest.trim_end().rsplit_once(char::is_whitespace).map(|(path, subject)| (path.trim_end(), subject, rights))) {
+                Some(fields) => fields,
+                None => return Err(format!("ACL file \"{}\" line {} not in format \"path user|@group|* rights\"", s, i)),
+            };
+
+            let subject = match subject {
+                "*" => AclSubject::Anyone,
+                group if group.starts_with('@') => AclSubject::Group(group[1..].to_string()),
+                user => AclSubject::User(user.to_string()),
+            };
+            let rights = rights.split(',').try_fold(AclRights::NONE, |acc, right| {
+                    Ok(acc.union(match right {
+                        "read" => AclRights::READ,
+                        "write" => AclRights::WRITE,
+                        "delete" => AclRights::DELETE,
+                        "all" => AclRights::ALL,
+                        "none" => AclRights::NONE,
+                        _ => return Err(format!("ACL file \"{}\" line {}: \"{}\" not one of read, write, delete, all, none", s, i, right)),
+                    }))
+                })?;
+            acl.rules.entry(Options::normalise_path(path)).or_default().push((subject, rights));
+        }
+
+        for subject in acl.rules.values().flatten().map(|(subject, _)| subject) {
+            if let AclSubject::Group(group) = subject {
+                if !acl.groups.contains_key(group) {
+                    return Err(format!("ACL file \"{}\" uses undefined group \"@{}\"", s, group));
+                }
+            }
+        }
+
+        Ok(((s, file), acl))
+    }
+
+    fn path_credentials_dupe(path: &str) -> ClapError {
+        ClapError {
+            message: format!("Credentials for path \"/{}\" already present", path),
+            kind: ClapErrorKind::ArgumentConflict,
+            info: None,
+        }
     }
 
     fn normalise_path(path: &str) -> String {
@@ -399,10 +961,66 @@ impl Options {
         IpAddr::from_str(&s).map(|_| ()).map_err(|_| format!("{} is not a valid IP address", s))
     }
 
+    fn san_validator(s: String) -> Result<(), String> {
+        if IpAddr::from_str(&s).is_ok() ||
+           (!s.is_empty() && s.split('.').all(|label| !label.is_empty() && label.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_*".contains(&b)))) {
+            Ok(())
+        } else {
+            Err(format!("{} is not a valid DNS name or IP address", s))
+        }
+    }
+
+    /// `[http://|https://]ADDRESS:PORT` -> (ADDRESS:PORT, whether https, if specified)
+    fn listen_parse(s: &str) -> Result<(SocketAddr, Option<bool>), String> {
+        let (addr, https) = if let Some(addr) = s.strip_prefix("http://") {
+            (addr, Some(false))
+        } else if let Some(addr) = s.strip_prefix("https://") {
+            (addr, Some(true))
+        } else {
+            (s, None)
+        };
+        SocketAddr::from_str(addr.trim_end_matches('/')).map(|addr| (addr, https)).map_err(|_| format!("{} is not a valid [http://|https://]ADDRESS:PORT", s))
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    fn unix_socket_validator(s: String) -> Result<(), String> {
+        Options::log_path_validator(&s, "Unix socket")
+    }
+
+    #[cfg(target_os = "windows")]
+    fn unix_socket_validator(s: String) -> Result<(), String> {
+        Err(format!("Can't listen on Unix socket {}: not supported on Windows", s))
+    }
+
     fn u16_validator(s: String) -> Result<(), String> {
         u16::from_str(&s).map(|_| ()).map_err(|_| format!("{} is not a valid port number", s))
     }
 
+    fn concurrency_validator(s: String) -> Result<(), String> {
+        u64::from_str(&s).map(|_| ()).map_err(|_| format!("{} is not a valid number", s))
+    }
+
+    fn access_log_validator(s: String) -> Result<(), String> {
+        if s == "-" {
+            return Ok(());
+        }
+        Options::log_path_validator(&s, "Access log")
+    }
+
+    fn log_path_validator(s: &str, what: &str) -> Result<(), String> {
+        let (_, path) = Options::log_path(s);
+        match path.parent() {
+            Some(dir) if !dir.is_dir() => Err(format!("{} directory {} not found", what, dir.display())),
+            _ if path.is_dir() => Err(format!("{} {} is a directory", what, s)),
+            _ => Ok(()),
+        }
+    }
+
+    fn log_path(s: &str) -> (String, PathBuf) {
+        let path = env::current_dir().map(|cwd| cwd.join(s)).unwrap_or_else(|_| PathBuf::from(s));
+        (s.to_string(), path)
+    }
+
     fn size_parse<'s>(s: Cow<'s, str>) -> Result<u64, String> {
         let mut s = &s[..];
         if matches!(s.as_bytes().last(), Some(b'b' | b'B')) {
@@ -422,7 +1040,7 @@ impl Options {
         s.parse().map(|size: u64| size * mul).map_err(|e| format!("{} not a valid (optionally-K/M/G/T/P[B]-suffixed) number: {}", s, e))
     }
 
-    fn age_parse<'s>(s: Cow<'s, str>) -> Result<u64, String> {
+    pub fn age_parse<'s>(s: Cow<'s, str>) -> Result<u64, String> {
         let mut s = &s[..];
         let (mul, trim) = match s.as_bytes().last() {
             Some(b's') => (1, true),
@@ -434,7 +1052,8 @@ impl Options {
         if trim {
             s = &s[..s.len() - 1];
         }
-        s.parse().map(|age: u64| age * mul).map_err(|e| format!("{} not a valid (optionally-s/m/h/d-suffixed) number: {}", s, e))
+        let age: u64 = s.parse().map_err(|e| format!("{} not a valid (optionally-s/m/h/d-suffixed) number: {}", s, e))?;
+        age.checked_mul(mul).ok_or_else(|| format!("{} too large", s))
     }
 
     fn proxy_parse<'s>(s: Cow<'s, str>) -> Result<(IpCidr, String), String> {
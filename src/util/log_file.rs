@@ -0,0 +1,106 @@
+use std::io::{ErrorKind as IoErrorKind, Result as IoResult, Write, stdout};
+use self::super::{redirect_stdout, hangups};
+use std::fs::{self, OpenOptions, File};
+use std::time::{Duration, Instant};
+use std::path::PathBuf;
+use std::thread;
+
+
+/// How often to check whether the log file needs to be rotated or reopened
+const LOG_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+
+/// The file stdout is redirected to with `--log-file`
+#[derive(Debug)]
+pub struct LogFile {
+    pub path: (String, PathBuf),
+    /// In bytes
+    pub rotate_size: Option<u64>,
+    /// In seconds
+    pub rotate_age: Option<u64>,
+    /// How many rotated files to keep, as `path.1` (newest) through `path.keep`
+    pub keep: u64,
+    current: File,
+    opened: Instant,
+    hangups: u64,
+}
+
+impl LogFile {
+    /// Open `path` for appending and redirect stdout to it
+    pub fn open(path: (String, PathBuf), rotate_size: Option<u64>, rotate_age: Option<u64>, keep: u64) -> IoResult<LogFile> {
+        let current = LogFile::open_redirect(&path.1)?;
+        Ok(LogFile {
+            path,
+            rotate_size,
+            rotate_age,
+            keep,
+            current,
+            opened: Instant::now(),
+            hangups: hangups(),
+        })
+    }
+
+    /// Reopen the log file on SIGHUP and rotate it when it gets too big or old, forever
+    pub fn maintain(mut self) -> ! {
+        loop {
+            thread::sleep(LOG_FILE_CHECK_INTERVAL);
+
+            let hangups = hangups();
+            if hangups != self.hangups {
+                self.hangups = hangups;
+                if let Err(err) = self.reopen() {
+                    eprintln!("Reopening log file {}: {}", self.path.0, err);
+                }
+            } else if self.needs_rotation() {
+                if let Err(err) = self.rotate() {
+                    eprintln!("Rotating log file {}: {}", self.path.0, err);
+                }
+            }
+        }
+    }
+
+    fn needs_rotation(&self) -> bool {
+        let len = self.current.metadata().map(|m| m.len()).unwrap_or(0);
+        self.rotate_size.map(|max| len >= max).unwrap_or(false) ||
+        self.rotate_age.map(|age| len != 0 && self.opened.elapsed() >= Duration::from_secs(age)).unwrap_or(false)
+    }
+
+    /// Move `path.1` to `path.2`, &c., dropping the ones past `keep`, then `path` to `path.1`, and start a new one
+    fn rotate(&mut self) -> IoResult<()> {
+        let _ = stdout().flush();
+
+        if self.keep == 0 {
+            fs::remove_file(&self.path.1)?;
+        } else {
+            for i in (1..self.keep).rev() {
+                match fs::rename(self.rotated(i), self.rotated(i + 1)) {
+                    Err(e) if e.kind() != IoErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path.1, self.rotated(1))?;
+        }
+
+        self.reopen()
+    }
+
+    fn reopen(&mut self) -> IoResult<()> {
+        let _ = stdout().flush();
+
+        self.current = LogFile::open_redirect(&self.path.1)?;
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    fn rotated(&self, i: u64) -> PathBuf {
+        let mut path = self.path.1.clone().into_os_string();
+        path.push(format!(".{}", i));
+        path.into()
+    }
+
+    fn open_redirect(path: &PathBuf) -> IoResult<File> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        redirect_stdout(&file)?;
+        Ok(file)
+    }
+}
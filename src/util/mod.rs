@@ -3,19 +3,25 @@
 
 mod os;
 mod webdav;
+mod log_file;
 mod content_encoding;
+#[cfg(not(target_os = "windows"))]
+mod unix_socket;
 
 use std::path::Path;
 use percent_encoding;
+use std::net::{SocketAddr, Ipv4Addr, IpAddr};
 use walkdir::WalkDir;
+use pwhash;
 use std::borrow::Cow;
 use rfsapi::RawFileData;
 use iron::{mime, Headers, Url};
+use argon2::{PasswordVerifier, PasswordHash, Argon2};
 use std::{cmp, fmt, f64, mem, str};
 use mime_guess::guess_mime_type_opt;
-use std::time::{SystemTime, Instant};
+use std::time::{SystemTime, Duration, Instant};
 use std::fs::{self, FileType, Metadata, File};
-use iron::headers::{HeaderFormat, UserAgent, Header};
+use iron::headers::{HeaderFormat, ByteRangeSpec, UserAgent, Header};
 use chrono::{DateTime, TimeZone, Utc, Local as LocalTz};
 use xml::name::{OwnedName as OwnedXmlName, Name as XmlName};
 use iron::error::{HttpResult as HyperResult, HttpError as HyperError};
@@ -24,7 +30,10 @@ use std::io::{ErrorKind as IoErrorKind, Result as IoResult, Error as IoError, Wr
 
 pub use self::os::*;
 pub use self::webdav::*;
+pub use self::log_file::LogFile;
 pub use self::content_encoding::*;
+#[cfg(not(target_os = "windows"))]
+pub use self::unix_socket::{UnixSocketListener, UnixSocketStream, bind_unix};
 
 
 pub trait HtmlResponseElement {
@@ -73,34 +82,28 @@ pub const USER_AGENT: &str = concat!("http/", env!("CARGO_PKG_VERSION"));
 /// Index file extensions to look for if `-i` was not specified and strippable extensions to look for if `-x` was specified.
 pub const INDEX_EXTENSIONS: &[&str] = &["html", "htm", "shtml"];
 
+/// What requests over Unix domain sockets seem to come from and be sent to.
+///
+/// No TCP peer can have the unspecified address, so these are never mistaken for localhost.
+pub const UNIX_SOCKET_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
 /// Maximum amount of symlinks to follow in any given path lookup.
 ///
 /// `40` matches the linux `MAXSYMLINKS` macro in `include/linux/namei.h`.
 pub const MAX_SYMLINKS: usize = 40;
 
+/// Longest a generated TLS certificate can be valid for, in days: about 1000 years, well within X.509's year 9999.
+pub const MAX_TLS_VALIDITY_DAYS: u64 = 1000 * 365;
 
-/// The [WWW-Authenticate header](https://tools.ietf.org/html/rfc7235#section-4.1), without parsing.
-///
-/// We don't ever receive this header, only ever send it, so this is fine.
-#[derive(Debug, Clone, Hash, PartialOrd, Ord, PartialEq, Eq)]
-pub struct WwwAuthenticate(pub Cow<'static, str>);
+/// Longest a share link can be valid for, in seconds: about 10 years.
+pub const MAX_SHARE_AGE: u64 = 10 * 365 * 24 * 60 * 60;
 
-impl Header for WwwAuthenticate {
-    fn header_name() -> &'static str {
-        "WWW-Authenticate"
-    }
+/// Maximum amount of entries to return from a `Depth: infinity` PROPFIND with `--webdav-infinity`.
+pub const MAX_PROPFIND_INFINITY_ENTRIES: usize = 100_000;
 
-    /// We only ever send these
-    fn parse_header<T: AsRef<[u8]>>(_: &[T]) -> HyperResult<WwwAuthenticate> {
-        unreachable!()
-    }
-}
+/// How long a resumable upload can go without being added to before it's abandoned and deleted: a day.
+pub const MAX_PARTIAL_UPLOAD_IDLE: Duration = Duration::from_secs(24 * 60 * 60);
 
-impl HeaderFormat for WwwAuthenticate {
-    fn fmt_header(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str(&self.0)
-    }
-}
 
 /// The `X-Last-Modified` header: milliseconds since epoch for PUTs.
 ///
@@ -162,6 +165,84 @@ impl HeaderFormat for XOcMTime {
     }
 }
 
+/// The [Retry-After header](https://tools.ietf.org/html/rfc7231#section-7.1.3), in seconds.
+///
+/// No parsing, we only send.
+#[derive(Debug, Clone, Copy, Hash, PartialOrd, Ord, PartialEq, Eq)]
+pub struct RetryAfter(pub u64);
+
+impl Header for RetryAfter {
+    fn header_name() -> &'static str {
+        "Retry-After"
+    }
+
+    /// We only ever send these
+    fn parse_header<T: AsRef<[u8]>>(_: &[T]) -> HyperResult<RetryAfter> {
+        unreachable!()
+    }
+}
+
+impl HeaderFormat for RetryAfter {
+    fn fmt_header(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// The `X-Upload-Offset` header: byte offset of a resumable upload `PATCH`.
+///
+/// Received as where the body goes, sent as how much of the upload has been stored so far.
+#[derive(Debug, Clone, Copy, Hash, PartialOrd, Ord, PartialEq, Eq)]
+pub struct XUploadOffset(pub u64);
+
+impl Header for XUploadOffset {
+    fn header_name() -> &'static str {
+        "X-Upload-Offset"
+    }
+
+    fn parse_header<T: AsRef<[u8]>>(data: &[T]) -> HyperResult<XUploadOffset> {
+        if data.len() != 1 {
+            return Err(HyperError::Header);
+        }
+        Ok(XUploadOffset(str::from_utf8(data.last().ok_or(HyperError::Header).map(|d| d.as_ref())?).map_err(|_| HyperError::Header)?
+            .parse()
+            .map_err(|_| HyperError::Header)?))
+    }
+}
+
+impl HeaderFormat for XUploadOffset {
+    fn fmt_header(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// The `X-Upload-Length` header: total size of the file being uploaded with resumable `PATCH`es.
+///
+/// No formatting, we only receive.
+#[derive(Debug, Clone, Copy, Hash, PartialOrd, Ord, PartialEq, Eq)]
+pub struct XUploadLength(pub u64);
+
+impl Header for XUploadLength {
+    fn header_name() -> &'static str {
+        "X-Upload-Length"
+    }
+
+    fn parse_header<T: AsRef<[u8]>>(data: &[T]) -> HyperResult<XUploadLength> {
+        if data.len() != 1 {
+            return Err(HyperError::Header);
+        }
+        Ok(XUploadLength(str::from_utf8(data.last().ok_or(HyperError::Header).map(|d| d.as_ref())?).map_err(|_| HyperError::Header)?
+            .parse()
+            .map_err(|_| HyperError::Header)?))
+    }
+}
+
+/// We only ever receive these
+impl HeaderFormat for XUploadLength {
+    fn fmt_header(&self, _: &mut fmt::Formatter) -> fmt::Result {
+        unreachable!()
+    }
+}
+
 /// The [Content-Disposition header](https://developer.mozilla.org/en-US/docs/Web/HTTP/Reference/Headers/Content-Disposition),
 /// without parsing.
 ///
@@ -385,6 +466,88 @@ pub fn percent_decode(s: &str) -> Option<Cow<str>> {
     percent_encoding::percent_decode(s.as_bytes()).decode_utf8().ok()
 }
 
+/// Resolve byte range specs against a file of length `flen` into sorted, inclusive `(from, to)` pairs,
+/// dropping the unsatisfiable ones and coalescing those that overlap or abut.
+///
+/// # Example
+///
+/// ```
+/// # extern crate iron;
+/// # extern crate https;
+/// # use iron::headers::ByteRangeSpec;
+/// # use https::util::coalesce_byte_ranges;
+/// assert_eq!(coalesce_byte_ranges(&[ByteRangeSpec::FromTo(50, 99), ByteRangeSpec::Last(10), ByteRangeSpec::FromTo(0, 60)], 200),
+///            vec![(0, 99), (190, 199)]);
+/// assert_eq!(coalesce_byte_ranges(&[ByteRangeSpec::AllFrom(200), ByteRangeSpec::Last(0)], 200), vec![]);
+/// ```
+pub fn coalesce_byte_ranges(brs: &[ByteRangeSpec], flen: u64) -> Vec<(u64, u64)> {
+    let mut ranges = brs.iter()
+        .flat_map(|br| match *br {
+            ByteRangeSpec::FromTo(from, to) if from < flen => Some((from, cmp::min(to, flen - 1))),
+            ByteRangeSpec::AllFrom(from) if from < flen => Some((from, flen - 1)),
+            ByteRangeSpec::Last(last) if last != 0 && flen != 0 => Some((flen.saturating_sub(last), flen - 1)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    ranges.sort_unstable();
+
+    let mut ret: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (from, to) in ranges {
+        match ret.last_mut() {
+            Some(prev) if from <= prev.1 + 1 => prev.1 = cmp::max(prev.1, to),
+            _ => ret.push((from, to)),
+        }
+    }
+    ret
+}
+
+/// Check if a crypt(3)-style password hash, as found in htpasswd files, is one we can verify.
+///
+/// These are bcrypt (`$2a$`, `$2b$`, `$2y$`), SHA-crypt (`$5$`, `$6$`), and Argon2 (`$argon2i$`, `$argon2d$`, `$argon2id$`).
+///
+/// # Examples
+///
+/// ```
+/// # use https::util::password_hash_supported;
+/// assert!(password_hash_supported("$2y$05$Cc0tMQ8pXvyPmtymmPRT4.zzYfprseav4huhLfjwBQ32uoAKllwJe"));
+/// assert!(!password_hash_supported("$apr1$/Zf/hQHh$.2ri9xwCwmkqJZQLSpSVK0"));
+/// assert!(!password_hash_supported("hunter2"));
+/// ```
+pub fn password_hash_supported(hash: &str) -> bool {
+    if hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$") {
+        hash.len() == 60
+    } else if hash.starts_with("$5$") || hash.starts_with("$6$") {
+        true
+    } else if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok()
+    } else {
+        false
+    }
+}
+
+/// Check `password` against a hash for which `password_hash_supported()` holds.
+///
+/// # Examples
+///
+/// ```
+/// # use https::util::verify_password_hash;
+/// assert!(verify_password_hash("hunter2", "$2y$05$Cc0tMQ8pXvyPmtymmPRT4.zzYfprseav4huhLfjwBQ32uoAKllwJe"));
+/// assert!(!verify_password_hash("hunter3", "$2y$05$Cc0tMQ8pXvyPmtymmPRT4.zzYfprseav4huhLfjwBQ32uoAKllwJe"));
+/// ```
+pub fn verify_password_hash(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).map(|h| Argon2::default().verify_password(password.as_bytes(), &h).is_ok()).unwrap_or(false)
+    } else if hash.starts_with("$2") {
+        pwhash::bcrypt::verify(password, hash)
+    } else if hash.starts_with("$5$") {
+        pwhash::sha256_crypt::verify(password, hash)
+    } else if hash.starts_with("$6$") {
+        pwhash::sha512_crypt::verify(password, hash)
+    } else {
+        false
+    }
+}
+
 /// Get the timestamp of the file's last modification as a `chrono::DateTime`.
 pub fn file_time_modified_p(f: &Path) -> DateTime<Utc> {
     file_time_modified(&f.metadata().expect("Failed to get file metadata"))
@@ -655,6 +818,9 @@ pub fn copy_dir(from: &Path, to: &Path) -> IoResult<Vec<(IoError, String)>> {
         return Err(IoError::new(IoErrorKind::Other, "cannot copy to a path prefixed by the source path"));
     }
 
+    let root = Path::new("");
+    push_error!(errors, root, copy_dead_properties(from, to));
+
     for entry in WalkDir::new(&from).min_depth(1).into_iter().flatten() {
         let source_metadata = match entry.metadata() {
             Ok(md) => md,
@@ -674,11 +840,22 @@ pub fn copy_dir(from: &Path, to: &Path) -> IoResult<Vec<(IoError, String)>> {
         } else {
             push_error!(errors, relative_path, fs::copy(entry.path(), &target_path));
         }
+        push_error!(errors, relative_path, copy_dead_properties(entry.path(), &target_path));
     }
 
     Ok(errors)
 }
 
+/// Give `to` the same WebDAV dead properties as `from`
+///
+/// Properties that can't be kept, because `to` is on a filesystem without extended attributes, are dropped
+pub fn copy_dead_properties(from: &Path, to: &Path) -> IoResult<()> {
+    match set_dead_properties(to, dead_properties(from)?.as_deref()) {
+        Err(e) if dead_properties_unsupported(&e) => Ok(()),
+        res => res,
+    }
+}
+
 /// `clock_gettime(CLOCK_MONOTONIC)` in ns
 pub fn precise_time_ns() -> u64 {
     // All Instants in libstd are PODs of integers
@@ -1,9 +1,14 @@
-use libc::{AT_SYMLINK_NOFOLLOW, UTIME_OMIT, AT_FDCWD, mode_t, futimens, utimensat, timespec, umask};
+use libc::{AT_SYMLINK_NOFOLLOW, ENOTSUP, STDOUT_FILENO, STDERR_FILENO, FD_CLOEXEC, UTIME_OMIT, AT_FDCWD, F_SETFD, SIGHUP, c_int, mode_t, futimens, utimensat, timespec, sighandler_t, signal,
+           gethostname, c_char, fcntl, umask, dup2, statvfs};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use std::io::{ErrorKind as IoErrorKind, Error as IoError, Result as IoResult, stdout};
 use std::os::unix::fs::{PermissionsExt, MetadataExt};
 use self::super::super::is_actually_file;
 use std::fs::{self, Metadata, File};
 use std::os::unix::ffi::OsStrExt;
-use std::os::fd::AsRawFd;
+use std::os::fd::{FromRawFd, AsRawFd, AsFd};
+use std::{env, mem, process};
+use socket2::Socket;
 use std::path::Path;
 
 
@@ -12,6 +17,14 @@ const FILE_ATTRIBUTE_HIDDEN: u32 = 0x02;
 const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
 const FILE_ATTRIBUTE_ARCHIVE: u32 = 0x20;
 
+static HANGUPS: AtomicU64 = AtomicU64::new(0);
+
+/// Extended attribute WebDAV dead properties are kept in
+const DEAD_PROPERTIES_XATTR: &str = "user.http.dead-properties";
+
+/// Where systemd's `LISTEN_FDS` start
+const SD_LISTEN_FDS_START: c_int = 3;
+
 
 /// Get windows-style attributes for the specified file
 ///
@@ -125,3 +138,111 @@ fn ms_to_timespec(ms: u64) -> timespec {
         tv_nsec: ((ms % 1000) * 1000_000) as _,
     }
 }
+
+
+/// Get the WebDAV dead properties stored for `f`, if any, as written by `set_dead_properties()`
+///
+/// Filesystems without user extended attributes have none
+pub fn dead_properties(f: &Path) -> IoResult<Option<Vec<u8>>> {
+    match xattr::get_deref(f, DEAD_PROPERTIES_XATTR) {
+        Err(e) if dead_properties_unsupported(&e) => Ok(None),
+        props => props,
+    }
+}
+
+/// Whether `e` means `f` is on a filesystem that can't store dead properties
+pub fn dead_properties_unsupported(e: &IoError) -> bool {
+    e.kind() == IoErrorKind::Unsupported || e.raw_os_error() == Some(ENOTSUP)
+}
+
+/// Store (or, with `None`, remove) the WebDAV dead properties of `f` in an extended attribute
+pub fn set_dead_properties(f: &Path, props: Option<&[u8]>) -> IoResult<()> {
+    match props {
+        Some(props) => xattr::set_deref(f, DEAD_PROPERTIES_XATTR, props),
+        None if dead_properties(f)?.is_some() => xattr::remove_deref(f, DEAD_PROPERTIES_XATTR),
+        None => Ok(()),
+    }
+}
+
+
+/// Bytes available to us and used on the filesystem `f` is on
+pub fn filesystem_space(f: &Path) -> IoResult<(u64, u64)> {
+    let mut path = f.as_os_str().as_bytes().to_vec();
+    path.push(0);
+
+    let mut st: statvfs = unsafe { mem::zeroed() };
+    if unsafe { statvfs(path.as_ptr() as *const c_char, &mut st) } != 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok((st.f_bavail as u64 * st.f_frsize as u64, (st.f_blocks as u64 - st.f_bfree as u64) * st.f_frsize as u64))
+}
+
+
+/// Make stdout write to `f` from now on
+pub fn redirect_stdout(f: &File) -> IoResult<()> {
+    if unsafe { dup2(f.as_raw_fd(), STDOUT_FILENO) } == -1 {
+        Err(IoError::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// A separate handle to what stdout writes to now, which `redirect_stdout()` doesn't affect
+pub fn duplicate_stdout() -> IoResult<File> {
+    stdout().as_fd().try_clone_to_owned().map(File::from)
+}
+
+/// Make stdout write to stderr from now on
+pub fn redirect_stdout_to_stderr() -> IoResult<()> {
+    if unsafe { dup2(STDERR_FILENO, STDOUT_FILENO) } == -1 {
+        Err(IoError::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Count SIGHUPs for `hangups()` instead of terminating
+pub fn catch_hangup() {
+    extern "C" fn handler(_: c_int) {
+        HANGUPS.fetch_add(1, AtomicOrdering::Relaxed);
+    }
+
+    unsafe { signal(SIGHUP, handler as extern "C" fn(c_int) as sighandler_t) };
+}
+
+/// How many SIGHUPs were received so far; each user remembers the last count it saw
+pub fn hangups() -> u64 {
+    HANGUPS.load(AtomicOrdering::Relaxed)
+}
+
+
+/// How many sockets systemd passed via `LISTEN_FDS`, if they're meant for this process
+pub fn activation_socket_count() -> u32 {
+    match (env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()), env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse().ok())) {
+        (Some(pid), Some(fds)) if pid == process::id() => fds,
+        _ => 0,
+    }
+}
+
+/// Take the sockets systemd passed via `LISTEN_FDS`, which only works once
+pub fn activation_sockets() -> Vec<Socket> {
+    (0..activation_socket_count() as c_int)
+        .map(|i| {
+            unsafe {
+                fcntl(SD_LISTEN_FDS_START + i, F_SETFD, FD_CLOEXEC);
+                Socket::from_raw_fd(SD_LISTEN_FDS_START + i)
+            }
+        })
+        .collect()
+}
+
+
+/// The machine's name, if it has one
+pub fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { gethostname(buf.as_mut_ptr() as *mut c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok().filter(|name| !name.is_empty())
+}
@@ -1,10 +1,16 @@
-use winapi::um::fileapi::{GetFileAttributesW, SetFileTime};
+use winapi::um::fileapi::{GetDiskFreeSpaceExW, GetFileAttributesW, SetFileTime};
+use winapi::um::winnt::ULARGE_INTEGER;
 use winapi::shared::minwindef::FILETIME;
-use std::os::windows::io::AsRawHandle;
+use winapi::um::processenv::{SetStdHandle, GetStdHandle};
+use winapi::um::winbase::{STD_OUTPUT_HANDLE, STD_ERROR_HANDLE};
+use std::os::windows::io::{AsRawHandle, AsHandle};
 use std::os::windows::fs::MetadataExt;
 use std::os::windows::ffi::OsStrExt;
-use std::fs::{Metadata, File};
-use std::path::Path;
+use std::io::{ErrorKind as IoErrorKind, Error as IoError, Result as IoResult, stdout};
+use std::fs::{self, Metadata, File};
+use std::path::{PathBuf, Path};
+use socket2::Socket;
+use std::{env, mem};
 
 
 /// Get windows-style attributes for the specified file
@@ -79,3 +85,99 @@ fn ms_to_FILETIME(ms: u64) -> FILETIME {
         dwHighDateTime: (ft >> 32) as u32,
     }
 }
+
+
+/// Alternate data stream WebDAV dead properties are kept in
+fn dead_properties_stream(f: &Path) -> PathBuf {
+    let mut stream = f.as_os_str().to_os_string();
+    stream.push(":http.dead-properties");
+    stream.into()
+}
+
+/// Get the WebDAV dead properties stored for `f`, if any, as written by `set_dead_properties()`
+pub fn dead_properties(f: &Path) -> IoResult<Option<Vec<u8>>> {
+    match fs::read(dead_properties_stream(f)) {
+        Ok(props) => Ok(Some(props)),
+        Err(e) if e.kind() == IoErrorKind::NotFound || dead_properties_unsupported(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether `e` means `f` is on a filesystem that can't store dead properties, like FAT without alternate data streams
+pub fn dead_properties_unsupported(e: &IoError) -> bool {
+    e.kind() == IoErrorKind::Unsupported
+}
+
+/// Store (or, with `None`, remove) the WebDAV dead properties of `f` in an alternate data stream
+pub fn set_dead_properties(f: &Path, props: Option<&[u8]>) -> IoResult<()> {
+    match props {
+        Some(props) => fs::write(dead_properties_stream(f), props),
+        None => {
+            match fs::remove_file(dead_properties_stream(f)) {
+                Err(e) if e.kind() != IoErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+/// Bytes available to us and used on the volume `f` is on
+pub fn filesystem_space(f: &Path) -> IoResult<(u64, u64)> {
+    let mut buf: Vec<_> = f.as_os_str().encode_wide().collect();
+    buf.push(0);
+
+    let (mut available, mut total, mut free): (ULARGE_INTEGER, ULARGE_INTEGER, ULARGE_INTEGER) = unsafe { (mem::zeroed(), mem::zeroed(), mem::zeroed()) };
+    if unsafe { GetDiskFreeSpaceExW(buf.as_ptr(), &mut available, &mut total, &mut free) } == 0 {
+        return Err(IoError::last_os_error());
+    }
+    unsafe { Ok((*available.QuadPart(), total.QuadPart() - free.QuadPart())) }
+}
+
+
+/// Make stdout write to `f` from now on, for as long as it's open
+pub fn redirect_stdout(f: &File) -> IoResult<()> {
+    if unsafe { SetStdHandle(STD_OUTPUT_HANDLE, f.as_raw_handle() as _) } == 0 {
+        Err(IoError::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// A separate handle to what stdout writes to now, which `redirect_stdout()` doesn't affect
+pub fn duplicate_stdout() -> IoResult<File> {
+    stdout().as_handle().try_clone_to_owned().map(File::from)
+}
+
+/// Make stdout write to stderr from now on
+pub fn redirect_stdout_to_stderr() -> IoResult<()> {
+    if unsafe { SetStdHandle(STD_OUTPUT_HANDLE, GetStdHandle(STD_ERROR_HANDLE)) } == 0 {
+        Err(IoError::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// There's no SIGHUP
+pub fn catch_hangup() {}
+
+/// There's no SIGHUP
+pub fn hangups() -> u64 {
+    0
+}
+
+
+/// There's no systemd
+pub fn activation_socket_count() -> u32 {
+    0
+}
+
+/// There's no systemd
+pub fn activation_sockets() -> Vec<Socket> {
+    vec![]
+}
+
+
+/// The machine's name, if it has one
+pub fn hostname() -> Option<String> {
+    env::var("COMPUTERNAME").ok().filter(|name| !name.is_empty())
+}
@@ -0,0 +1,110 @@
+use std::os::unix::fs::{PermissionsExt, FileTypeExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use hyper::net::{NetworkListener, NetworkStream};
+use std::io::{Result as IoResult, Write, Read};
+use self::super::UNIX_SOCKET_ADDRESS;
+use std::net::{SocketAddr, Shutdown};
+use std::time::Duration;
+use std::path::Path;
+use std::sync::Arc;
+use std::{fs, io};
+use hyper;
+
+
+/// Bind a Unix domain socket at `path`, replacing a stale one, and set its permissions to `mode`, if any
+pub fn bind_unix(path: &Path, mode: Option<u32>) -> IoResult<UnixListener> {
+    if fs::symlink_metadata(path).map(|meta| meta.file_type().is_socket()).unwrap_or(false) && UnixStream::connect(path).is_err() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+/// Serves HTTP over a Unix domain socket
+#[derive(Clone)]
+pub struct UnixSocketListener {
+    listener: Arc<UnixListener>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl From<UnixListener> for UnixSocketListener {
+    fn from(listener: UnixListener) -> UnixSocketListener {
+        UnixSocketListener {
+            listener: Arc::new(listener),
+            read_timeout: None,
+            write_timeout: None,
+        }
+    }
+}
+
+impl NetworkListener for UnixSocketListener {
+    type Stream = UnixSocketStream;
+
+    fn accept(&mut self) -> hyper::Result<UnixSocketStream> {
+        let stream = UnixSocketStream(self.listener.accept()?.0);
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        Ok(stream)
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(UNIX_SOCKET_ADDRESS)
+    }
+
+    fn set_read_timeout(&mut self, duration: Option<Duration>) {
+        self.read_timeout = duration;
+    }
+
+    fn set_write_timeout(&mut self, duration: Option<Duration>) {
+        self.write_timeout = duration;
+    }
+}
+
+pub struct UnixSocketStream(UnixStream);
+
+impl Clone for UnixSocketStream {
+    fn clone(&self) -> UnixSocketStream {
+        UnixSocketStream(self.0.try_clone().expect("Failed to clone Unix socket"))
+    }
+}
+
+impl Read for UnixSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for UnixSocketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl NetworkStream for UnixSocketStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(UNIX_SOCKET_ADDRESS)
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        match self.0.shutdown(how) {
+            Err(e) if e.kind() != io::ErrorKind::NotConnected => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
@@ -16,10 +16,18 @@ macro_rules! xml_name {
     }
 }
 
-/// HTTP methods we support for WebDAV level 1, as specified in https://tools.ietf.org/html/rfc2518, without locks
-pub fn dav_level_1_methods(writes: bool) -> &'static [method::Method] {
-    static METHODS: [method::Method; 5] = [method::DavPropfind, method::DavCopy, method::DavMkcol, method::DavMove, method::DavProppatch];
-    if writes { &METHODS[..] } else { &METHODS[..1] }
+/// HTTP methods we support for WebDAV level 2, as specified in https://tools.ietf.org/html/rfc2518, with write locks,
+/// and for searching, as specified in https://tools.ietf.org/html/rfc5323
+pub fn dav_level_2_methods(writes: bool) -> &'static [method::Method] {
+    static METHODS: [method::Method; 8] = [method::DavPropfind,
+                                           method::DavSearch,
+                                           method::DavCopy,
+                                           method::DavMkcol,
+                                           method::DavMove,
+                                           method::DavProppatch,
+                                           method::DavLock,
+                                           method::DavUnlock];
+    if writes { &METHODS[..] } else { &METHODS[..2] }
 }
 
 /// Prefix and namespace URI for generic WebDAV elements
@@ -44,7 +52,9 @@ pub const WEBDAV_ALLPROP_PROPERTIES_NON_WINDOWS: &[&[XmlName]] = &[&[xml_name!(W
                                                                      xml_name!(WEBDAV_XML_NAMESPACE_DAV, "getcontentlength"),
                                                                      xml_name!(WEBDAV_XML_NAMESPACE_DAV, "getcontenttype"),
                                                                      xml_name!(WEBDAV_XML_NAMESPACE_DAV, "getlastmodified"),
-                                                                     xml_name!(WEBDAV_XML_NAMESPACE_DAV, "resourcetype")]];
+                                                                     xml_name!(WEBDAV_XML_NAMESPACE_DAV, "resourcetype"),
+                                                                     xml_name!(WEBDAV_XML_NAMESPACE_DAV, "supportedlock"),
+                                                                     xml_name!(WEBDAV_XML_NAMESPACE_DAV, "lockdiscovery")]];
 
 /// Properties to return on empty body or [`<allprop />`](https://tools.ietf.org/html/rfc2518#section-12.14.1)
 /// for Windows clients
@@ -72,7 +82,7 @@ pub const WEBDAV_PROPNAME_PROPERTIES: &[&[XmlName]] = &[&WEBDAV_ALLPROP_PROPERTI
 pub struct Dav(pub &'static [&'static str]);
 
 impl Dav {
-    pub const LEVEL_1: Dav = Dav(&["1"]);
+    pub const LEVEL_2: Dav = Dav(&["1", "2"]);
 }
 
 impl Header for Dav {
@@ -98,6 +108,32 @@ impl HeaderFormat for Dav {
 }
 
 
+/// The [DASL header](https://tools.ietf.org/html/rfc5323#section-3.2), listing supported SEARCH grammars
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub struct Dasl(pub &'static str);
+
+impl Dasl {
+    pub const BASICSEARCH: Dasl = Dasl("<DAV:basicsearch>");
+}
+
+impl Header for Dasl {
+    fn header_name() -> &'static str {
+        "DASL"
+    }
+
+    /// We only ever send these
+    fn parse_header<T: AsRef<[u8]>>(_: &[T]) -> HyperResult<Dasl> {
+        unreachable!()
+    }
+}
+
+impl HeaderFormat for Dasl {
+    fn fmt_header(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+
 /// The [Depth header](https://tools.ietf.org/html/rfc2518#section-9.2).
 #[derive(Debug, Copy, Clone, Hash, PartialOrd, Ord, PartialEq, Eq)]
 pub enum Depth {
@@ -227,3 +263,199 @@ impl Default for Overwrite {
         Overwrite(true)
     }
 }
+
+
+/// The [Lock-Token header](https://tools.ietf.org/html/rfc4918#section-10.5), without the angle brackets.
+#[derive(Debug, Clone, Hash, PartialOrd, Ord, PartialEq, Eq)]
+pub struct LockToken(pub String);
+
+impl Header for LockToken {
+    fn header_name() -> &'static str {
+        "Lock-Token"
+    }
+
+    fn parse_header<T: AsRef<[u8]>>(raw: &[T]) -> HyperResult<LockToken> {
+        if raw.len() != 1 {
+            return Err(HyperError::Header);
+        }
+
+        let token = str::from_utf8(unsafe { raw.get_unchecked(0) }.as_ref()).map_err(|_| HyperError::Header)?.trim();
+        if token.len() > 2 && token.starts_with('<') && token.ends_with('>') {
+            Ok(LockToken(token[1..token.len() - 1].to_string()))
+        } else {
+            Err(HyperError::Header)
+        }
+    }
+}
+
+impl HeaderFormat for LockToken {
+    fn fmt_header(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{}>", self.0)
+    }
+}
+
+
+/// The [Timeout header](https://tools.ietf.org/html/rfc4918#section-10.7), in seconds, `None` for `Infinite`.
+///
+/// Of the values a client sends, the first one we understand is used.
+#[derive(Debug, Copy, Clone, Hash, PartialOrd, Ord, PartialEq, Eq)]
+pub struct Timeout(pub Option<u64>);
+
+impl Header for Timeout {
+    fn header_name() -> &'static str {
+        "Timeout"
+    }
+
+    fn parse_header<T: AsRef<[u8]>>(raw: &[T]) -> HyperResult<Timeout> {
+        for line in raw {
+            for tt in str::from_utf8(line.as_ref()).map_err(|_| HyperError::Header)?.split(',').map(str::trim) {
+                if tt.eq_ignore_ascii_case("Infinite") {
+                    return Ok(Timeout(None));
+                }
+                if let Some(secs) = tt.get(..7).filter(|pf| pf.eq_ignore_ascii_case("Second-")).and_then(|_| tt[7..].parse().ok()) {
+                    return Ok(Timeout(Some(secs)));
+                }
+            }
+        }
+        Err(HyperError::Header)
+    }
+}
+
+impl HeaderFormat for Timeout {
+    fn fmt_header(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(secs) => write!(f, "Second-{}", secs),
+            None => f.write_str("Infinite"),
+        }
+    }
+}
+
+impl fmt::Display for Timeout {
+    #[inline(always)]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_header(f)
+    }
+}
+
+
+/// The [If header](https://tools.ietf.org/html/rfc4918#section-10.4).
+///
+/// The header is satisfied if any of the lists is; a list is if all of its conditions are.
+#[derive(Debug, Clone, Hash, PartialOrd, Ord, PartialEq, Eq)]
+pub struct If(pub Vec<IfList>);
+
+/// A parenthesised list of conditions in an [`If`] header, with the resource it's tagged for, if any
+#[derive(Debug, Clone, Hash, PartialOrd, Ord, PartialEq, Eq)]
+pub struct IfList {
+    pub resource: Option<String>,
+    pub conditions: Vec<IfCondition>,
+}
+
+/// A single, possibly negated, condition in an [`IfList`]
+#[derive(Debug, Clone, Hash, PartialOrd, Ord, PartialEq, Eq)]
+pub enum IfCondition {
+    StateToken(bool, String),
+    /// Entity tag without quotes and weakness marker
+    ETag(bool, String),
+}
+
+impl If {
+    /// All state tokens the client submitted, negated or not
+    pub fn state_tokens(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().flat_map(|l| l.conditions.iter()).flat_map(|c| match c {
+            IfCondition::StateToken(_, token) => Some(&token[..]),
+            IfCondition::ETag(..) => None,
+        })
+    }
+}
+
+impl Header for If {
+    fn header_name() -> &'static str {
+        "If"
+    }
+
+    fn parse_header<T: AsRef<[u8]>>(raw: &[T]) -> HyperResult<If> {
+        fn until(s: &str, end: char) -> HyperResult<(&str, &str)> {
+            s.find(end).map(|idx| (&s[..idx], &s[idx + 1..])).ok_or(HyperError::Header)
+        }
+
+        let mut lists = vec![];
+        for line in raw {
+            let mut s = str::from_utf8(line.as_ref()).map_err(|_| HyperError::Header)?.trim_start();
+            let mut resource = None;
+            while !s.is_empty() {
+                if let Some(rest) = s.strip_prefix('<') {
+                    let (tag, rest) = until(rest, '>')?;
+                    resource = Some(tag.to_string());
+                    s = rest;
+                } else if let Some(mut rest) = s.strip_prefix('(') {
+                    let mut conditions = vec![];
+                    loop {
+                        rest = rest.trim_start();
+                        let not = rest.starts_with("Not") && rest[3..].starts_with(|c: char| c.is_whitespace() || c == '<' || c == '[');
+                        if not {
+                            rest = rest[3..].trim_start();
+                        }
+                        if let Some(r) = rest.strip_prefix('<') {
+                            let (token, r) = until(r, '>')?;
+                            conditions.push(IfCondition::StateToken(not, token.to_string()));
+                            rest = r;
+                        } else if let Some(r) = rest.strip_prefix('[') {
+                            let (etag, r) = until(r, ']')?;
+                            conditions.push(IfCondition::ETag(not, etag.trim().trim_start_matches("W/").trim_matches('"').to_string()));
+                            rest = r;
+                        } else if let Some(r) = rest.strip_prefix(')') {
+                            rest = r;
+                            break;
+                        } else {
+                            return Err(HyperError::Header);
+                        }
+                    }
+                    if conditions.is_empty() {
+                        return Err(HyperError::Header);
+                    }
+                    lists.push(IfList {
+                        resource: resource.clone(),
+                        conditions,
+                    });
+                    s = rest;
+                } else {
+                    return Err(HyperError::Header);
+                }
+                s = s.trim_start();
+            }
+        }
+
+        if lists.is_empty() {
+            return Err(HyperError::Header);
+        }
+        Ok(If(lists))
+    }
+}
+
+impl HeaderFormat for If {
+    fn fmt_header(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, list) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_str(" ")?;
+            }
+            if let Some(res) = list.resource.as_ref() {
+                if i == 0 || self.0[i - 1].resource.as_ref() != Some(res) {
+                    write!(f, "<{}> ", res)?;
+                }
+            }
+            f.write_str("(")?;
+            for (j, cond) in list.conditions.iter().enumerate() {
+                if j != 0 {
+                    f.write_str(" ")?;
+                }
+                match cond {
+                    IfCondition::StateToken(not, token) => write!(f, "{}<{}>", if *not { "Not " } else { "" }, token)?,
+                    IfCondition::ETag(not, etag) => write!(f, "{}[\"{}\"]", if *not { "Not " } else { "" }, etag)?,
+                }
+            }
+            f.write_str(")")?;
+        }
+        Ok(())
+    }
+}